@@ -6,20 +6,23 @@ use std::env::args;
 use std::fs::OpenOptions;
 use std::io::BufRead;
 use std::io::Read;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use thiserror::*;
 
 use bumpalo::collections::Vec as BumpVec;
 use bumpalo::Bump;
 
 mod midi;
-use midi::{MidiChannel, MidiMessage, MidiNote, NoteOn, PressVelocity};
+use midi::{all_notes_off, ActiveNotes, MidiMessage};
 mod model;
 mod songlang;
-use songlang::{compile_song, parse_file, LangItem, PortList};
+use songlang::{
+    compile_song, lint_song, parse_file, song_tuning, LangItem, LintContext, PortList, SongTuning,
+};
 mod track;
+mod tuning;
 mod utils;
 use track::*;
 pub use utils::*;
@@ -41,6 +44,8 @@ pub enum MyError {
     Io(#[from] std::io::Error),
     #[error("Parse error: {0}")]
     Parser(String),
+    #[error(transparent)]
+    Tuning(#[from] tuning::TuningError),
 }
 
 impl From<String> for MyError {
@@ -49,30 +54,87 @@ impl From<String> for MyError {
     }
 }
 
-fn get_tracks() -> impl Iterator<Item = (String, Result<Vec<LangItem>, MyError>)> {
-    TuplerIter::new(args().skip(1), |raw_path| {
-        let trimmed_path = raw_path.trim();
-        let mut fh = OpenOptions::new().read(true).open(trimmed_path)?;
-        let mut buff = String::new();
-        fh.read_to_string(&mut buff)?;
-        let (out, res) = parse_file(&buff).map_err(|e| match e {
-            NomErr::Error(e) | NomErr::Failure(e) => format!(
-                "Parse error: {}\n\nRaw:\n{:?}",
-                convert_nom_error(&buff, e.clone()),
-                e
-            ),
-            NomErr::Incomplete(ic) => format!("Incomplete: {:?}", ic),
-        })?;
-
-        if !out.trim().is_empty() {
-            return Err(MyError::Parser(format!(
-                "Could not parse full file. Data: {:?}, Rest: {:?}",
-                &res, &out
-            )));
+/// The options given on the command line.
+struct Options {
+    /// The song files to play.
+    paths: Vec<String>,
+    /// Tracks keep their own tempo unless a file is given as `--master-tempo FILE`.
+    tempo_mode: TempoMode,
+    /// Whether to reload song files when they change, given as `--watch`.
+    watch: bool,
+    /// Whether files are clips that only play once launched, given as `--session`.
+    session: bool,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        paths: Vec::new(),
+        tempo_mode: TempoMode::Polytempo,
+        watch: false,
+        session: false,
+    };
+    for arg in args().skip(1) {
+        if arg == "--master-tempo" {
+            options.tempo_mode = TempoMode::Master(options.paths.len());
+        } else if arg == "--watch" {
+            options.watch = true;
+        } else if arg == "--session" {
+            options.session = true;
+        } else {
+            options.paths.push(arg);
         }
+    }
+    options
+}
 
-        Ok(res)
-    })
+fn get_tracks(
+    paths: Vec<String>,
+) -> impl Iterator<Item = (String, Result<Vec<LangItem>, MyError>)> {
+    TuplerIter::new(paths.into_iter(), |raw_path| read_song(raw_path))
+}
+
+fn read_song(raw_path: &str) -> Result<Vec<LangItem>, MyError> {
+    let trimmed_path = raw_path.trim();
+    let mut fh = OpenOptions::new().read(true).open(trimmed_path)?;
+    let mut buff = String::new();
+    fh.read_to_string(&mut buff)?;
+    let (out, res) = parse_file(&buff).map_err(|e| match e {
+        NomErr::Error(e) | NomErr::Failure(e) => format!(
+            "Parse error: {}\n\nRaw:\n{:?}",
+            convert_nom_error(&buff, e.clone()),
+            e
+        ),
+        NomErr::Incomplete(ic) => format!("Incomplete: {:?}", ic),
+    })?;
+
+    if !out.trim().is_empty() {
+        return Err(MyError::Parser(format!(
+            "Could not parse full file. Data: {:?}, Rest: {:?}",
+            &res, &out
+        )));
+    }
+
+    Ok(res)
+}
+
+/// Compiles a song into an optimized track, printing any lints.
+/// Returns the track along with its output ports and MTS tuning messages.
+fn compile_file(
+    file: &str,
+    song: Vec<LangItem>,
+) -> Result<(CompiledTrack, PortList, Vec<Vec<u8>>), MyError> {
+    let settings = song_tuning(&song)?;
+    let context = LintContext::new(&song);
+    let (track, ports) = compile_song(song)?;
+    for lint in lint_song(&context, &track, &ports) {
+        eprintln!("Warning in file {:?} : {}", file, lint);
+    }
+    let (track, sysex) = if settings.is_retuned() {
+        apply_tuning(&settings, &load_tuning(file, &settings)?, track)
+    } else {
+        (track, Vec::new())
+    };
+    Ok((optimize(track), ports, sysex))
 }
 
 fn scope_range(
@@ -139,62 +201,348 @@ fn initialize_client<I: IntoIterator<Item = PortList>>(
     Ok((client, jack_resolver))
 }
 
-fn send_alloff(writers: &mut BumpVec<(PortIdent, jack::MidiWriter)>) -> Result<(), MyError> {
-    let mut cur_res = Ok(());
-    for writer in writers.iter_mut().map(|(_, v)| v) {
-        for note in MidiNote::all() {
-            let msg = MidiMessage::NoteOn(NoteOn::new(
-                MidiChannel::default(),
-                *note,
-                PressVelocity::from_raw(0).unwrap(),
-            ))
-            .as_raw();
-            let wrapped_msg = jack::RawMidi {
-                time: 0,
-                bytes: &msg.bytes(),
-            };
-            if let Err(e) = writer.write(&wrapped_msg) {
-                cur_res = Err(e.into());
+/// The most `NoteOff`s `release_notes` sends to a single port. Past that it
+/// sends CC 123 and 120 on every channel instead, which takes 32 messages.
+const MAX_NOTE_RELEASES: usize = 32;
+
+/// Releases every note that is sounding on the ports of the tracks
+/// `release_track` picks.
+///
+/// Ports with only a few notes sounding get a `NoteOff` for each one; ports
+/// with more, or whose writer fills up partway through, are silenced with
+/// All Notes Off and All Sound Off on every channel. Ports where even that
+/// does not fit are marked in `active` and silenced again on the next call,
+/// whichever tracks it picks, so this never fails.
+fn release_notes(
+    writers: &mut BumpVec<(PortIdent, jack::MidiWriter)>,
+    active: &mut ActiveNotes,
+    release_track: impl Fn(usize) -> bool,
+) {
+    let write = |writer: &mut jack::MidiWriter, msg: MidiMessage| {
+        let raw = msg.as_raw();
+        let wrapped_msg = jack::RawMidi {
+            time: 0,
+            bytes: raw.bytes(),
+        };
+        writer.write(&wrapped_msg).is_ok()
+    };
+    for (port_idx, ((track_idx, _), writer)) in writers.iter_mut().enumerate() {
+        let retry = active.is_unreleased(port_idx);
+        if !retry && !release_track(*track_idx) {
+            continue;
+        }
+        let mut exact = !retry && active.count(port_idx) <= MAX_NOTE_RELEASES;
+        if exact {
+            exact = active.release(port_idx).all(|msg| write(writer, msg));
+        }
+        if !exact {
+            active.clear(port_idx);
+            let silenced = all_notes_off().all(|msg| write(writer, msg));
+            active.set_unreleased(port_idx, !silenced);
+        }
+    }
+}
+
+/// How often song files are checked for changes in watch mode.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The way a reloaded track gets to the realtime thread, and back again
+/// once it has been swapped in or turned down.
+#[derive(Default)]
+struct ReloadSlot {
+    incoming: Mailbox<TrackSwap<CompiledTrack>>,
+    returned: Mailbox<TrackSwap<CompiledTrack>>,
+}
+
+/// The way a seek prepared on the command line gets to the realtime thread,
+/// and the cursors it replaced back again.
+#[derive(Default)]
+struct SeekSlot {
+    incoming: Mailbox<PreparedSeek<CompiledTrack>>,
+    returned: Mailbox<PreparedSeek<CompiledTrack>>,
+}
+
+/// A song file that is reloaded when it changes.
+struct WatchedFile {
+    path: String,
+    modified: Option<SystemTime>,
+    ports: PortList,
+    /// The MTS tuning messages sent for the song at startup.
+    sysex: Vec<Vec<u8>>,
+    /// The version of the track that is playing.
+    playing: CompiledTrack,
+    /// The version on its way to the realtime thread, if any.
+    sending: Option<CompiledTrack>,
+    /// A newer version waiting to be sent.
+    pending: Option<CompiledTrack>,
+}
+
+impl WatchedFile {
+    fn new(path: String, track: &CompiledTrack, ports: &PortList, sysex: &[Vec<u8>]) -> Self {
+        WatchedFile {
+            modified: modified_time(&path),
+            path,
+            ports: ports.clone(),
+            sysex: sysex.to_vec(),
+            playing: track.clone(),
+            sending: None,
+            pending: None,
+        }
+    }
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path.trim())
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// How often the realtime thread is checked for new track faults.
+const FAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Prints the errors that stop tracks as the realtime thread publishes
+/// them. Never returns.
+///
+/// The position slots stop moving once a track faults, so they still hold
+/// where it stopped.
+fn report_faults(faults: &[FaultSlot], positions: &[[AtomicU64; 3]]) {
+    loop {
+        for (idx, slot) in faults.iter().enumerate() {
+            if let Some(fault) = slot.take() {
+                let [bar, beat, tick] = &positions[idx];
+                eprintln!(
+                    "Track {} stopped at {}:{}:{:02}: {}",
+                    idx,
+                    bar.load(Ordering::Relaxed),
+                    beat.load(Ordering::Relaxed),
+                    tick.load(Ordering::Relaxed),
+                    fault
+                );
             }
         }
+        std::thread::sleep(FAULT_POLL_INTERVAL);
+    }
+}
+
+/// Recompiles song files when they change, and hands each new track to the
+/// realtime thread to swap in at the start of the next bar. Never returns.
+///
+/// Errors are printed and the old version keeps playing, as it does when the
+/// new version changes the output ports or the tuning. The master tempo
+/// track is not reloaded, since every other track follows it. Swapped in
+/// versions are stored in `songs`, for seeks to start from.
+fn watch_files(
+    mut files: Vec<WatchedFile>,
+    tempo_mode: TempoMode,
+    slots: &[ReloadSlot],
+    positions: &[[AtomicU64; 3]],
+    songs: &Mutex<Vec<CompiledTrack>>,
+) {
+    let master = match tempo_mode {
+        TempoMode::Master(idx) => files
+            .get(idx)
+            .and_then(|file| tempo_map(&file.playing).ok())
+            .map(Arc::new),
+        TempoMode::Polytempo => None,
+    };
+    loop {
+        std::thread::sleep(WATCH_INTERVAL);
+        for (idx, file) in files.iter_mut().enumerate() {
+            if let Some(swap) = slots[idx].returned.take() {
+                let sent = file.sending.take();
+                if swap.swapped {
+                    eprintln!("Reloaded file {:?}.", file.path);
+                    file.playing = sent.unwrap_or_default();
+                    if let Ok(mut songs) = songs.lock() {
+                        if let Some(song) = songs.get_mut(idx) {
+                            *song = file.playing.clone();
+                        }
+                    }
+                } else if file.pending.is_none() {
+                    // It missed its bar, IE because of a seek; try the next one.
+                    file.pending = sent;
+                }
+            }
+
+            let modified = modified_time(&file.path);
+            if modified != file.modified {
+                file.modified = modified;
+                let compiled = read_song(&file.path).and_then(|song| compile_file(&file.path, song));
+                match compiled {
+                    Ok((_, ports, _)) if ports != file.ports => eprintln!(
+                        "Warning in file {:?} : its output ports changed; restart to apply the changes.",
+                        file.path
+                    ),
+                    Ok((_, _, sysex)) if sysex != file.sysex => eprintln!(
+                        "Warning in file {:?} : its tuning changed; restart to apply the changes.",
+                        file.path
+                    ),
+                    Ok((track, _, _)) => file.pending = Some(track),
+                    Err(e) => eprintln!("Error in file {:?} : {}", file.path, e),
+                }
+            }
+
+            if file.sending.is_some() || file.pending.is_none() {
+                continue;
+            }
+            let track = file.pending.take().unwrap_or_default();
+            if tempo_mode == TempoMode::Master(idx) {
+                eprintln!(
+                    "Warning in file {:?} : the master tempo track cannot be reloaded; restart to apply the changes.",
+                    file.path
+                );
+                continue;
+            }
+            let bar = positions[idx][0].load(Ordering::Relaxed) + 1;
+            let bar = bar.min(u64::from(u32::MAX)) as u32;
+            match TrackSwap::at_bar(file.playing.clone(), track.clone(), master.clone(), bar) {
+                Some(swap) => {
+                    if slots[idx].incoming.put(Box::new(swap)).is_ok() {
+                        file.sending = Some(track);
+                    }
+                }
+                None => eprintln!(
+                    "Warning in file {:?} : playback has stopped; restart to apply the changes.",
+                    file.path
+                ),
+            }
+        }
+    }
+}
+
+/// Loads the tuning a song asks for, resolving file paths relative to the song.
+fn load_tuning(file: &str, settings: &SongTuning) -> Result<tuning::Tuning, MyError> {
+    let base_dir = std::path::Path::new(file.trim())
+        .parent()
+        .map(|dir| dir.to_path_buf())
+        .unwrap_or_default();
+    let scale = settings.scale.as_ref().map(|path| base_dir.join(path));
+    let keymap = settings.keymap.as_ref().map(|path| base_dir.join(path));
+    let mut retvl = tuning::Tuning::load(scale.as_deref(), keymap.as_deref())?;
+    if let Some((note, freq)) = settings.reference_pitch() {
+        retvl = retvl.with_reference_pitch(note, freq)?;
+    }
+    Ok(retvl)
+}
+
+/// Applies a song's tuning to its compiled track, using the method the song
+/// asks for. Returns the MTS messages to send at startup if the song uses them.
+fn apply_tuning(
+    settings: &SongTuning,
+    loaded: &tuning::Tuning,
+    track: CompiledTrack,
+) -> (CompiledTrack, Vec<Vec<u8>>) {
+    match settings.method.clone().unwrap_or_default() {
+        tuning::TuningMethod::PitchBend { range, channels } => {
+            let track = tuning::retune_with_bend(track, loaded, range, &channels);
+            (track, Vec::new())
+        }
+        tuning::TuningMethod::Mts => (track, tuning::mts_messages(loaded, 0x7F)),
     }
-    cur_res
 }
 
 fn main() {
-    let (tracks, ports) = get_tracks()
+    let Options {
+        paths,
+        tempo_mode,
+        watch,
+        session,
+    } = parse_args();
+    let (tracks, ports, markers, sysex, watched) = get_tracks(paths)
         .map(|(file, res)| {
-            (
-                file,
-                res.and_then(|r| compile_song(r).map_err(|e| e.into())),
-            )
+            let compiled = res.and_then(|r| compile_file(&file, r));
+            (file, compiled)
         })
         .fold(
-            (Vec::new(), Vec::new()),
-            |(mut tracks, mut ports), (cur_file, res)| {
-                let (cur_track, cur_ports) = match res {
+            (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()),
+            |(mut tracks, mut ports, mut markers, mut sysex, mut watched), (cur_file, res)| {
+                let (cur_track, cur_ports, cur_sysex) = match res {
                     Ok(data) => data,
                     Err(e) => {
                         panic!("Error in file {:?} : {}", cur_file, e);
                     }
                 };
+                match tempo_map(&cur_track) {
+                    Ok(map) => eprintln!("File {:?} {}.", cur_file, map.length()),
+                    Err(e) => eprintln!("Warning in file {:?} : {}", cur_file, e),
+                }
+                if watch {
+                    watched.push(WatchedFile::new(cur_file, &cur_track, &cur_ports, &cur_sysex));
+                }
+                markers.push(cur_track.markers.clone());
                 tracks.push(TrackCursor::new(cur_track));
                 ports.push(cur_ports);
-                (tracks, ports)
+                sysex.push(cur_sysex);
+                (tracks, ports, markers, sysex, watched)
             },
         );
+    // The version of each track that is playing, for seeks to start from.
+    let songs: Vec<CompiledTrack> = tracks.iter().map(|track| track.data().clone()).collect();
+    let songs = Arc::new(Mutex::new(songs));
     let mut cursor = VecMultiCursor::new(tracks);
+    if let Err(e) = cursor.set_tempo_mode(tempo_mode) {
+        panic!("Could not use the master tempo: {}", e);
+    }
+    if session {
+        cursor.start_stopped();
+    }
+    let reloads: Arc<Vec<ReloadSlot>> =
+        Arc::new(markers.iter().map(|_| ReloadSlot::default()).collect());
+    let reloadref = Arc::clone(&reloads);
     let (client, mut outs) = initialize_client(ports).unwrap();
 
     #[cfg(feature = "rt-alloc-panic")]
     eprintln!("RT-ALLOC-PANIC was enabled: will panic if the realtime thread allocates.");
 
     let mut start_usecs = None;
+    // The track time playback started from, which is nonzero after a seek.
+    let mut seek_offset = Duration::from_nanos(0);
+    // Whether each track's current fault has been published yet.
+    let mut fault_reported = vec![false; markers.len()];
+    // MTS tuning messages are sent before anything else, and again after a restart.
+    let mut tuning_sent = false;
+    // The number of tuning messages already sent on each port, following `outs`.
+    let mut tuning_progress = vec![0; outs.len()];
 
     let mut writer_allocator = make_writer_allocator(outs.len());
+    // Indexed by the position of each port's writer, which follows `outs`.
+    let mut active_notes = ActiveNotes::new(outs.len());
+    let mut was_paused = false;
+    let mut released_for_shutdown = false;
 
     let flags = Arc::new((AtomicBool::new(false), AtomicBool::new(false)));
     let flagref = Arc::clone(&flags);
+    // Whether shutdown was requested, and whether the notes have been released since.
+    let shutdown = Arc::new((AtomicBool::new(false), AtomicBool::new(false)));
+    let shutdownref = Arc::clone(&shutdown);
+    let seek_slot = Arc::new(SeekSlot::default());
+    let seekref = Arc::clone(&seek_slot);
+    // A seek whose replaced cursors could not be handed back yet.
+    let mut unreturned_seek = None;
+
+    // The start of the section each track is playing, or `NO_SECTION`.
+    const NO_SECTION: usize = usize::MAX;
+    let sections: Arc<Vec<AtomicUsize>> = Arc::new(
+        markers
+            .iter()
+            .map(|_| AtomicUsize::new(NO_SECTION))
+            .collect(),
+    );
+    let sectionref = Arc::clone(&sections);
+    // The bar, beat and tick each track is at. Only used for reporting,
+    // so the three values are not updated together.
+    let positions: Arc<Vec<[AtomicU64; 3]>> =
+        Arc::new(markers.iter().map(|_| Default::default()).collect());
+    let positionref = Arc::clone(&positions);
+    let mixes: Arc<Vec<MixControl>> =
+        Arc::new(markers.iter().map(|_| MixControl::default()).collect());
+    let mixref = Arc::clone(&mixes);
+    let clips: Arc<Vec<ClipControl>> =
+        Arc::new(markers.iter().map(|_| ClipControl::default()).collect());
+    let clipref = Arc::clone(&clips);
+    let faults: Arc<Vec<FaultSlot>> =
+        Arc::new(markers.iter().map(|_| FaultSlot::default()).collect());
+    let faultref = Arc::clone(&faults);
     let cb = move |client: &Client, ps: &ProcessScope| {
         #[cfg(feature = "rt-alloc-panic")]
         malloc::MYALLOC.set_rt();
@@ -204,19 +552,121 @@ fn main() {
         let writer_iter = outs.iter_mut().map(|(id, port)| (*id, port.writer(ps)));
         let mut writers = BumpVec::from_iter_in(writer_iter, &writer_allocator);
 
+        if shutdownref.0.load(Ordering::Acquire) {
+            // Give the releases a full cycle to go out before reporting back.
+            if !released_for_shutdown {
+                release_notes(&mut writers, &mut active_notes, |_| true);
+                released_for_shutdown = !active_notes.any_unreleased();
+            } else {
+                shutdownref.1.store(true, Ordering::Release);
+            }
+            drop(writers);
+            writer_allocator.reset();
+            #[cfg(feature = "rt-alloc-panic")]
+            malloc::MYALLOC.unset_rt();
+            return jack::Control::Continue;
+        }
+
         let should_restart = flagref.1.compare_and_swap(true, false, Ordering::AcqRel);
         if should_restart {
-            send_alloff(&mut writers).unwrap();
+            release_notes(&mut writers, &mut active_notes, |_| true);
             cursor.reset();
             start_usecs = None;
+            seek_offset = Duration::from_nanos(0);
+            tuning_sent = false;
+            for sent in tuning_progress.iter_mut() {
+                *sent = 0;
+            }
+        }
+
+        // The old cursors go back to be freed off this thread, and no new
+        // seek is taken until they have.
+        if let Some(seek) = unreturned_seek.take() {
+            unreturned_seek = seekref.returned.put(seek).err();
+        }
+        if unreturned_seek.is_none() {
+            if let Some(mut seek) = seekref.incoming.take() {
+                release_notes(&mut writers, &mut active_notes, |_| true);
+                seek_offset = cursor.apply_seek(&mut seek);
+                start_usecs = None;
+                unreturned_seek = seekref.returned.put(seek).err();
+            }
+        }
+
+        if !tuning_sent {
+            tuning_sent = true;
+            let progress = writers.iter_mut().zip(tuning_progress.iter_mut());
+            for (((track_idx, _), writer), sent) in progress {
+                for msg in sysex[*track_idx][*sent..].iter() {
+                    let wrapped_msg = jack::RawMidi { time: 0, bytes: msg };
+                    if writer.write(&wrapped_msg).is_err() {
+                        tuning_sent = false;
+                        break;
+                    }
+                    *sent += 1;
+                }
+            }
+        }
+        if !tuning_sent {
+            // The rest of the tuning goes out next cycle, and playback waits
+            // for it so that no note is played out of tune.
+            drop(writers);
+            writer_allocator.reset();
+            #[cfg(feature = "rt-alloc-panic")]
+            malloc::MYALLOC.unset_rt();
+            return jack::Control::Continue;
+        }
+
+        for (idx, control) in mixref.iter().enumerate() {
+            let mix = control.load();
+            if cursor.mix(idx) == Some(mix) {
+                continue;
+            }
+            let silenced = cursor.set_mix(idx, mix);
+            release_notes(&mut writers, &mut active_notes, silenced);
+        }
+
+        // Clips that stopped during the last cycle are released at the start
+        // of this one, after everything they sent before stopping. This also
+        // retries any release that did not fit last cycle.
+        release_notes(&mut writers, &mut active_notes, |idx| cursor.clip_stopped(idx));
+        cursor.clear_stopped();
+        for (idx, control) in clipref.iter().enumerate() {
+            cursor.set_looping(idx, control.is_looping());
+            match control.take() {
+                Some(ClipCommand::Launch(quantize)) => cursor.launch(idx, quantize),
+                Some(ClipCommand::Stop(quantize)) => cursor.stop(idx, quantize),
+                None => {}
+            }
+        }
+
+        for (idx, slot) in reloadref.iter().enumerate() {
+            // The watcher waits for each swap to come back before sending
+            // another, so the way back is always free.
+            if let Some(swap) = slot.incoming.take() {
+                if let Err(swap) = cursor.stage_swap(idx, swap) {
+                    let _ = slot.returned.put(swap);
+                }
+            }
+            if slot.returned.is_empty() {
+                if let Some(swap) = cursor.take_retired(idx) {
+                    let _ = slot.returned.put(swap);
+                }
+            }
         }
 
         let is_paused = flagref.0.load(Ordering::Acquire);
+        if is_paused && !was_paused {
+            release_notes(&mut writers, &mut active_notes, |_| true);
+        }
+        was_paused = is_paused;
         if is_paused {
             if let Some(start_usecs) = start_usecs.as_mut() {
                 *start_usecs += nxt_usecs - cur_usecs;
             }
 
+            drop(writers);
+            writer_allocator.reset();
             #[cfg(feature = "rt-alloc-panic")]
             malloc::MYALLOC.unset_rt();
             return jack::Control::Continue;
@@ -225,42 +675,62 @@ fn main() {
         let start_time = Duration::from_micros(*start_usecs.get_or_insert(cur_usecs));
         let nxt_time = Duration::from_micros(nxt_usecs)
             .checked_sub(start_time)
-            .unwrap_or_default();
+            .unwrap_or_default()
+            + seek_offset;
 
         for evt in cursor.step_until(nxt_time) {
             let (time, port, msg) = evt;
 
-            let sys_time = (time.as_micros() + start_time.as_micros()) as u64;
+            let play_time = time.checked_sub(seek_offset).unwrap_or_default();
+            let sys_time = (play_time.as_micros() + start_time.as_micros()) as u64;
             let sys_frames = client.time_to_frames(sys_time);
             let frame_offset = sys_frames.saturating_sub(cur_frames);
 
-            let rawmsg = msg.as_raw();
-            let outdata = jack::RawMidi {
-                time: frame_offset,
-                bytes: rawmsg.bytes(),
-            };
-
-            let outcon = writers
+            let (port_idx, outcon) = writers
                 .iter_mut()
-                .find(|(id, _)| id == &port)
-                .map(|(_, writer)| writer)
+                .enumerate()
+                .find(|(_, (id, _))| id == &port)
+                .map(|(port_idx, (_, writer))| (port_idx, writer))
                 .ok_or_else(|| MyError::InvalidPortId(port))
                 .unwrap();
-            let write_res = outcon.write(&outdata).map_err(MyError::Jack);
-            match write_res {
-                Ok(_) => {}
-                Err(MyError::Jack(jack::Error::NotEnoughSpace)) => {
-                    #[cfg(feature = "rt-alloc-panic")]
-                    malloc::MYALLOC.unset_rt();
-                    todo!("Handle a backlog.");
-                }
-                Err(_) => {
-                    #[cfg(feature = "rt-alloc-panic")]
-                    malloc::MYALLOC.unset_rt();
-                    write_res.unwrap();
+            // Events that do not fit this cycle are dropped rather than held
+            // back, since playing them late would put them out of time.
+            active_notes.send(port_idx, msg, |msg| {
+                let rawmsg = msg.as_raw();
+                let outdata = jack::RawMidi {
+                    time: frame_offset,
+                    bytes: rawmsg.bytes(),
+                };
+                match outcon.write(&outdata) {
+                    Ok(()) => true,
+                    Err(jack::Error::NotEnoughSpace) => false,
+                    Err(e) => {
+                        #[cfg(feature = "rt-alloc-panic")]
+                        malloc::MYALLOC.unset_rt();
+                        panic!("{}", MyError::Jack(e));
+                    }
                 }
+            });
+        }
+        let reports = sectionref.iter().zip(positionref.iter());
+        for (track, (section, position)) in cursor.cursors().iter().zip(reports) {
+            let start = track.section().map_or(NO_SECTION, |marker| marker.start);
+            section.store(start, Ordering::Release);
+            let cur = track.position();
+            for (slot, value) in position.iter().zip(&[cur.bar, cur.beat, cur.tick]) {
+                slot.store(*value, Ordering::Relaxed);
             }
         }
+        // Faults are printed by the fault reporting thread, since printing
+        // can block or allocate. Each one is published once; a track only
+        // faults again after a restart, seek or reload has cleared it.
+        let tracks = cursor.cursors().iter().zip(fault_reported.iter_mut());
+        for (idx, (track, reported)) in tracks.enumerate() {
+            if let (Some(fault), false) = (track.fault(), *reported) {
+                faultref[idx].publish(fault);
+            }
+            *reported = track.fault().is_some();
+        }
         drop(writers);
         writer_allocator.reset();
         #[cfg(feature = "rt-alloc-panic")]
@@ -268,16 +738,87 @@ fn main() {
 
         jack::Control::Continue
     };
-    let _active_client = client
+    let active_client = client
         .activate_async((), jack::ClosureProcessHandler::new(cb))
         .unwrap();
+    if watch {
+        let positions = Arc::clone(&positions);
+        let songs = Arc::clone(&songs);
+        std::thread::spawn(move || {
+            watch_files(watched, tempo_mode, &reloads, &positions, &songs)
+        });
+    }
+    {
+        let faults = Arc::clone(&faults);
+        let positions = Arc::clone(&positions);
+        std::thread::spawn(move || report_faults(&faults, &positions));
+    }
     let inp = std::io::stdin();
     let mut inplock = inp.lock();
     loop {
         eprintln!("Hit top of loop.");
         let mut line = String::new();
-        inplock.read_line(&mut line).unwrap();
-        if line
+        let read = inplock.read_line(&mut line).unwrap();
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default().to_ascii_lowercase();
+        let track = words
+            .next()
+            .and_then(|word| word.parse::<usize>().ok())
+            .and_then(|idx| mixes.get(idx).map(|control| (idx, control)));
+        if read == 0
+            || line
+                .trim()
+                .starts_with(|c: char| c.eq_ignore_ascii_case(&'q'))
+        {
+            eprintln!("Hit quit.");
+            break;
+        } else if command == "launch" || command == "stop" || command == "loop" {
+            let (idx, control) = match track.and_then(|(idx, _)| Some((idx, clips.get(idx)?))) {
+                Some(track) => track,
+                None => {
+                    eprintln!("Usage: launch <track> [beat|bar|<bars>], stop <track> [beat|bar|<bars>] or loop <track>");
+                    continue;
+                }
+            };
+            let quantize = match words.next().map(str::parse::<Quantize>) {
+                Some(Ok(quantize)) => quantize,
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    continue;
+                }
+                None => Quantize::default(),
+            };
+            match command.as_str() {
+                "launch" => {
+                    control.launch(quantize);
+                    eprintln!("Track {} launching on the next {:?}.", idx, quantize);
+                }
+                "stop" => {
+                    control.stop(quantize);
+                    eprintln!("Track {} stopping on the next {:?}.", idx, quantize);
+                }
+                _ => eprintln!("Track {} looping: {}", idx, control.toggle_loop()),
+            }
+        } else if command == "mute" || command == "solo" || command == "gain" {
+            let (idx, control) = match track {
+                Some(track) => track,
+                None => {
+                    eprintln!("Usage: mute <track>, solo <track> or gain <track> <factor>");
+                    continue;
+                }
+            };
+            match command.as_str() {
+                "mute" => eprintln!("Track {} muted: {}", idx, control.toggle_mute()),
+                "solo" => eprintln!("Track {} soloed: {}", idx, control.toggle_solo()),
+                _ => match words.next().and_then(|word| word.parse::<f32>().ok()) {
+                    Some(gain) if gain.is_finite() && gain >= 0.0 => {
+                        control.set_gain(gain);
+                        eprintln!("Track {} gain: {}", idx, gain);
+                    }
+                    _ => eprintln!("Usage: gain <track> <factor>"),
+                },
+            }
+        } else if line
             .trim()
             .starts_with(|c: char| c.eq_ignore_ascii_case(&'p'))
         {
@@ -295,10 +836,61 @@ fn main() {
         {
             eprintln!("Hit restart.");
             flags.1.store(true, Ordering::Release);
+        } else if line
+            .trim()
+            .starts_with(|c: char| c.eq_ignore_ascii_case(&'s'))
+        {
+            let reports = sections.iter().zip(positions.iter());
+            for (idx, ((section, position), track_markers)) in reports.zip(markers.iter()).enumerate() {
+                let start = section.load(Ordering::Acquire);
+                let [bar, beat, tick] = position;
+                let name = track_markers
+                    .iter()
+                    .find(|marker| marker.kind == MarkerKind::Section && marker.start == start)
+                    .map_or("<none>", |marker| marker.name.as_str());
+                eprintln!(
+                    "Track {} at {}:{}:{:02}, section: {}",
+                    idx,
+                    bar.load(Ordering::Relaxed),
+                    beat.load(Ordering::Relaxed),
+                    tick.load(Ordering::Relaxed),
+                    name
+                );
+            }
+        } else if line
+            .trim()
+            .starts_with(|c: char| c.eq_ignore_ascii_case(&'g'))
+        {
+            match line.trim()[1..].parse::<SeekPosition>() {
+                Ok(position) => {
+                    // Running the tracks up to the position is done here, so
+                    // the realtime thread only has to swap them in.
+                    drop(seek_slot.returned.take());
+                    drop(seek_slot.incoming.take());
+                    let tracks = songs.lock().map(|songs| songs.clone()).unwrap_or_default();
+                    match PreparedSeek::new(tracks, tempo_mode, position) {
+                        Ok(seek) => {
+                            eprintln!("Seeking to {:?}.", position);
+                            let _ = seek_slot.incoming.put(Box::new(seek));
+                        }
+                        Err(e) => eprintln!("Could not seek to {:?}: {}", position, e),
+                    }
+                }
+                Err(e) => eprintln!("{}", e),
+            }
         } else if !line.trim().is_empty() {
             eprintln!("Bad cmd: {:?}", line);
         } else {
             eprintln!("Empty line.");
         }
     }
+
+    // Wait for the realtime thread to release any sounding notes before
+    // disconnecting, giving up if the server stopped running the callback.
+    shutdown.0.store(true, Ordering::Release);
+    let deadline = std::time::Instant::now() + Duration::from_secs(1);
+    while !shutdown.1.load(Ordering::Acquire) && std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    active_client.deactivate().unwrap();
 }
@@ -1,10 +1,14 @@
 use crate::const_min;
 use crate::model::{NoteClass, Octave};
+use crate::tuning::Tuning;
 use thiserror::*;
 
 mod notes;
 pub use notes::*;
 
+mod active;
+pub use active::*;
+
 #[derive(Debug, Error)]
 pub enum MessageParseError {
     #[error("Wrong midi tag: expected {expected:b}, but found {actual:b}.")]
@@ -245,6 +249,13 @@ impl MidiNote {
             MidiNote::from_raw(raw_result as u8)
         }
     }
+    /// Gets the frequency of this note in Hz in the given tuning, or `None`
+    /// if the tuning leaves its key unmapped.
+    pub fn frequency_in(&self, tuning: &Tuning) -> Option<f64> {
+        tuning.frequency(*self)
+    }
+    /// Gets the frequency of this note in Hz in 12-TET with A440, which is
+    /// only right for songs without a tuning. Use `frequency_in` otherwise.
     pub fn frequency(&self) -> f64 {
         // Note -> freq
         // Given a Midi note number `a`, get the frequency in Hz.
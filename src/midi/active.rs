@@ -0,0 +1,214 @@
+use super::{MidiChannel, MidiMessage, MidiNote, NoteOff, PressVelocity, RawMessage};
+
+const CONTROL_CHANGE: u8 = 0xB0;
+const ALL_SOUND_OFF: u8 = 120;
+const ALL_NOTES_OFF: u8 = 123;
+
+/// The notes currently sounding on each output port, per channel.
+///
+/// The whole table is allocated up front, so recording and releasing notes
+/// is safe to do on the realtime thread.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ActiveNotes {
+    /// One bit per note number, for each channel of each port.
+    ports: Vec<[u128; 16]>,
+    /// The ports that still need to be silenced, because the messages
+    /// releasing them did not fit.
+    unreleased: Vec<bool>,
+}
+
+impl ActiveNotes {
+    pub fn new(num_ports: usize) -> Self {
+        ActiveNotes {
+            ports: vec![[0; 16]; num_ports],
+            unreleased: vec![false; num_ports],
+        }
+    }
+
+    /// Updates the table with a message that was sent to port `port`.
+    pub fn record(&mut self, port: usize, msg: MidiMessage) {
+        let channels = match self.ports.get_mut(port) {
+            Some(channels) => channels,
+            None => return,
+        };
+        match msg {
+            MidiMessage::NoteOn(data) if data.vel().as_u8() > 0 => {
+                channels[usize::from(data.channel().as_u8())] |= 1 << data.note().as_u8();
+            }
+            MidiMessage::NoteOn(data) => {
+                channels[usize::from(data.channel().as_u8())] &= !(1 << data.note().as_u8());
+            }
+            MidiMessage::NoteOff(data) => {
+                channels[usize::from(data.channel().as_u8())] &= !(1 << data.note().as_u8());
+            }
+            MidiMessage::Other(raw) => {
+                let bytes = raw.bytes();
+                let is_all_off = bytes.len() == 3
+                    && bytes[0] & 0xF0 == CONTROL_CHANGE
+                    && (bytes[1] == ALL_SOUND_OFF || bytes[1] == ALL_NOTES_OFF);
+                if is_all_off {
+                    channels[usize::from(bytes[0] & 0x0F)] = 0;
+                }
+            }
+        }
+    }
+
+    /// Sends a message to port `port` with `write`, which returns whether it
+    /// fit, and updates the table if it did.
+    ///
+    /// Messages that do not fit are dropped. A dropped NoteOn just goes
+    /// unheard, but a dropped release would leave a note hanging, so the
+    /// port is marked as still needing to be silenced instead.
+    pub fn send(&mut self, port: usize, msg: MidiMessage, write: impl FnOnce(MidiMessage) -> bool) -> bool {
+        if write(msg) {
+            self.record(port, msg);
+            return true;
+        }
+        let releases = match msg {
+            MidiMessage::NoteOn(data) => data.vel().as_u8() == 0,
+            MidiMessage::NoteOff(_) => true,
+            MidiMessage::Other(_) => false,
+        };
+        if releases {
+            self.set_unreleased(port, true);
+        }
+        false
+    }
+
+    /// Gets the number of notes sounding on port `port`.
+    pub fn count(&self, port: usize) -> usize {
+        self.ports.get(port).map_or(0, |channels| {
+            channels
+                .iter()
+                .map(|notes| notes.count_ones() as usize)
+                .sum()
+        })
+    }
+
+    /// Forgets all notes sounding on port `port`, without releasing them.
+    pub fn clear(&mut self, port: usize) {
+        if let Some(channels) = self.ports.get_mut(port) {
+            *channels = [0; 16];
+        }
+    }
+
+    /// Marks whether port `port` still needs to be silenced.
+    pub fn set_unreleased(&mut self, port: usize, unreleased: bool) {
+        if let Some(slot) = self.unreleased.get_mut(port) {
+            *slot = unreleased;
+        }
+    }
+
+    pub fn is_unreleased(&self, port: usize) -> bool {
+        self.unreleased.get(port).copied().unwrap_or(false)
+    }
+
+    /// Checks whether any port still needs to be silenced.
+    pub fn any_unreleased(&self) -> bool {
+        self.unreleased.iter().any(|unreleased| *unreleased)
+    }
+
+    /// Forgets all notes sounding on port `port`, returning the
+    /// `NoteOff` messages that release them.
+    pub fn release(&mut self, port: usize) -> impl Iterator<Item = MidiMessage> {
+        let channels = self
+            .ports
+            .get_mut(port)
+            .map(std::mem::take)
+            .unwrap_or([0; 16]);
+        MidiChannel::all().iter().flat_map(move |channel| {
+            let notes = channels[usize::from(channel.as_u8())];
+            MidiNote::all()
+                .iter()
+                .filter(move |note| notes & (1 << note.as_u8()) != 0)
+                .map(move |note| {
+                    let vel = PressVelocity::from_raw(0).unwrap();
+                    MidiMessage::NoteOff(NoteOff::new(*channel, *note, vel))
+                })
+        })
+    }
+}
+
+/// Gets the messages that silence every channel of a port without knowing
+/// which notes are sounding: an All Notes Off (CC 123) followed by an All
+/// Sound Off (CC 120) on each channel.
+pub fn all_notes_off() -> impl Iterator<Item = MidiMessage> {
+    MidiChannel::all().iter().flat_map(|channel| {
+        let status = CONTROL_CHANGE | channel.as_u8();
+        let notes_off = RawMessage::from_raw(&[status, ALL_NOTES_OFF, 0]);
+        let sound_off = RawMessage::from_raw(&[status, ALL_SOUND_OFF, 0]);
+        std::iter::once(notes_off)
+            .chain(std::iter::once(sound_off))
+            .map(MidiMessage::Other)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::NoteOn;
+
+    #[test]
+    fn test_active_notes() {
+        let channel = |raw| MidiChannel::from_raw(raw).unwrap();
+        let note = |raw| MidiNote::from_raw(raw).unwrap();
+        let vel = |raw| PressVelocity::from_raw(raw).unwrap();
+        let on = |chan, key, velocity| {
+            MidiMessage::NoteOn(NoteOn::new(channel(chan), note(key), vel(velocity)))
+        };
+        let off = |chan, key| MidiMessage::NoteOff(NoteOff::new(channel(chan), note(key), vel(0)));
+        let control = |chan: u8, cc| {
+            MidiMessage::Other(RawMessage::from_raw(&[CONTROL_CHANGE | chan, cc, 0]))
+        };
+
+        let mut active = ActiveNotes::new(2);
+        active.record(0, on(0, 60, 90));
+        active.record(0, on(0, 64, 90));
+        active.record(0, on(3, 60, 90));
+        active.record(1, on(0, 72, 90));
+        // Out of range ports are ignored.
+        active.record(2, on(0, 72, 90));
+        assert_eq!((3, 1, 0), (active.count(0), active.count(1), active.count(2)));
+
+        // Both a NoteOff and a NoteOn with no velocity end a note.
+        active.record(0, off(0, 64));
+        active.record(1, on(0, 72, 0));
+        assert_eq!((2, 0), (active.count(0), active.count(1)));
+
+        active.record(0, on(5, 10, 90));
+        active.record(0, on(6, 11, 90));
+        active.record(0, control(5, ALL_NOTES_OFF));
+        active.record(0, control(6, ALL_SOUND_OFF));
+        // Other controllers leave the notes alone.
+        active.record(0, control(0, 7));
+        assert_eq!(2, active.count(0));
+
+        assert_eq!(vec![off(0, 60), off(3, 60)], active.release(0).collect::<Vec<_>>());
+        assert_eq!(0, active.count(0));
+        assert_eq!(0, active.release(0).count());
+
+        active.record(1, on(2, 40, 90));
+        active.set_unreleased(1, true);
+        assert!(active.is_unreleased(1) && active.any_unreleased());
+        active.clear(1);
+        active.set_unreleased(1, false);
+        assert_eq!((0, false), (active.count(1), active.any_unreleased()));
+
+        // Messages that do not fit are left out of the table, and a release
+        // that does not fit leaves the port to be silenced.
+        let mut active = ActiveNotes::new(2);
+        assert!(active.send(0, on(0, 60, 90), |_| true));
+        assert!(!active.send(0, on(0, 62, 90), |_| false));
+        assert_eq!((1, false), (active.count(0), active.is_unreleased(0)));
+        assert!(!active.send(0, off(0, 60), |_| false));
+        assert_eq!((1, true), (active.count(0), active.is_unreleased(0)));
+        assert!(!active.send(1, on(0, 60, 0), |_| false));
+        assert!(active.is_unreleased(1));
+
+        let silence: Vec<_> = all_notes_off().collect();
+        assert_eq!(32, silence.len());
+        assert_eq!(control(0, ALL_NOTES_OFF), silence[0]);
+        assert_eq!(control(0, ALL_SOUND_OFF), silence[1]);
+        assert_eq!(control(15, ALL_SOUND_OFF), silence[31]);
+    }
+}
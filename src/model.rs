@@ -1,3 +1,8 @@
+use std::fmt;
+
+mod interval;
+pub use interval::*;
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum NoteClass {
     C,
@@ -90,6 +95,187 @@ impl NoteClass {
 
 impl NoteClass {}
 
+/// The letter name of a note, without any accidental.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Letter {
+    C,
+    D,
+    E,
+    F,
+    G,
+    A,
+    B,
+}
+
+#[allow(dead_code)]
+impl Letter {
+    pub const fn all() -> &'static [Letter] {
+        &[
+            Letter::C,
+            Letter::D,
+            Letter::E,
+            Letter::F,
+            Letter::G,
+            Letter::A,
+            Letter::B,
+        ]
+    }
+    /// The position of this letter in the sequence C D E F G A B.
+    pub const fn index(&self) -> u8 {
+        match self {
+            Letter::C => 0,
+            Letter::D => 1,
+            Letter::E => 2,
+            Letter::F => 3,
+            Letter::G => 4,
+            Letter::A => 5,
+            Letter::B => 6,
+        }
+    }
+    pub const fn from_index(idx: u8) -> Self {
+        match idx % 7 {
+            0 => Letter::C,
+            1 => Letter::D,
+            2 => Letter::E,
+            3 => Letter::F,
+            4 => Letter::G,
+            5 => Letter::A,
+            // Always 6
+            _ => Letter::B,
+        }
+    }
+    /// Gets the letter `steps` letters above this one, wrapping around after B.
+    pub const fn shift(&self, steps: i8) -> Self {
+        let raw = (self.index() as i16 + steps as i16).rem_euclid(7);
+        Letter::from_index(raw as u8)
+    }
+    pub const fn natural(&self) -> NoteClass {
+        match self {
+            Letter::C => NoteClass::C,
+            Letter::D => NoteClass::D,
+            Letter::E => NoteClass::E,
+            Letter::F => NoteClass::F,
+            Letter::G => NoteClass::G,
+            Letter::A => NoteClass::A,
+            Letter::B => NoteClass::B,
+        }
+    }
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Letter::C => "C",
+            Letter::D => "D",
+            Letter::E => "E",
+            Letter::F => "F",
+            Letter::G => "G",
+            Letter::A => "A",
+            Letter::B => "B",
+        }
+    }
+}
+
+/// A note as it is written: a letter plus a number of sharps (positive)
+/// or flats (negative).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct SpelledNote {
+    letter: Letter,
+    accidental: i8,
+}
+
+/// The most sharps or flats a `SpelledNote` can have.
+pub const MAX_ACCIDENTALS: i8 = 2;
+
+#[allow(dead_code)]
+impl SpelledNote {
+    pub const fn new(letter: Letter, accidental: i8) -> Option<Self> {
+        if accidental > MAX_ACCIDENTALS || accidental < -MAX_ACCIDENTALS {
+            None
+        } else {
+            Some(SpelledNote { letter, accidental })
+        }
+    }
+    pub const fn natural(letter: Letter) -> Self {
+        SpelledNote {
+            letter,
+            accidental: 0,
+        }
+    }
+    /// Spells `note` using `letter`, if it is at most `MAX_ACCIDENTALS` away.
+    pub const fn with_letter(note: NoteClass, letter: Letter) -> Option<Self> {
+        let diff = (note.as_u8() as i8 - letter.natural().as_u8() as i8).rem_euclid(12);
+        let accidental = if diff > 6 { diff - 12 } else { diff };
+        SpelledNote::new(letter, accidental)
+    }
+    /// Spells `note` with a sharp if it is not a natural.
+    pub const fn sharp(note: NoteClass) -> Self {
+        match SpelledNote::with_letter(note, Letter::from_index(sharp_letter_idx(note))) {
+            Some(spelled) => spelled,
+            None => SpelledNote::natural(Letter::C),
+        }
+    }
+    /// Spells `note` with a flat if it is not a natural.
+    pub const fn flat(note: NoteClass) -> Self {
+        let letter = Letter::from_index(sharp_letter_idx(note));
+        let natural = SpelledNote::natural(letter);
+        if natural.note_class().as_u8() == note.as_u8() {
+            natural
+        } else {
+            SpelledNote {
+                letter: letter.shift(1),
+                accidental: -1,
+            }
+        }
+    }
+    pub const fn letter(&self) -> Letter {
+        self.letter
+    }
+    pub const fn accidental(&self) -> i8 {
+        self.accidental
+    }
+    pub const fn note_class(&self) -> NoteClass {
+        self.letter.natural().shift(self.accidental)
+    }
+    /// How many octaves the written octave differs from the octave of the
+    /// sounding pitch, IE `B#3` sounds in octave 4 and `Cb4` in octave 3.
+    pub const fn octave_offset(&self) -> i8 {
+        let raw = self.letter.natural().as_u8() as i8 + self.accidental;
+        raw.div_euclid(12)
+    }
+    /// Gets the octave this note sounds in when written in octave `written`.
+    pub const fn sounding_octave(&self, written: Octave) -> Option<Octave> {
+        Octave::from_raw(written.as_raw() + self.octave_offset())
+    }
+}
+
+/// The letter used to spell a note class with sharps.
+const fn sharp_letter_idx(note: NoteClass) -> u8 {
+    match note {
+        NoteClass::C | NoteClass::Cs => 0,
+        NoteClass::D | NoteClass::Ds => 1,
+        NoteClass::E => 2,
+        NoteClass::F | NoteClass::Fs => 3,
+        NoteClass::G | NoteClass::Gs => 4,
+        NoteClass::A | NoteClass::As => 5,
+        NoteClass::B => 6,
+    }
+}
+
+impl fmt::Display for SpelledNote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.letter.name())?;
+        let symbol = if self.accidental > 0 { "#" } else { "b" };
+        for _ in 0..self.accidental.abs() {
+            f.write_str(symbol)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<NoteClass> for SpelledNote {
+    fn from(note: NoteClass) -> Self {
+        SpelledNote::sharp(note)
+    }
+}
+
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct Octave(i8);
 
@@ -134,6 +320,7 @@ impl NoteKey {
         self.notes_with_root |= mask;
         self
     }
+    #[allow(dead_code)]
     const fn without_note(mut self, note: NoteClass) -> Self {
         let mask = !(1 << (note.as_u8()));
         self.notes_with_root &= mask;
@@ -157,6 +344,7 @@ impl NoteKey {
         retvl
     }
 
+    #[allow(dead_code)]
     pub const fn minor(root: NoteClass) -> Self {
         let mut retvl = Self::major(root);
         retvl = retvl.without_note(root.shift(4)).with_note(root.shift(3));
@@ -165,6 +353,70 @@ impl NoteKey {
         retvl
     }
 
+    /// Builds a key from the semitone offsets of its notes above `root`.
+    /// The root itself is always part of the key.
+    pub const fn from_intervals(root: NoteClass, intervals: &[u8]) -> Self {
+        let mut retvl = Self::empty();
+        retvl.notes_with_root |= (root.as_u8() as u16) << 12;
+        retvl = retvl.with_note(root);
+        let mut idx = 0;
+        while idx < intervals.len() {
+            retvl = retvl.with_note(root.shift((intervals[idx] % 12) as i8));
+            idx += 1;
+        }
+        retvl
+    }
+
+    pub const fn from_scale(root: NoteClass, scale: ScaleKind) -> Self {
+        Self::from_intervals(root, scale.intervals())
+    }
+
+    /// Gets the mode of this key starting on its `degree`th note, IE the
+    /// second mode of C major is D dorian.
+    #[allow(dead_code)]
+    pub const fn mode(&self, degree: isize) -> Self {
+        let new_root = self.nth(degree);
+        let notes = self.notes_with_root & NOTES_MASK;
+        NoteKey {
+            notes_with_root: notes | ((new_root.as_u8() as u16) << 12),
+        }
+    }
+
+    /// Gets the position of `note` in this key counting up from the root,
+    /// or `None` if the note is not in the key.
+    #[allow(dead_code)]
+    pub const fn degree_of(&self, note: NoteClass) -> Option<usize> {
+        if !self.contains(note) {
+            return None;
+        }
+        let mut degree = 0;
+        let mut cur = self.root();
+        while cur.as_u8() != note.as_u8() {
+            if self.contains(cur.shift(1)) {
+                degree += 1;
+            }
+            cur = cur.shift(1);
+        }
+        Some(degree)
+    }
+
+    /// Whether every note of this key is also in `other`.
+    #[allow(dead_code)]
+    pub const fn is_subset_of(&self, other: &NoteKey) -> bool {
+        let self_notes = self.notes_with_root & NOTES_MASK;
+        let other_notes = other.notes_with_root & NOTES_MASK;
+        self_notes & !other_notes == 0
+    }
+
+    /// Gets the semitone offsets of every note in this key above the root.
+    #[allow(dead_code)]
+    pub fn intervals(&self) -> Vec<u8> {
+        let root = self.root();
+        (0..12)
+            .filter(|offset| self.contains(root.shift(*offset as i8)))
+            .collect()
+    }
+
     #[allow(dead_code)]
     pub const fn equivalent(&self, other: &NoteKey) -> bool {
         let self_notes = self.notes_with_root & NOTES_MASK;
@@ -185,6 +437,63 @@ impl NoteKey {
         let notes = self.notes_with_root & NOTES_MASK;
         notes.count_ones() as usize
     }
+    /// Spells every note of a seven-note key on consecutive letters, starting
+    /// from `root`. Returns the spellings and the total number of accidentals,
+    /// or `None` if a note would need more than `MAX_ACCIDENTALS`.
+    fn spell_degrees(&self, root: SpelledNote) -> Option<([SpelledNote; 7], u32)> {
+        let mut retvl = [root; 7];
+        let mut total = 0;
+        for (idx, spelled) in retvl.iter_mut().enumerate() {
+            let letter = root.letter().shift(idx as i8);
+            *spelled = SpelledNote::with_letter(self.nth(idx as isize), letter)?;
+            total += spelled.accidental().unsigned_abs() as u32;
+        }
+        Some((retvl, total))
+    }
+
+    /// Spells the notes of a seven-note key, picking the spelling of the
+    /// root that needs the fewest accidentals and preferring sharps on a tie.
+    fn degree_spellings(&self) -> Option<[SpelledNote; 7]> {
+        if self.len() != 7 {
+            return None;
+        }
+        let root = self.root();
+        let sharp = self.spell_degrees(SpelledNote::sharp(root));
+        let flat = self.spell_degrees(SpelledNote::flat(root));
+        match (sharp, flat) {
+            (Some((sharp, sharp_count)), Some((flat, flat_count))) => {
+                Some(if flat_count < sharp_count { flat } else { sharp })
+            }
+            (Some((spelled, _)), None) | (None, Some((spelled, _))) => Some(spelled),
+            (None, None) => None,
+        }
+    }
+
+    /// Whether notes in this key are written with flats rather than sharps.
+    pub fn uses_flats(&self) -> bool {
+        let degrees = match self.degree_spellings() {
+            Some(degrees) => degrees,
+            None => match NoteKey::major(self.root()).degree_spellings() {
+                Some(degrees) => degrees,
+                None => return false,
+            },
+        };
+        degrees.iter().any(|spelled| spelled.accidental() < 0)
+    }
+
+    /// Spells a note the way it would be written in this key, IE `Bb` rather
+    /// than `A#` in F major. Notes outside the key follow the key's accidentals.
+    pub fn spell(&self, note: NoteClass) -> SpelledNote {
+        let in_key = self
+            .degree_spellings()
+            .and_then(|degrees| degrees.iter().copied().find(|d| d.note_class() == note));
+        match in_key {
+            Some(spelled) => spelled,
+            None if self.uses_flats() => SpelledNote::flat(note),
+            None => SpelledNote::sharp(note),
+        }
+    }
+
     pub const fn nth(&self, keystep: isize) -> NoteClass {
         let mapped_step = if keystep < 0 {
             self.len() as isize + (keystep % self.len() as isize)
@@ -206,6 +515,156 @@ impl NoteKey {
     }
 }
 
+/// The built-in scales and modes a `NoteKey` can be built from.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ScaleKind {
+    Major,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Minor,
+    Locrian,
+    HarmonicMinor,
+    MelodicMinor,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    WholeTone,
+    /// The diminished scale starting with a whole step.
+    Diminished,
+    /// The diminished scale starting with a half step.
+    HalfWhole,
+}
+
+#[allow(dead_code)]
+impl ScaleKind {
+    pub const fn all() -> &'static [ScaleKind] {
+        &[
+            ScaleKind::Major,
+            ScaleKind::Dorian,
+            ScaleKind::Phrygian,
+            ScaleKind::Lydian,
+            ScaleKind::Mixolydian,
+            ScaleKind::Minor,
+            ScaleKind::Locrian,
+            ScaleKind::HarmonicMinor,
+            ScaleKind::MelodicMinor,
+            ScaleKind::MajorPentatonic,
+            ScaleKind::MinorPentatonic,
+            ScaleKind::Blues,
+            ScaleKind::WholeTone,
+            ScaleKind::Diminished,
+            ScaleKind::HalfWhole,
+        ]
+    }
+    /// The semitone offsets of each note of the scale above its root.
+    pub const fn intervals(&self) -> &'static [u8] {
+        match self {
+            ScaleKind::Major => &[0, 2, 4, 5, 7, 9, 11],
+            ScaleKind::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            ScaleKind::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            ScaleKind::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            ScaleKind::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            ScaleKind::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            ScaleKind::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            ScaleKind::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            ScaleKind::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            ScaleKind::MajorPentatonic => &[0, 2, 4, 7, 9],
+            ScaleKind::MinorPentatonic => &[0, 3, 5, 7, 10],
+            ScaleKind::Blues => &[0, 3, 5, 6, 7, 10],
+            ScaleKind::WholeTone => &[0, 2, 4, 6, 8, 10],
+            ScaleKind::Diminished => &[0, 2, 3, 5, 6, 8, 9, 11],
+            ScaleKind::HalfWhole => &[0, 1, 3, 4, 6, 7, 9, 10],
+        }
+    }
+    pub const fn name(&self) -> &'static str {
+        match self {
+            ScaleKind::Major => "major",
+            ScaleKind::Dorian => "dorian",
+            ScaleKind::Phrygian => "phrygian",
+            ScaleKind::Lydian => "lydian",
+            ScaleKind::Mixolydian => "mixolydian",
+            ScaleKind::Minor => "minor",
+            ScaleKind::Locrian => "locrian",
+            ScaleKind::HarmonicMinor => "harmonic-minor",
+            ScaleKind::MelodicMinor => "melodic-minor",
+            ScaleKind::MajorPentatonic => "major-pentatonic",
+            ScaleKind::MinorPentatonic => "minor-pentatonic",
+            ScaleKind::Blues => "blues",
+            ScaleKind::WholeTone => "whole-tone",
+            ScaleKind::Diminished => "diminished",
+            ScaleKind::HalfWhole => "half-whole",
+        }
+    }
+    /// Finds a scale by name, also accepting the names of the church modes.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let lowered = name.to_ascii_lowercase();
+        match lowered.as_str() {
+            "ionian" => Some(ScaleKind::Major),
+            "aeolian" => Some(ScaleKind::Minor),
+            other => ScaleKind::all()
+                .iter()
+                .copied()
+                .find(|kind| kind.name() == other),
+        }
+    }
+}
+
+/// A dynamic marking, from softest to loudest.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Dynamic {
+    Ppp,
+    Pp,
+    P,
+    Mp,
+    Mf,
+    F,
+    Ff,
+    Fff,
+}
+
+#[allow(dead_code)]
+impl Dynamic {
+    pub const fn all() -> &'static [Dynamic] {
+        &[
+            Dynamic::Ppp,
+            Dynamic::Pp,
+            Dynamic::P,
+            Dynamic::Mp,
+            Dynamic::Mf,
+            Dynamic::F,
+            Dynamic::Ff,
+            Dynamic::Fff,
+        ]
+    }
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Dynamic::Ppp => "ppp",
+            Dynamic::Pp => "pp",
+            Dynamic::P => "p",
+            Dynamic::Mp => "mp",
+            Dynamic::Mf => "mf",
+            Dynamic::F => "f",
+            Dynamic::Ff => "ff",
+            Dynamic::Fff => "fff",
+        }
+    }
+    /// The MIDI velocity this marking maps to unless a song overrides it.
+    pub const fn default_velocity(&self) -> u8 {
+        match self {
+            Dynamic::Ppp => 16,
+            Dynamic::Pp => 33,
+            Dynamic::P => 49,
+            Dynamic::Mp => 64,
+            Dynamic::Mf => 80,
+            Dynamic::F => 96,
+            Dynamic::Ff => 112,
+            Dynamic::Fff => 127,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -217,6 +676,63 @@ mod tests {
         assert_eq!(NoteClass::As, c.shift(-2));
     }
 
+    #[test]
+    fn test_spelling() {
+        let f_major = NoteKey::major(NoteClass::F);
+        assert_eq!("Bb", f_major.spell(NoteClass::As).to_string());
+        assert_eq!("Db", f_major.spell(NoteClass::Cs).to_string());
+        let e_major = NoteKey::major(NoteClass::E);
+        assert_eq!("D#", e_major.spell(NoteClass::Ds).to_string());
+        // Db major needs fewer accidentals than C# major, but C# minor fewer than Db minor.
+        assert_eq!("Db", NoteKey::major(NoteClass::Cs).spell(NoteClass::Cs).to_string());
+        let cs_minor = NoteKey::minor(NoteClass::Cs);
+        assert_eq!("C#", cs_minor.spell(NoteClass::Cs).to_string());
+        // F# and Gb major tie, so sharps win.
+        let fs_major = NoteKey::major(NoteClass::Fs);
+        assert_eq!("E#", fs_major.spell(NoteClass::F).to_string());
+
+        let b_sharp = SpelledNote::new(Letter::B, 1).unwrap();
+        assert_eq!(NoteClass::C, b_sharp.note_class());
+        assert_eq!(1, b_sharp.octave_offset());
+        let c_flat = SpelledNote::new(Letter::C, -1).unwrap();
+        assert_eq!(NoteClass::B, c_flat.note_class());
+        assert_eq!(-1, c_flat.octave_offset());
+        assert_eq!(None, SpelledNote::new(Letter::C, 3));
+    }
+
+    #[test]
+    fn test_scales() {
+        let c_major = NoteKey::major(NoteClass::C);
+        assert_eq!(c_major, NoteKey::from_scale(NoteClass::C, ScaleKind::Major));
+        assert_eq!(
+            NoteKey::minor(NoteClass::A),
+            NoteKey::from_scale(NoteClass::A, ScaleKind::Minor)
+        );
+        let modes = [
+            ScaleKind::Major,
+            ScaleKind::Dorian,
+            ScaleKind::Phrygian,
+            ScaleKind::Lydian,
+            ScaleKind::Mixolydian,
+            ScaleKind::Minor,
+            ScaleKind::Locrian,
+        ];
+        for (degree, kind) in modes.iter().enumerate() {
+            let mode = c_major.mode(degree as isize);
+            let expected = NoteKey::from_scale(c_major.nth(degree as isize), *kind);
+            assert_eq!(expected, mode, "{}", kind.name());
+            assert_eq!(kind.intervals(), mode.intervals().as_slice());
+        }
+
+        let blues = NoteKey::from_scale(NoteClass::A, ScaleKind::Blues);
+        assert_eq!(Some(3), blues.degree_of(NoteClass::Ds));
+        assert_eq!(None, blues.degree_of(NoteClass::B));
+        assert!(NoteKey::from_scale(NoteClass::A, ScaleKind::MinorPentatonic).is_subset_of(&blues));
+        assert!(!blues.is_subset_of(&NoteKey::minor(NoteClass::A)));
+        assert_eq!(Some(ScaleKind::Minor), ScaleKind::from_name("Aeolian"));
+        assert_eq!(Some(ScaleKind::WholeTone), ScaleKind::from_name("whole-tone"));
+    }
+
     #[test]
     fn test_key() {
         let c_major = NoteKey::major(NoteClass::C);
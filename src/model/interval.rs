@@ -0,0 +1,283 @@
+use super::{Letter, NoteKey, SpelledNote};
+use crate::midi::MidiNote;
+
+use std::fmt;
+
+/// The semitones spanned by the major and perfect intervals from a unison
+/// up to a seventh.
+const NATURAL_SEMITONES: [i8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum IntervalQuality {
+    /// Diminished by the given number of semitones, IE 2 for doubly diminished.
+    Diminished(u8),
+    Minor,
+    Perfect,
+    Major,
+    /// Augmented by the given number of semitones, IE 2 for doubly augmented.
+    Augmented(u8),
+}
+
+/// The distance between two written notes, counted both in letters and in
+/// semitones, IE a major third or a diminished fifth.
+///
+/// Intervals always go upwards; an interval is never smaller than a unison
+/// by letter, though it may be by pitch (a diminished unison).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Interval {
+    /// The number of letters spanned, one less than the interval's number.
+    steps: u8,
+    semitones: i8,
+}
+
+#[allow(dead_code)]
+impl Interval {
+    pub const UNISON: Interval = Interval::raw(0, 0);
+    pub const MINOR_SECOND: Interval = Interval::raw(1, 1);
+    pub const MAJOR_SECOND: Interval = Interval::raw(1, 2);
+    pub const MINOR_THIRD: Interval = Interval::raw(2, 3);
+    pub const MAJOR_THIRD: Interval = Interval::raw(2, 4);
+    pub const PERFECT_FOURTH: Interval = Interval::raw(3, 5);
+    pub const AUGMENTED_FOURTH: Interval = Interval::raw(3, 6);
+    pub const DIMINISHED_FIFTH: Interval = Interval::raw(4, 6);
+    pub const PERFECT_FIFTH: Interval = Interval::raw(4, 7);
+    pub const AUGMENTED_FIFTH: Interval = Interval::raw(4, 8);
+    pub const MINOR_SIXTH: Interval = Interval::raw(5, 8);
+    pub const MAJOR_SIXTH: Interval = Interval::raw(5, 9);
+    pub const DIMINISHED_SEVENTH: Interval = Interval::raw(6, 9);
+    pub const MINOR_SEVENTH: Interval = Interval::raw(6, 10);
+    pub const MAJOR_SEVENTH: Interval = Interval::raw(6, 11);
+    pub const OCTAVE: Interval = Interval::raw(7, 12);
+
+    const fn raw(steps: u8, semitones: i8) -> Self {
+        Interval { steps, semitones }
+    }
+
+    /// The semitones a natural interval with this many steps spans.
+    const fn natural_semitones(steps: u8) -> i16 {
+        NATURAL_SEMITONES[(steps % 7) as usize] as i16 + 12 * (steps / 7) as i16
+    }
+
+    const fn is_perfect_class(steps: u8) -> bool {
+        matches!(steps % 7, 0 | 3 | 4)
+    }
+
+    /// Builds an interval from its quality and number, IE `Minor, 3` for a
+    /// minor third. Returns `None` if the quality does not apply to the
+    /// number, like a perfect third, or if the number is 0.
+    pub const fn new(quality: IntervalQuality, number: u8) -> Option<Self> {
+        if number == 0 {
+            return None;
+        }
+        let steps = number - 1;
+        let natural = Interval::natural_semitones(steps);
+        let perfect = Interval::is_perfect_class(steps);
+        let offset = match quality {
+            IntervalQuality::Perfect if perfect => 0,
+            IntervalQuality::Major if !perfect => 0,
+            IntervalQuality::Minor if !perfect => -1,
+            IntervalQuality::Perfect | IntervalQuality::Major | IntervalQuality::Minor => {
+                return None
+            }
+            IntervalQuality::Augmented(count) => count as i16,
+            IntervalQuality::Diminished(count) if perfect => -(count as i16),
+            IntervalQuality::Diminished(count) => -1 - count as i16,
+        };
+        let semitones = natural + offset;
+        if semitones < i8::MIN as i16 || semitones > i8::MAX as i16 {
+            return None;
+        }
+        Some(Interval::raw(steps, semitones as i8))
+    }
+
+    /// Gets the most common spelling of an interval spanning `semitones`,
+    /// using an augmented fourth for the tritone.
+    pub const fn from_semitones(semitones: u8) -> Self {
+        const STEPS: [u8; 12] = [0, 1, 1, 2, 2, 3, 3, 4, 5, 5, 6, 6];
+        let octaves = semitones / 12;
+        let steps = STEPS[(semitones % 12) as usize] + 7 * octaves;
+        Interval::raw(steps, semitones as i8)
+    }
+
+    /// Gets the interval from `lower` up to the nearest `higher` above it,
+    /// which is always less than an octave by letter.
+    pub const fn between(lower: SpelledNote, higher: SpelledNote) -> Self {
+        let steps = (higher.letter().index() as i16 - lower.letter().index() as i16).rem_euclid(7);
+        let natural = (higher.letter().natural().as_u8() as i16
+            - lower.letter().natural().as_u8() as i16)
+            .rem_euclid(12);
+        let semitones = natural + higher.accidental() as i16 - lower.accidental() as i16;
+        Interval::raw(steps as u8, semitones as i8)
+    }
+
+    /// Gets the interval between two pitches, spelling both as they would be
+    /// written in `key`. Returns `None` if `higher` is below `lower`.
+    pub fn between_pitches(lower: MidiNote, higher: MidiNote, key: &NoteKey) -> Option<Self> {
+        let distance = i16::from(higher.as_u8()) - i16::from(lower.as_u8());
+        if distance < 0 {
+            return None;
+        }
+        let simple = Interval::between(key.spell(lower.note()), key.spell(higher.note()));
+        let octaves = (distance - i16::from(simple.semitones)) / 12;
+        if octaves < 0 {
+            return None;
+        }
+        Some(Interval::raw(
+            simple.steps + 7 * octaves as u8,
+            distance as i8,
+        ))
+    }
+
+    /// The interval's number, IE 3 for any kind of third.
+    pub const fn number(&self) -> u8 {
+        self.steps + 1
+    }
+
+    pub const fn semitones(&self) -> i8 {
+        self.semitones
+    }
+
+    pub const fn quality(&self) -> IntervalQuality {
+        let offset = self.semitones as i16 - Interval::natural_semitones(self.steps);
+        if Interval::is_perfect_class(self.steps) {
+            match offset {
+                0 => IntervalQuality::Perfect,
+                up if up > 0 => IntervalQuality::Augmented(up as u8),
+                down => IntervalQuality::Diminished(-down as u8),
+            }
+        } else {
+            match offset {
+                0 => IntervalQuality::Major,
+                -1 => IntervalQuality::Minor,
+                up if up > 0 => IntervalQuality::Augmented(up as u8),
+                down => IntervalQuality::Diminished((-down - 1) as u8),
+            }
+        }
+    }
+
+    /// Whether the interval spans more than an octave.
+    pub const fn is_compound(&self) -> bool {
+        self.steps > 7
+    }
+
+    /// Removes whole octaves until the interval spans at most an octave.
+    pub const fn simple(&self) -> Self {
+        let mut retvl = *self;
+        while retvl.steps > 7 {
+            retvl.steps -= 7;
+            retvl.semitones -= 12;
+        }
+        retvl
+    }
+
+    /// Inverts the interval within the octave, IE a major third becomes a
+    /// minor sixth. Compound intervals are reduced to simple ones first.
+    pub const fn invert(&self) -> Self {
+        let simple = self.simple();
+        Interval::raw(7 - simple.steps, 12 - simple.semitones)
+    }
+
+    /// Adds `other` to this interval, returning `None` if the result is too
+    /// wide to be represented.
+    pub fn checked_add(self, other: Interval) -> Option<Self> {
+        let steps = self.steps.checked_add(other.steps)?;
+        let semitones = self.semitones.checked_add(other.semitones)?;
+        Some(Interval::raw(steps, semitones))
+    }
+
+    /// Subtracts `other` from this interval, returning `None` if it spans
+    /// more letters than this one does.
+    pub fn checked_sub(self, other: Interval) -> Option<Self> {
+        let steps = self.steps.checked_sub(other.steps)?;
+        let semitones = self.semitones.checked_sub(other.semitones)?;
+        Some(Interval::raw(steps, semitones))
+    }
+
+    /// Transposes a written note up by this interval, keeping the spelling
+    /// correct, IE a major third above `Eb` is `G` and above `E` is `G#`.
+    /// Returns `None` if the result would need more than the allowed accidentals.
+    pub const fn transpose(&self, note: SpelledNote) -> Option<SpelledNote> {
+        let letter: Letter = note.letter().shift(self.steps as i8);
+        let target = note.note_class().shift(self.semitones % 12);
+        SpelledNote::with_letter(target, letter)
+    }
+
+    /// Transposes a pitch up by this interval.
+    pub const fn transpose_pitch(&self, note: MidiNote) -> Option<MidiNote> {
+        note.checked_add(self.semitones)
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (symbol, count) = match self.quality() {
+            IntervalQuality::Diminished(count) => ("d", count),
+            IntervalQuality::Minor => ("m", 1),
+            IntervalQuality::Perfect => ("P", 1),
+            IntervalQuality::Major => ("M", 1),
+            IntervalQuality::Augmented(count) => ("A", count),
+        };
+        for _ in 0..count {
+            f.write_str(symbol)?;
+        }
+        write!(f, "{}", self.number())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::NoteClass;
+
+    #[test]
+    fn test_intervals() {
+        let minor_third = Interval::new(IntervalQuality::Minor, 3).unwrap();
+        assert_eq!(Interval::MINOR_THIRD, minor_third);
+        assert_eq!(None, Interval::new(IntervalQuality::Perfect, 3));
+        assert_eq!(
+            Some(Interval::PERFECT_FIFTH),
+            minor_third.checked_add(Interval::MAJOR_THIRD)
+        );
+        assert_eq!(None, Interval::raw(0, i8::MAX).checked_add(Interval::MINOR_SECOND));
+        assert_eq!(
+            Some(Interval::MINOR_THIRD),
+            Interval::PERFECT_FIFTH.checked_sub(Interval::MAJOR_THIRD)
+        );
+        assert_eq!(Interval::MINOR_SIXTH, Interval::MAJOR_THIRD.invert());
+        assert_eq!(
+            Interval::DIMINISHED_FIFTH,
+            Interval::AUGMENTED_FOURTH.invert()
+        );
+        assert_eq!(Interval::UNISON, Interval::OCTAVE.invert());
+        assert_eq!("A4", Interval::AUGMENTED_FOURTH.to_string());
+        assert_eq!(
+            "M10",
+            Interval::OCTAVE
+                .checked_add(Interval::MAJOR_THIRD)
+                .unwrap()
+                .to_string()
+        );
+
+        let e_flat = SpelledNote::flat(NoteClass::Ds);
+        let g = SpelledNote::natural(Letter::G);
+        assert_eq!(Interval::MAJOR_THIRD, Interval::between(e_flat, g));
+        assert_eq!(
+            Interval::AUGMENTED_FOURTH,
+            Interval::between(
+                SpelledNote::natural(Letter::F),
+                SpelledNote::natural(Letter::B)
+            )
+        );
+        let g_sharp = Interval::MAJOR_THIRD.transpose(SpelledNote::natural(Letter::E));
+        assert_eq!(Some(SpelledNote::sharp(NoteClass::Gs)), g_sharp);
+        assert_eq!(Some(g), Interval::MAJOR_THIRD.transpose(e_flat));
+
+        let key = NoteKey::major(NoteClass::F);
+        let f4 = MidiNote::from_raw(65).unwrap();
+        let b_flat5 = MidiNote::from_raw(82).unwrap();
+        let eleventh = Interval::between_pitches(f4, b_flat5, &key).unwrap();
+        assert_eq!("P11", eleventh.to_string());
+        assert_eq!(Some(b_flat5), eleventh.transpose_pitch(f4));
+        assert_eq!(None, Interval::between_pitches(b_flat5, f4, &key));
+    }
+}
@@ -1,7 +1,17 @@
 mod ast;
 pub use ast::*;
+mod groove;
+pub use groove::*;
+mod voicing;
+pub use voicing::*;
 mod parser;
 pub use parser::*;
 
 mod compiler;
-pub use compiler::*;
\ No newline at end of file
+pub use compiler::*;
+
+mod chords;
+pub use chords::*;
+
+mod lint;
+pub use lint::*;
\ No newline at end of file
@@ -1,8 +1,10 @@
-use crate::midi::{MidiChannel, MidiMessage, PressVelocity};
-use crate::model::{NoteClass, Octave};
+use super::{Groove, VoiceLeading};
+use crate::midi::{MidiChannel, MidiMessage, MidiNote, PressVelocity};
+use crate::model::{Dynamic, Interval, NoteClass, Octave, ScaleKind};
 use crate::track::{BpmInfo, WaitTime};
+use crate::tuning::TuningMethod;
 
-use std::num::NonZeroU16;
+use std::num::{NonZeroU16, NonZeroU32, NonZeroU8};
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum AsmCommand {
@@ -17,6 +19,8 @@ pub enum AsmCommand {
     },
     SetBpm(BpmInfo),
     Label(String),
+    Call(String),
+    Return,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -46,14 +50,76 @@ pub enum LangItem {
     Asm(AsmCommand),
     #[allow(dead_code)]
     SetAttribute(SongAttribute), 
+    Section {
+        name: String,
+        body: Vec<LangItem>,
+    },
+    Arrange(Vec<ArrangeEntry>),
+    Hairpin(Hairpin),
+    DefineGroove {
+        name: String,
+        groove: Groove,
+    },
+    SetGroove(GrooveChoice),
+    /// Declares a scale by the semitone offsets of its notes above the root.
+    DefineScale {
+        name: String,
+        intervals: Vec<u8>,
+    },
+    VoiceLead {
+        settings: VoiceLeading,
+        body: Vec<LangItem>,
+    },
+    /// A block where notes without an octave are placed relative to the
+    /// previous note, starting from `start`.
+    Relative {
+        start: MidiNote,
+        body: Vec<LangItem>,
+    },
+}
+
+/// Which groove to play the rest of the current block with.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum GrooveChoice {
+    Straight,
+    Named(String),
+    Template(Groove),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ArrangeEntry {
+    pub section: String,
+    pub repetitions: NonZeroU16,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum PressModifier {
     Velocity(PressVelocity),
+    Dynamic(Dynamic),
     Channel(MidiChannel),
     Duration(WaitTime),
     Port(OutputLabel),
+    /// Plays the chord in the given inversion, IE 1 puts the third in the bass.
+    Inversion(u8),
+    /// Drops the second-highest note an octave.
+    Drop2,
+    /// Raises the second-lowest note an octave.
+    Open,
+    /// Spreads the chord across this many octaves, which is always at least 2.
+    Spread(NonZeroU8),
+    /// Staggers the start of each chord tone by `step`.
+    Strum {
+        step: WaitTime,
+        direction: StrumDirection,
+    },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum StrumDirection {
+    /// From the lowest note to the highest.
+    Up,
+    /// From the highest note to the lowest.
+    Down,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -86,12 +152,27 @@ impl PressLine {
             _ => None,
         })
     }
+    pub fn dynamic(&self) -> Option<Dynamic> {
+        self.modifiers.iter().find_map(|md| match md {
+            PressModifier::Dynamic(d) => Some(*d),
+            _ => None,
+        })
+    }
+}
+
+/// The octave a press is written in.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum PressOctave {
+    Absolute(Octave),
+    /// The octave closest to the previous note in a `relative` block, moved
+    /// up or down by this many octaves with `'` and `,` marks.
+    Relative(i8),
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct ChordPress {
     pub root: NoteClass,
-    pub octave: Octave,
+    pub octave: PressOctave,
     pub kind: ChordKind,
     pub modifiers: Vec<PressModifier>,
 }
@@ -121,6 +202,12 @@ impl ChordPress {
             _ => None,
         })
     }
+    pub fn dynamic(&self) -> Option<Dynamic> {
+        self.modifiers.iter().find_map(|md| match md {
+            PressModifier::Dynamic(d) => Some(*d),
+            _ => None,
+        })
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -133,11 +220,116 @@ pub enum ChordKind {
     Minor7,
 }
 
+impl ChordKind {
+    /// The intervals of each note of the chord above its root.
+    pub const fn intervals(&self) -> &'static [Interval] {
+        match self {
+            ChordKind::Raw => &[Interval::UNISON],
+            ChordKind::Fifth => &[Interval::UNISON, Interval::PERFECT_FIFTH],
+            ChordKind::Major => &[
+                Interval::UNISON,
+                Interval::MAJOR_THIRD,
+                Interval::PERFECT_FIFTH,
+            ],
+            ChordKind::Minor => &[
+                Interval::UNISON,
+                Interval::MINOR_THIRD,
+                Interval::PERFECT_FIFTH,
+            ],
+            ChordKind::Major7 => &[
+                Interval::UNISON,
+                Interval::MAJOR_THIRD,
+                Interval::PERFECT_FIFTH,
+                Interval::MAJOR_SEVENTH,
+            ],
+            ChordKind::Minor7 => &[
+                Interval::UNISON,
+                Interval::MINOR_THIRD,
+                Interval::PERFECT_FIFTH,
+                Interval::MINOR_SEVENTH,
+            ],
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum SongAttribute {
     Signature(BpmInfo), 
     DefaultDuration(WaitTime),
     DefaultChannel(MidiChannel),
+    /// The output presses go to when they do not name one. It is opened
+    /// even if nothing is ever sent to it.
     DefaultPort(OutputLabel),
     DefaultPressVelocity(PressVelocity),
+    DynamicLevel(Dynamic, PressVelocity),
+    Tuning(TuningAttribute),
+    Key(KeyChoice),
+}
+
+/// The key a song is written in, as declared in its header.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct KeyChoice {
+    pub root: NoteClass,
+    pub scale: ScaleChoice,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ScaleChoice {
+    Builtin(ScaleKind),
+    /// A scale declared with `scale NAME = ...`.
+    Named(String),
+}
+
+/// Settings for playing a song in a tuning other than 12-TET with A440.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum TuningAttribute {
+    /// The path to a Scala `.scl` file.
+    Scale(String),
+    /// The path to a Scala `.kbm` keyboard mapping file.
+    KeyMap(String),
+    /// Pins a note to a frequency, IE A4 = 432 Hz.
+    Reference {
+        note: MidiNote,
+        millihertz: NonZeroU32,
+    },
+    Method(TuningMethod),
+}
+
+/// A gradual change in dynamics over a span of the song,
+/// IE a crescendo or diminuendo.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Hairpin {
+    pub from: Dynamic,
+    pub to: Dynamic,
+    pub length: SpanLength,
+    pub curve: VelocityCurve,
+}
+
+/// The length of a span of the song.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SpanLength {
+    Bars(NonZeroU16),
+    Time(WaitTime),
+}
+
+/// How velocities are interpolated across a `Hairpin`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum VelocityCurve {
+    Linear,
+    Exponential,
+}
+
+impl VelocityCurve {
+    /// Gets the velocity `progress` of the way from `from` to `to`, where
+    /// `progress` is between 0 and 1.
+    pub fn interpolate(self, from: u8, to: u8, progress: f64) -> u8 {
+        let (from, to) = (f64::from(from), f64::from(to));
+        let raw = match self {
+            VelocityCurve::Exponential if from > 0.0 && to > 0.0 => {
+                from * (to / from).powf(progress)
+            }
+            VelocityCurve::Linear | VelocityCurve::Exponential => from + (to - from) * progress,
+        };
+        raw.round().clamp(0.0, 127.0) as u8
+    }
 }
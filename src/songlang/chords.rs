@@ -0,0 +1,218 @@
+use super::ChordKind;
+use crate::midi::{MidiMessage, MidiNote};
+use crate::model::{NoteClass, NoteKey, Octave, SpelledNote};
+use crate::track::{CompiledTrack, TrackCursor};
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::time::Duration;
+
+/// Chord kinds in the order they are tried, largest first so that a
+/// seventh chord is not named as the triad it contains.
+const RECOGNIZED_KINDS: [ChordKind; 6] = [
+    ChordKind::Major7,
+    ChordKind::Minor7,
+    ChordKind::Major,
+    ChordKind::Minor,
+    ChordKind::Fifth,
+    ChordKind::Raw,
+];
+
+/// The name of a chord, IE `C4M7/E` for a C major seventh chord
+/// in fourth octave with E in the bass.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ChordName {
+    pub root: SpelledNote,
+    /// The written octave of the lowest sounding root.
+    pub octave: Octave,
+    pub kind: ChordKind,
+    /// The lowest note, if it is not the root.
+    pub bass: Option<SpelledNote>,
+}
+
+impl ChordKind {
+    /// The suffix that selects this kind of chord in a `play` line.
+    pub const fn suffix(&self) -> &'static str {
+        match self {
+            ChordKind::Raw => "",
+            ChordKind::Fifth => "5",
+            ChordKind::Major => "M",
+            ChordKind::Minor => "m",
+            ChordKind::Major7 => "M7",
+            ChordKind::Minor7 => "m7",
+        }
+    }
+}
+
+impl fmt::Display for ChordName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            self.root,
+            self.octave.as_raw(),
+            self.kind.suffix()
+        )?;
+        if let Some(bass) = self.bass {
+            write!(f, "/{}", bass)?;
+        }
+        Ok(())
+    }
+}
+
+/// Names the chord formed by a set of sounding notes, spelling it as it
+/// would be written in `key`.
+///
+/// Doubled notes are ignored. Returns `None` if the notes are empty or do
+/// not form any `ChordKind`.
+pub fn recognize_chord(notes: &[MidiNote], key: &NoteKey) -> Option<ChordName> {
+    let lowest = *notes.iter().min()?;
+    let classes: BTreeSet<u8> = notes.iter().map(|note| note.note().as_u8()).collect();
+
+    // Root position is preferred over inversions when a set of notes
+    // could be read either way.
+    let mut roots: Vec<NoteClass> = vec![lowest.note()];
+    roots.extend(
+        classes
+            .iter()
+            .map(|class| NoteClass::from_u8(*class))
+            .filter(|class| *class != lowest.note()),
+    );
+    for root in roots {
+        for kind in RECOGNIZED_KINDS.iter() {
+            let chord_classes: BTreeSet<u8> = kind
+                .intervals()
+                .iter()
+                .map(|interval| root.shift(interval.semitones()).as_u8())
+                .collect();
+            if chord_classes != classes {
+                continue;
+            }
+            let spelled_root = key.spell(root);
+            let root_pitch = notes.iter().filter(|note| note.note() == root).min()?;
+            let written = root_pitch.octave().as_raw() - spelled_root.octave_offset();
+            let octave = Octave::from_raw(written)?;
+            let bass = if lowest.note() == root {
+                None
+            } else {
+                // Spell the bass from the root so the chord reads correctly,
+                // IE the third of Ab major is C rather than B#.
+                kind.intervals()
+                    .iter()
+                    .find(|interval| root.shift(interval.semitones()) == lowest.note())
+                    .and_then(|interval| interval.transpose(spelled_root))
+            };
+            return Some(ChordName {
+                root: spelled_root,
+                octave,
+                kind: *kind,
+                bass,
+            });
+        }
+    }
+    None
+}
+
+/// Names the chord sounding after each change in the notes a track holds,
+/// up to `end`. Times where nothing is held, or the held notes form no
+/// known chord, are labeled `None`.
+#[allow(dead_code)]
+pub fn label_chords(
+    track: CompiledTrack,
+    key: &NoteKey,
+    end: Duration,
+) -> Vec<(Duration, Option<ChordName>)> {
+    let mut cursor = TrackCursor::new(track);
+    let mut held: Vec<MidiNote> = Vec::new();
+    let mut retvl: Vec<(Duration, Option<ChordName>)> = Vec::new();
+    let mut pending: Option<Duration> = None;
+    let flush = |time: Duration, held: &[MidiNote], retvl: &mut Vec<_>| {
+        let chord = recognize_chord(held, key);
+        let changed = match retvl.last() {
+            Some((_, prev)) => *prev != chord,
+            None => chord.is_some(),
+        };
+        if changed {
+            retvl.push((time, chord));
+        }
+    };
+    for (time, _, message) in cursor.step_until(end) {
+        if let Some(prev) = pending.filter(|prev| *prev != time) {
+            flush(prev, &held, &mut retvl);
+        }
+        pending = Some(time);
+        match message {
+            MidiMessage::NoteOn(data) if data.vel().as_u8() > 0 => held.push(data.note()),
+            MidiMessage::NoteOn(data) => remove_note(&mut held, data.note()),
+            MidiMessage::NoteOff(data) => remove_note(&mut held, data.note()),
+            MidiMessage::Other(_) => {}
+        }
+    }
+    if let Some(prev) = pending {
+        flush(prev, &held, &mut retvl);
+    }
+    retvl
+}
+
+fn remove_note(held: &mut Vec<MidiNote>, note: MidiNote) {
+    if let Some(idx) = held.iter().position(|cur| *cur == note) {
+        held.swap_remove(idx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::songlang::{compile_song, parse_file};
+
+    fn notes(raw: &[u8]) -> Vec<MidiNote> {
+        raw.iter()
+            .map(|n| MidiNote::from_raw(*n).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_recognize_chord() {
+        let c_major = NoteKey::major(NoteClass::C);
+        let name = |raw: &[u8], key: &NoteKey| {
+            recognize_chord(&notes(raw), key).map(|chord| chord.to_string())
+        };
+        assert_eq!(Some("C4M".to_owned()), name(&[60, 64, 67], &c_major));
+        assert_eq!(Some("C4M7/E".to_owned()), name(&[52, 55, 59, 60], &c_major));
+        assert_eq!(
+            Some("A3m7".to_owned()),
+            name(&[57, 60, 64, 67, 69], &c_major)
+        );
+        assert_eq!(Some("G2".to_owned()), name(&[43, 55], &c_major));
+        assert_eq!(None, name(&[60, 61, 62], &c_major));
+        assert_eq!(None, name(&[], &c_major));
+
+        let a_flat = NoteKey::major(NoteClass::Gs);
+        assert_eq!(Some("Ab3M/C".to_owned()), name(&[48, 56, 63], &a_flat));
+    }
+
+    #[test]
+    fn test_label_chords() {
+        let src = "play for 1 beat C4M\n\
+                   play for 1 beat A3m7\n\
+                   WAIT 1 beat\n\
+                   play for 1 beat Eb45\n";
+        let (rest, song) = parse_file(src).unwrap();
+        assert_eq!("", rest.trim());
+        let (track, _) = compile_song(song).unwrap();
+        let a_flat = NoteKey::major(NoteClass::Gs);
+        let labels: Vec<_> = label_chords(track, &a_flat, Duration::from_secs(10))
+            .into_iter()
+            .map(|(time, chord)| (time.as_millis(), chord.map(|chord| chord.to_string())))
+            .collect();
+        // Chords that follow each other directly change without a gap.
+        let expected = vec![
+            (0, Some("C4M".to_owned())),
+            (500, Some("A3m7".to_owned())),
+            (1000, None),
+            (1500, Some("Eb45".to_owned())),
+            (2000, None),
+        ];
+        assert_eq!(expected, labels);
+    }
+}
@@ -1,10 +1,18 @@
-use super::ast::{AsmCommand, ChordKind, LangItem, OutputLabel, PressLine, SongAttribute};
-use crate::midi::{MidiChannel, MidiMessage, MidiNote, NoteOn, PressVelocity};
-use crate::model::NoteKey;
-use crate::track::{BpmInfo, OutputPort, TrackEvent, WaitTime};
+use super::ast::{
+    ArrangeEntry, AsmCommand, GrooveChoice, Hairpin, KeyChoice, LangItem, OutputLabel,
+    PressLine, PressOctave, ScaleChoice, SongAttribute, SpanLength, StrumDirection, TuningAttribute,
+    VelocityCurve,
+};
+use super::{voice_lead, Groove, VoiceLeading, Voicing};
+use crate::midi::{MidiChannel, MidiMessage, MidiNote, NoteOff, NoteOn, PressVelocity};
+use crate::model::{Dynamic, NoteClass, NoteKey, ScaleKind};
+use crate::track::{
+    zero_time_loops, BpmInfo, CompiledTrack, Marker, MarkerKind, OutputPort, TrackEvent, WaitTime,
+};
+use crate::tuning::TuningMethod;
 use crate::utils::ONE_NZU16;
 use std::collections::HashMap;
-use std::num::NonZeroU16;
+use std::num::{NonZeroU16, NonZeroU32};
 use thiserror::*;
 
 #[derive(Debug, Error)]
@@ -24,6 +32,51 @@ pub enum CompilerError {
 
     #[error("Attribute set multiple times: encountered {0:?} and {1:?}")]
     DuplicateAttributes(SongAttribute, SongAttribute),
+
+    #[error("Infinite loop without a wait: the jump at event {jump} back to event {target} never advances time.")]
+    ZeroTimeLoop { jump: usize, target: usize },
+
+    #[error("Section {0:?} was defined more than once.")]
+    DuplicateSection(String),
+
+    #[error("Section {0:?} was arranged but never defined.")]
+    SectionNotFound(String),
+
+    #[error("Section {0:?} was defined but never arranged.")]
+    UnusedSection(String),
+
+    #[error("Groove {0:?} was defined more than once.")]
+    DuplicateGroove(String),
+
+    #[error("Groove {0:?} was used but never defined.")]
+    GrooveNotFound(String),
+
+    #[error("Groove {0:?} would play its subdivisions out of order.")]
+    InvalidGroove(Groove),
+
+    #[error("Groove subdivision 1/{subdivision} does not fit on a grid of {ticks_per_beat} ticks per beat.")]
+    GrooveTooFine {
+        subdivision: NonZeroU16,
+        ticks_per_beat: NonZeroU16,
+    },
+
+    #[error("Scale {0:?} was defined more than once or shadows a built-in scale.")]
+    DuplicateScale(String),
+
+    #[error("Scale {0:?} was used but never defined.")]
+    ScaleNotFound(String),
+
+    #[error("Note {0:?} has no octave, which is only allowed inside a relative block.")]
+    MissingOctave(NoteClass),
+
+    #[error("Relative note {0:?} falls outside of the MIDI note range.")]
+    RelativeOutOfRange(NoteClass),
+
+    #[error("The loop ending at event {jump} lasts {ticks} ticks, which is not a whole number of passes through the active groove, so its repeats would be out of phase.")]
+    GrooveOutOfPhase { jump: usize, ticks: u64 },
+
+    #[error("A hairpin crosses the edge of the loop ending at event {0}, but every repeat of a loop plays the same velocities.")]
+    HairpinAcrossLoop(usize),
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Default)]
@@ -33,6 +86,119 @@ struct SongAttributes {
     bpm: Option<BpmInfo>,
     channel: Option<MidiChannel>,
     outport: Option<OutputLabel>,
+    dynamics: HashMap<Dynamic, PressVelocity>,
+    key: Option<KeyChoice>,
+}
+
+/// The tuning settings declared in a song's header.
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct SongTuning {
+    pub scale: Option<String>,
+    pub keymap: Option<String>,
+    pub reference: Option<(MidiNote, NonZeroU32)>,
+    pub method: Option<TuningMethod>,
+}
+
+impl SongTuning {
+    /// Whether the song asks to be played in anything but 12-TET with A440.
+    pub fn is_retuned(&self) -> bool {
+        self.scale.is_some() || self.keymap.is_some() || self.reference.is_some()
+    }
+
+    /// Gets the reference pitch in Hz, if one was set.
+    pub fn reference_pitch(&self) -> Option<(MidiNote, f64)> {
+        self.reference
+            .map(|(note, millihertz)| (note, f64::from(millihertz.get()) / 1000.0))
+    }
+
+    fn push(&mut self, attr: TuningAttribute) -> Result<(), CompilerError> {
+        let duplicate = |prev: TuningAttribute| {
+            CompilerError::DuplicateAttributes(
+                SongAttribute::Tuning(prev),
+                SongAttribute::Tuning(attr.clone()),
+            )
+        };
+        match attr.clone() {
+            TuningAttribute::Scale(path) => match self.scale.replace(path) {
+                Some(prev) => Err(duplicate(TuningAttribute::Scale(prev))),
+                None => Ok(()),
+            },
+            TuningAttribute::KeyMap(path) => match self.keymap.replace(path) {
+                Some(prev) => Err(duplicate(TuningAttribute::KeyMap(prev))),
+                None => Ok(()),
+            },
+            TuningAttribute::Reference { note, millihertz } => {
+                match self.reference.replace((note, millihertz)) {
+                    Some((note, millihertz)) => {
+                        Err(duplicate(TuningAttribute::Reference { note, millihertz }))
+                    }
+                    None => Ok(()),
+                }
+            }
+            TuningAttribute::Method(method) => match self.method.replace(method) {
+                Some(prev) => Err(duplicate(TuningAttribute::Method(prev))),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+fn collect_scales(
+    items: &[LangItem],
+    scales: &mut HashMap<String, Vec<u8>>,
+) -> Result<(), CompilerError> {
+    for itm in items {
+        match itm {
+            LangItem::DefineScale { name, intervals } => {
+                let shadows_builtin = ScaleKind::from_name(name).is_some();
+                if shadows_builtin || scales.insert(name.clone(), intervals.clone()).is_some() {
+                    return Err(CompilerError::DuplicateScale(name.clone()));
+                }
+            }
+            LangItem::Loop { expr, .. }
+            | LangItem::Section { body: expr, .. }
+            | LangItem::VoiceLead { body: expr, .. }
+            | LangItem::Relative { body: expr, .. } => {
+                collect_scales(expr, scales)?
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Gets the key declared in the header of a song, resolving scales
+/// declared anywhere in the song.
+pub fn song_key(song: &[LangItem]) -> Result<Option<NoteKey>, CompilerError> {
+    let mut scales = HashMap::new();
+    collect_scales(song, &mut scales)?;
+    let choice = song.iter().find_map(|itm| match itm {
+        LangItem::SetAttribute(SongAttribute::Key(choice)) => Some(choice),
+        _ => None,
+    });
+    let choice = match choice {
+        Some(choice) => choice,
+        None => return Ok(None),
+    };
+    let key = match &choice.scale {
+        ScaleChoice::Builtin(kind) => NoteKey::from_scale(choice.root, *kind),
+        ScaleChoice::Named(name) => match scales.get(name) {
+            Some(intervals) => NoteKey::from_intervals(choice.root, intervals),
+            None => return Err(CompilerError::ScaleNotFound(name.clone())),
+        },
+    };
+    Ok(Some(key))
+}
+
+/// Gets the tuning settings from the header of a song.
+pub fn song_tuning(song: &[LangItem]) -> Result<SongTuning, CompilerError> {
+    let mut retvl = SongTuning::default();
+    for itm in song {
+        if let LangItem::SetAttribute(SongAttribute::Tuning(attr)) = itm {
+            retvl.push(attr.clone())?;
+        }
+    }
+    Ok(retvl)
 }
 
 impl SongAttributes {
@@ -60,6 +226,14 @@ impl SongAttributes {
         self.bpm.unwrap_or_default()
     }
 
+    /// Gets the velocity a dynamic marking maps to in this song.
+    pub fn dynamic_velocity(&self, dynamic: Dynamic) -> PressVelocity {
+        self.dynamics
+            .get(&dynamic)
+            .copied()
+            .unwrap_or_else(|| PressVelocity::from_raw(dynamic.default_velocity()).unwrap())
+    }
+
     pub fn push_attribute(&mut self, attr: SongAttribute) -> Result<(), CompilerError> {
         match attr {
             SongAttribute::DefaultDuration(dur) => {
@@ -112,33 +286,125 @@ impl SongAttributes {
                 self.channel = Some(chan);
                 Ok(())
             }
+            SongAttribute::DynamicLevel(dynamic, vel) => {
+                if let Some(prev) = self.dynamics.get(&dynamic) {
+                    return Err(CompilerError::DuplicateAttributes(
+                        SongAttribute::DynamicLevel(dynamic, *prev),
+                        SongAttribute::DynamicLevel(dynamic, vel),
+                    ));
+                }
+                self.dynamics.insert(dynamic, vel);
+                Ok(())
+            }
+            // Read on their own by `song_tuning()`, since tuning is applied
+            // after compiling.
+            SongAttribute::Tuning(_) => Ok(()),
+            SongAttribute::Key(key) => {
+                if let Some(prev) = self.key.as_ref() {
+                    return Err(CompilerError::DuplicateAttributes(
+                        SongAttribute::Key(prev.clone()),
+                        SongAttribute::Key(key),
+                    ));
+                }
+                self.key = Some(key);
+                Ok(())
+            }
         }
     }
 }
+/// A hairpin that the compiler is currently in the middle of, with its span
+/// measured in ticks from the start of the current block.
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct ActiveHairpin {
+    start: u64,
+    end: u64,
+    from: PressVelocity,
+    to: PressVelocity,
+    curve: VelocityCurve,
+}
+
+/// The compiler state where a section was defined, which its body is
+/// compiled with once the main track is done.
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct SectionState {
+    bpm: BpmInfo,
+    groove: Option<Groove>,
+    hairpin: Option<ActiveHairpin>,
+    voice_leading: Option<VoiceLeading>,
+    relative_pitch: Option<MidiNote>,
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Default)]
 struct Compiler {
     attributes: SongAttributes,
 
-    tick_spans: Vec<(usize, WaitTime)>,
+    /// The BPM information most recently set in the track.
+    cur_bpm: BpmInfo,
+    /// The position in ticks on the straight grid, assuming straight-line
+    /// playback from the start of the song or current section.
+    position: u64,
+    /// The number of ticks the emitted waits add up to, which differs from
+    /// `position` when a groove is active.
+    emitted: u64,
+    hairpin: Option<ActiveHairpin>,
+    groove: Option<Groove>,
+    grooves: HashMap<String, Groove>,
+    voice_leading: Option<VoiceLeading>,
+    /// The pitches of the last chord voiced in the current `voicelead` block.
+    last_voicing: Option<Vec<MidiNote>>,
+    /// The pitch the next relative note is placed closest to, when inside a
+    /// `relative` block.
+    relative_pitch: Option<MidiNote>,
+
     jump_fix_backlog: HashMap<usize, String>,
 
     ports: HashMap<Option<OutputLabel>, OutputPort>,
     labels: HashMap<String, usize>,
 
+    /// Sections waiting to be compiled, along with the state where they
+    /// were defined.
+    sections: Vec<(String, Vec<LangItem>, SectionState)>,
+    section_calls: Vec<(usize, String)>,
+    markers: Vec<Marker>,
+
     track: Vec<TrackEvent>,
 }
 
+/// The output ports a compiled song uses, by label.
+///
+/// Every port the song declares with a default port attribute is included,
+/// even if nothing is ever sent to it. That way it is still opened, so it
+/// can be connected ahead of time, and `lint_song` can report it as unused.
 pub type PortList = HashMap<Option<OutputLabel>, OutputPort>;
 
-pub fn compile_song(song: Vec<LangItem>) -> Result<(Vec<TrackEvent>, PortList), CompilerError> {
+pub fn compile_song(song: Vec<LangItem>) -> Result<(CompiledTrack, PortList), CompilerError> {
+    song_key(&song)?;
     let mut compiler = Compiler::new();
     for itm in song {
         compiler.compile_item(itm)?;
     }
     compiler.track.push(TrackEvent::End);
+    compiler.compile_sections()?;
     compiler.resolve_jumps()?;
-    compiler.resolve_tickspans()?;
-    Ok((compiler.track, compiler.ports))
+    if let Some(lp) = zero_time_loops(&compiler.track).first() {
+        return Err(CompilerError::ZeroTimeLoop {
+            jump: lp.jump,
+            target: lp.target,
+        });
+    }
+    let mut markers = compiler.markers;
+    markers.extend(compiler.labels.into_iter().map(|(name, idx)| Marker {
+        name,
+        kind: MarkerKind::Label,
+        start: idx,
+        end: idx,
+    }));
+    markers.sort_by_key(|marker| (marker.start, marker.end));
+    let track = CompiledTrack {
+        events: compiler.track,
+        markers,
+    };
+    Ok((track, compiler.ports))
 }
 
 impl Compiler {
@@ -152,31 +418,139 @@ impl Compiler {
         port
     }
 
-    #[allow(unused)]
-    fn resolve_tickspans(&mut self) -> Result<(), CompilerError> {
-        self.tick_spans
-            .sort_by_key(|(idx, _)| (*idx as i128).saturating_neg());
-        while let Some((next_instr_idx, next_span)) = self.tick_spans.pop() {
-            todo!()
+    /// Converts a wait period to ticks at the current BPM.
+    fn wait_ticks(&self, time: WaitTime) -> u64 {
+        match time {
+            WaitTime::Ticks(ticks) => ticks.get() as u64,
+            WaitTime::Beats(beats) => {
+                (beats.get() as u64) * (self.cur_bpm.ticks_per_beat.get() as u64)
+            }
+            WaitTime::Clock(dur) => self.cur_bpm.duration_ticks(dur).max(1),
         }
-        Ok(())
     }
 
-    #[allow(dead_code)]
-    fn insert_event(&mut self, idx: usize, event: TrackEvent) {
-        self.track.insert(idx, event);
-        for instr in self.track.iter_mut() {
-            if let TrackEvent::Jump { target, .. } = instr {
-                if *target >= idx {
-                    *target = target.wrapping_add(1);
+    /// Gets the active groove and the length of its subdivisions in ticks.
+    fn groove_grid(&self) -> Option<(&Groove, u64)> {
+        let groove = self.groove.as_ref()?;
+        let step_ticks = groove.step_ticks(self.cur_bpm)?;
+        Some((groove, step_ticks))
+    }
+
+    /// Checks that the active groove can be played at the current BPM.
+    fn check_groove(&self) -> Result<(), CompilerError> {
+        match &self.groove {
+            Some(groove) if !groove.is_valid() => Err(CompilerError::InvalidGroove(groove.clone())),
+            Some(groove) if groove.step_ticks(self.cur_bpm).is_none() => {
+                Err(CompilerError::GrooveTooFine {
+                    subdivision: groove.subdivision,
+                    ticks_per_beat: self.cur_bpm.ticks_per_beat,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Advances the position by `ticks` on the straight grid, pushing
+    /// `TrackEvent::Wait`s to wherever the active groove places the new position.
+    fn push_wait_ticks(&mut self, ticks: u64) {
+        self.position += ticks;
+        let target = match self.groove_grid() {
+            Some((groove, step_ticks)) => groove.apply(self.position, step_ticks),
+            None => self.position,
+        };
+        let mut remaining = target.saturating_sub(self.emitted);
+        self.emitted += remaining;
+        while let Some(chunk) = NonZeroU16::new(remaining.min(u16::max_value() as u64) as u16) {
+            self.track.push(TrackEvent::Wait(WaitTime::Ticks(chunk)));
+            remaining -= chunk.get() as u64;
+        }
+    }
+
+    /// Pushes a list of events with their offsets in ticks from the
+    /// current position, in time order and separated by waits.
+    fn push_schedule(&mut self, mut schedule: Vec<(u64, TrackEvent)>) {
+        schedule.sort_by_key(|(offset, _)| *offset);
+        let mut cur = 0;
+        for (offset, evt) in schedule {
+            if offset > cur {
+                self.push_wait_ticks(offset - cur);
+                cur = offset;
+            }
+            self.track.push(evt);
+        }
+    }
+
+    /// Gets the velocity for presses that do not set one themselves, based on
+    /// the hairpin the current position falls in.
+    fn hairpin_velocity(&self) -> Option<PressVelocity> {
+        let hairpin = self.hairpin.as_ref()?;
+        if self.position >= hairpin.end {
+            return Some(hairpin.to);
+        }
+        let progress =
+            (self.position - hairpin.start) as f64 / (hairpin.end - hairpin.start) as f64;
+        let raw = hairpin
+            .curve
+            .interpolate(hairpin.from.as_u8(), hairpin.to.as_u8(), progress);
+        PressVelocity::from_raw(raw)
+    }
+
+    /// Compiles every section body as a subroutine after the end of the main
+    /// track, and points the arrangement's calls at them.
+    fn compile_sections(&mut self) -> Result<(), CompilerError> {
+        let mut section_starts = HashMap::new();
+        // Sections may define further sections, so keep going until none are left.
+        loop {
+            let pending = std::mem::take(&mut self.sections);
+            if pending.is_empty() {
+                break;
+            }
+            for (name, body, state) in pending {
+                if section_starts.contains_key(&name) {
+                    return Err(CompilerError::DuplicateSection(name));
+                }
+                let start = self.track.len();
+                // Sections can be called from anywhere, so their timing starts fresh.
+                self.position = 0;
+                self.emitted = 0;
+                self.last_voicing = None;
+                self.hairpin = state.hairpin;
+                self.groove = state.groove;
+                self.voice_leading = state.voice_leading;
+                self.relative_pitch = state.relative_pitch;
+                self.cur_bpm = state.bpm;
+                for itm in body {
+                    self.compile_item(itm)?;
                 }
+                self.track.push(TrackEvent::Return);
+                section_starts.insert(name.clone(), start);
+                self.markers.push(Marker {
+                    name,
+                    kind: MarkerKind::Section,
+                    start,
+                    end: self.track.len(),
+                });
+            }
+        }
+        for (instr_idx, name) in self.section_calls.drain(..) {
+            let new_target = section_starts
+                .get(&name)
+                .copied()
+                .ok_or_else(|| CompilerError::SectionNotFound(name.clone()))?;
+            if let Some(TrackEvent::Call { target }) = self.track.get_mut(instr_idx) {
+                *target = new_target;
             }
         }
-        for (curidx, _) in self.tick_spans.iter_mut() {
-            if *curidx >= idx {
-                *curidx += 1;
+        for marker in self.markers.iter() {
+            let is_used = self.track.iter().any(|evt| match evt {
+                TrackEvent::Call { target } => *target == marker.start,
+                _ => false,
+            });
+            if !is_used {
+                return Err(CompilerError::UnusedSection(marker.name.clone()));
             }
         }
+        Ok(())
     }
 
     fn resolve_jumps(&mut self) -> Result<(), CompilerError> {
@@ -187,7 +561,7 @@ impl Compiler {
                 .copied()
                 .ok_or_else(|| CompilerError::LabelNotFound(lbl.clone()))?;
             match self.track.get_mut(instr_idx) {
-                Some(TrackEvent::Jump { target, .. }) => {
+                Some(TrackEvent::Jump { target, .. }) | Some(TrackEvent::Call { target }) => {
                     *target = new_target;
                 }
                 other => {
@@ -221,9 +595,24 @@ impl Compiler {
 
     fn encounter_pressline(&mut self, data: PressLine) -> Result<(), CompilerError> {
         let line_duration = data.duration();
-        let line_vel = data.velocity();
+        let line_vel = data
+            .velocity()
+            .or_else(|| data.dynamic().map(|d| self.attributes.dynamic_velocity(d)));
         let line_channel = data.channel();
         let line_port = data.port().cloned();
+        let base_vel = self
+            .hairpin_velocity()
+            .unwrap_or_else(|| self.attributes.default_velocity());
+        let accent = match self.groove_grid() {
+            Some((groove, step_ticks)) => groove.velocity_offset(self.position, step_ticks),
+            None => 0,
+        };
+
+        // Every press in the line starts together; the line lasts as long as
+        // its longest press. Inside a `voicelead` block, the presses of a line
+        // are a progression instead, each starting when the previous one ends.
+        let mut schedule = Vec::new();
+        let mut next_chord = 0;
         for press in data.presses {
             let channel = press
                 .channel()
@@ -232,13 +621,21 @@ impl Compiler {
 
             let vel = press
                 .velocity()
+                .or_else(|| press.dynamic().map(|d| self.attributes.dynamic_velocity(d)))
                 .or(line_vel)
-                .unwrap_or_else(|| self.attributes.default_velocity());
+                .unwrap_or(base_vel);
+            let vel = if accent == 0 {
+                vel
+            } else {
+                let raw = (i16::from(vel.as_u8()) + i16::from(accent)).clamp(1, 127);
+                PressVelocity::from_raw(raw as u8).unwrap_or(vel)
+            };
 
             let duration = press
                 .duration()
                 .or(line_duration)
                 .unwrap_or_else(|| self.attributes.default_duration());
+            let duration = self.wait_ticks(duration);
 
             let port = press
                 .port()
@@ -247,37 +644,73 @@ impl Compiler {
                 .or_else(|| self.attributes.default_port());
 
             let port = self.port_label_to_idx(port);
-            let offsets: &[_] = match press.kind {
-                ChordKind::Raw => &[0],
-                ChordKind::Fifth => &[0, 4],
-                ChordKind::Major | ChordKind::Minor => &[0, 2, 4],
-                ChordKind::Major7 | ChordKind::Minor7 => &[0, 2, 4, 7],
-            };
-            let key = match press.kind {
-                ChordKind::Minor | ChordKind::Minor7 => NoteKey::minor(press.root),
-                ChordKind::Major | ChordKind::Major7 | ChordKind::Raw | ChordKind::Fifth => {
-                    NoteKey::major(press.root)
+            let root_pitch = self.press_pitch(press.root, press.octave)?;
+            let intervals = press.kind.intervals();
+            // Modifiers on the press override the ones on the line.
+            let voicing =
+                Voicing::from_modifiers(data.modifiers.iter().chain(press.modifiers.iter()));
+            let pitches = match self.voice_leading {
+                // Single notes are melody, so they are left where they were written.
+                Some(settings) if intervals.len() > 1 && !voicing.is_manual() => {
+                    let previous = self.last_voicing.as_deref();
+                    voice_lead(root_pitch, intervals, previous, &settings)
                 }
+                _ => voicing.apply(root_pitch, intervals),
             };
-            let root_pitch = MidiNote::from_note_octave(press.root, press.octave);
-            let mut prev_pitch = root_pitch;
-            for offset in offsets {
-                let mut cur_pitch = MidiNote::from_note_octave(key.nth(*offset), press.octave);
-                if cur_pitch < prev_pitch {
-                    cur_pitch = cur_pitch.wrapping_add(12);
+            let offset = if self.voice_leading.is_some() {
+                if intervals.len() > 1 {
+                    self.last_voicing = Some(pitches.clone());
                 }
-                let noteon = NoteOn::new(channel, cur_pitch, vel);
-                let evt = TrackEvent::SendMessage {
-                    message: MidiMessage::from(noteon),
-                    port,
+                next_chord += duration;
+                next_chord - duration
+            } else {
+                0
+            };
+            let strum = voicing
+                .strum
+                .map(|(step, direction)| (self.wait_ticks(step), direction));
+            let note_count = pitches.len() as u64;
+            for (idx, cur_pitch) in pitches.into_iter().enumerate() {
+                let start = match strum {
+                    Some((step, StrumDirection::Up)) => idx as u64 * step,
+                    Some((step, StrumDirection::Down)) => (note_count - 1 - idx as u64) * step,
+                    None => 0,
                 };
-                self.tick_spans.push((self.track.len(), duration));
-                self.track.push(evt);
-                prev_pitch = cur_pitch;
+                let noteon = NoteOn::new(channel, cur_pitch, vel);
+                schedule.push((
+                    offset + start,
+                    TrackEvent::SendMessage {
+                        message: MidiMessage::from(noteon),
+                        port,
+                    },
+                ));
+                let noteoff = NoteOff::new(channel, cur_pitch, PressVelocity::default());
+                // Strummed notes all end together, unless the strum outlasts the press.
+                schedule.push((
+                    offset + duration.max(start),
+                    TrackEvent::SendMessage {
+                        message: MidiMessage::from(noteoff),
+                        port,
+                    },
+                ));
             }
         }
-        let line_wait = TrackEvent::Wait(WaitTime::Ticks(ONE_NZU16));
-        self.track.push(line_wait);
+        self.push_schedule(schedule);
+        Ok(())
+    }
+
+    fn encounter_hairpin(&mut self, hairpin: Hairpin) -> Result<(), CompilerError> {
+        let length = match hairpin.length {
+            SpanLength::Bars(bars) => (bars.get() as u64) * self.cur_bpm.ticks_per_bar(),
+            SpanLength::Time(time) => self.wait_ticks(time),
+        };
+        self.hairpin = Some(ActiveHairpin {
+            start: self.position,
+            end: self.position + length,
+            from: self.attributes.dynamic_velocity(hairpin.from),
+            to: self.attributes.dynamic_velocity(hairpin.to),
+            curve: hairpin.curve,
+        });
         Ok(())
     }
 
@@ -293,14 +726,113 @@ impl Compiler {
                 .unwrap_or(ONE_NZU16)
         });
         let target = self.track.len();
+        let start_position = self.position;
+        let start_emitted = self.emitted;
+        let outer_groove = self.groove.clone();
+        let outer_cycle = self
+            .groove_grid()
+            .map(|(groove, step_ticks)| groove.cycle_ticks(step_ticks));
+        let outer_hairpin = self.hairpin.clone();
         for itm in body {
             self.compile_item(itm)?;
         }
+        let body_ticks = self.position - start_position;
+        // The body is only compiled once, so a hairpin that is still going on
+        // when the body starts or ends would be cut short on every repeat.
+        let is_repeated = rawcount.is_none_or(|n| n.get() > 1);
+        let crosses_start = outer_hairpin.is_some_and(|h| h.end > start_position);
+        let crosses_end = self.hairpin.as_ref().is_some_and(|h| h.end > self.position);
+        if is_repeated && (crosses_start || crosses_end) {
+            return Err(CompilerError::HairpinAcrossLoop(self.track.len()));
+        }
+        // The body's waits are played again as they are, so every repeat
+        // has to start at the same point of the groove as the first one.
+        let inner_cycle = self
+            .groove_grid()
+            .map(|(groove, step_ticks)| groove.cycle_ticks(step_ticks));
+        let in_phase = [outer_cycle, inner_cycle]
+            .iter()
+            .flatten()
+            .all(|cycle| body_ticks.is_multiple_of(*cycle));
+        if is_repeated && !in_phase {
+            return Err(CompilerError::GrooveOutOfPhase {
+                jump: self.track.len(),
+                ticks: body_ticks,
+            });
+        }
+        let body_emitted = self.emitted - start_emitted;
+        let repetitions = rawcount.map_or(1, |n| n.get() as u64);
+        self.position = start_position + body_ticks * repetitions;
+        self.emitted = start_emitted + body_emitted * repetitions;
+        self.groove = outer_groove;
+        self.check_groove()?;
         let jmp = TrackEvent::Jump { target, count };
         self.track.push(jmp);
         Ok(())
     }
 
+    fn encounter_voicelead(
+        &mut self,
+        settings: VoiceLeading,
+        body: Vec<LangItem>,
+    ) -> Result<(), CompilerError> {
+        let outer_settings = self.voice_leading.replace(settings);
+        let outer_voicing = self.last_voicing.take();
+        for itm in body {
+            self.compile_item(itm)?;
+        }
+        self.voice_leading = outer_settings;
+        self.last_voicing = outer_voicing;
+        Ok(())
+    }
+
+    fn encounter_relative(
+        &mut self,
+        start: MidiNote,
+        body: Vec<LangItem>,
+    ) -> Result<(), CompilerError> {
+        let outer = self.relative_pitch.replace(start);
+        for itm in body {
+            self.compile_item(itm)?;
+        }
+        self.relative_pitch = outer;
+        Ok(())
+    }
+
+    /// Gets the pitch of a press's root, placing relative notes in the octave
+    /// closest to the previous note. Inside a `relative` block, every press
+    /// becomes the reference for the next one.
+    fn press_pitch(
+        &mut self,
+        root: NoteClass,
+        octave: PressOctave,
+    ) -> Result<MidiNote, CompilerError> {
+        let pitch = match (octave, self.relative_pitch) {
+            (PressOctave::Absolute(octave), _) => MidiNote::from_note_octave(root, octave),
+            (PressOctave::Relative(_), None) => return Err(CompilerError::MissingOctave(root)),
+            (PressOctave::Relative(marks), Some(prev)) => {
+                let prev_raw = i16::from(prev.as_u8());
+                let octave_start = prev_raw - i16::from(prev.note().as_u8());
+                let same_octave = octave_start + i16::from(root.as_u8());
+                // A tritone away is ambiguous, so it goes up.
+                let closest = match same_octave - prev_raw {
+                    diff if diff > 6 => same_octave - 12,
+                    diff if diff <= -6 => same_octave + 12,
+                    _ => same_octave,
+                };
+                let raw = closest + 12 * i16::from(marks);
+                if !(0..=127).contains(&raw) {
+                    return Err(CompilerError::RelativeOutOfRange(root));
+                }
+                MidiNote::mask(raw as u8)
+            }
+        };
+        if self.relative_pitch.is_some() {
+            self.relative_pitch = Some(pitch);
+        }
+        Ok(pitch)
+    }
+
     fn encounter_jump(
         &mut self,
         count: Option<NonZeroU16>,
@@ -316,15 +848,95 @@ impl Compiler {
         Ok(())
     }
 
+    fn encounter_call(&mut self, label: String) -> Result<(), CompilerError> {
+        let target_opt = self.labels.get(&label).copied();
+        let target = target_opt.unwrap_or_else(|| {
+            self.jump_fix_backlog.insert(self.track.len(), label);
+            usize::max_value()
+        });
+        self.track.push(TrackEvent::Call { target });
+        Ok(())
+    }
+
+    fn encounter_section(&mut self, name: String, body: Vec<LangItem>) -> Result<(), CompilerError> {
+        if self.sections.iter().any(|(prev, _, _)| prev == &name) {
+            return Err(CompilerError::DuplicateSection(name));
+        }
+        // The section's timing starts fresh, so a hairpin that is in
+        // progress here holds the velocity it has reached.
+        let hairpin = self.hairpin.as_ref().and_then(|hairpin| {
+            let velocity = self.hairpin_velocity()?;
+            Some(ActiveHairpin {
+                start: 0,
+                end: 0,
+                from: velocity,
+                to: velocity,
+                curve: hairpin.curve,
+            })
+        });
+        let state = SectionState {
+            bpm: self.cur_bpm,
+            groove: self.groove.clone(),
+            hairpin,
+            voice_leading: self.voice_leading,
+            relative_pitch: self.relative_pitch,
+        };
+        self.sections.push((name, body, state));
+        Ok(())
+    }
+
+    fn encounter_define_groove(&mut self, name: String, groove: Groove) -> Result<(), CompilerError> {
+        if !groove.is_valid() {
+            return Err(CompilerError::InvalidGroove(groove));
+        }
+        if self.grooves.contains_key(&name) {
+            return Err(CompilerError::DuplicateGroove(name));
+        }
+        self.grooves.insert(name, groove);
+        Ok(())
+    }
+
+    fn encounter_set_groove(&mut self, choice: GrooveChoice) -> Result<(), CompilerError> {
+        self.groove = match choice {
+            GrooveChoice::Straight => None,
+            GrooveChoice::Template(groove) => Some(groove),
+            GrooveChoice::Named(name) => match self.grooves.get(&name) {
+                Some(groove) => Some(groove.clone()),
+                None => return Err(CompilerError::GrooveNotFound(name)),
+            },
+        };
+        // Start the groove from the grid point we are at, so the
+        // switch does not jump backwards in time.
+        self.emitted = self.emitted.max(self.position);
+        self.check_groove()
+    }
+
+    fn encounter_arrange(&mut self, entries: Vec<ArrangeEntry>) -> Result<(), CompilerError> {
+        for entry in entries {
+            for _ in 0..entry.repetitions.get() {
+                self.section_calls.push((self.track.len(), entry.section.clone()));
+                self.track.push(TrackEvent::Call {
+                    target: usize::max_value(),
+                });
+            }
+        }
+        Ok(())
+    }
+
     fn encounter_setattr(&mut self, attr: SongAttribute) -> Result<(), CompilerError> {
         let new_bpm = match attr {
             SongAttribute::Signature(bpm) => Some(bpm),
             _ => None,
         };
+        // Declared ports are always registered, even if nothing ends up using them.
+        if let SongAttribute::DefaultPort(lbl) = &attr {
+            self.port_label_to_idx(Some(lbl.clone()));
+        }
         match self.track.first() {
             None => {
                 self.attributes.push_attribute(attr)?;
                 if let Some(bpm) = new_bpm {
+                    self.cur_bpm = bpm;
                     self.track.push(TrackEvent::SetBpm(bpm));
                 }
                 Ok(())
@@ -349,11 +961,22 @@ impl Compiler {
                 Ok(())
             }
             LangItem::Wait(dur) | LangItem::Asm(AsmCommand::Wait(dur)) => {
-                let evt = TrackEvent::Wait(dur);
-                self.track.push(evt);
+                let ticks = self.wait_ticks(dur);
+                // Clock waits do not sit on the beat grid, so grooves leave them alone.
+                let is_clock = matches!(dur, WaitTime::Clock(_));
+                if self.groove.is_some() && !is_clock {
+                    self.push_wait_ticks(ticks);
+                } else {
+                    self.position += ticks;
+                    self.emitted += ticks;
+                    let evt = TrackEvent::Wait(dur);
+                    self.track.push(evt);
+                }
                 Ok(())
             }
             LangItem::Asm(AsmCommand::SetBpm(bpm)) => {
+                self.cur_bpm = bpm;
+                self.check_groove()?;
                 let evt = TrackEvent::SetBpm(bpm);
                 self.track.push(evt);
                 Ok(())
@@ -372,12 +995,315 @@ impl Compiler {
                 self.encounter_jump(count, label)?;
                 Ok(())
             }
+            LangItem::Asm(AsmCommand::Call(label)) => {
+                self.encounter_call(label)?;
+                Ok(())
+            }
+            LangItem::Asm(AsmCommand::Return) => {
+                self.track.push(TrackEvent::Return);
+                Ok(())
+            }
             LangItem::SetAttribute(attr) => {
                 self.encounter_setattr(attr)?;
                 Ok(())
             }
+            LangItem::Section { name, body } => {
+                self.encounter_section(name, body)?;
+                Ok(())
+            }
+            LangItem::Arrange(entries) => {
+                self.encounter_arrange(entries)?;
+                Ok(())
+            }
+            LangItem::Hairpin(hairpin) => {
+                self.encounter_hairpin(hairpin)?;
+                Ok(())
+            }
+            LangItem::DefineGroove { name, groove } => {
+                self.encounter_define_groove(name, groove)?;
+                Ok(())
+            }
+            LangItem::SetGroove(choice) => {
+                self.encounter_set_groove(choice)?;
+                Ok(())
+            }
+            LangItem::Relative { start, body } => {
+                self.encounter_relative(start, body)?;
+                Ok(())
+            }
+            LangItem::VoiceLead { settings, body } => {
+                self.encounter_voicelead(settings, body)?;
+                Ok(())
+            }
+            // Scales only affect the song's key, which `song_key` resolves up front.
+            LangItem::DefineScale { .. } => Ok(()),
             #[allow(unreachable_patterns)]
             other => todo!("LangItem not implemented: {:?}", other),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::songlang::{parse_file, ChordKind};
+    use crate::track::TrackCursor;
+    use std::time::Duration;
+
+    fn compile_str(src: &str) -> Result<CompiledTrack, CompilerError> {
+        let (rest, song) = parse_file(src).unwrap();
+        assert_eq!("", rest.trim());
+        compile_song(song).map(|(track, _)| track)
+    }
+
+    /// The time in ticks and the note of a NoteOn or NoteOff.
+    type NoteTime = (u64, u8);
+
+    /// Gets the times of every NoteOn and every NoteOff in a track without jumps.
+    fn note_times(track: &CompiledTrack) -> (Vec<NoteTime>, Vec<NoteTime>) {
+        let mut now = 0;
+        let mut ons = Vec::new();
+        let mut offs = Vec::new();
+        for evt in track.events.iter() {
+            match evt {
+                TrackEvent::Wait(WaitTime::Ticks(ticks)) => now += u64::from(ticks.get()),
+                TrackEvent::SendMessage {
+                    message: MidiMessage::NoteOn(on),
+                    ..
+                } => ons.push((now, on.note().as_u8())),
+                TrackEvent::SendMessage {
+                    message: MidiMessage::NoteOff(off),
+                    ..
+                } => offs.push((now, off.note().as_u8())),
+                _ => {}
+            }
+        }
+        (ons, offs)
+    }
+
+    fn note_ons(track: &CompiledTrack) -> Vec<NoteTime> {
+        note_times(track).0
+    }
+
+    /// Gets the time and velocity of every NoteOn in a track without jumps.
+    fn note_vels(track: &CompiledTrack) -> Vec<(u64, u8)> {
+        let mut now = 0;
+        let mut vels = Vec::new();
+        for evt in track.events.iter() {
+            match evt {
+                TrackEvent::Wait(WaitTime::Ticks(ticks)) => now += u64::from(ticks.get()),
+                TrackEvent::SendMessage {
+                    message: MidiMessage::NoteOn(on),
+                    ..
+                } => vels.push((now, on.vel().as_u8())),
+                _ => {}
+            }
+        }
+        vels
+    }
+
+    #[test]
+    fn test_hairpin_bars() {
+        let presses = "cresc pp -> ff over 1 bar\n\
+                       play for 6 ticks C4\n\
+                       play for 6 ticks C4\n\
+                       play for 6 ticks C4\n";
+
+        // In 3/4, the bar is over by the third press.
+        let track = compile_str(&format!("SETBPM 120, 4, 3\n{}", presses)).unwrap();
+        let vels = note_vels(&track);
+        assert_eq!(vec![0, 6, 12], vels.iter().map(|(time, _)| *time).collect::<Vec<_>>());
+        assert_eq!(33, vels[0].1);
+        assert!(33 < vels[1].1 && vels[1].1 < 112);
+        assert_eq!(112, vels[2].1);
+
+        // In the default 4/4, it still has a beat to go.
+        let track = compile_str(&format!("SETBPM 120, 4\n{}", presses)).unwrap();
+        assert!(note_vels(&track)[2].1 < 112);
+    }
+
+    #[test]
+    fn test_sections() {
+        let src = "section verse {\n\
+                   play for 4 ticks C4\n\
+                   }\n\
+                   section chorus {\n\
+                   play for 2 ticks G4\n\
+                   }\n\
+                   arrange verse*2 chorus verse\n";
+        let track = compile_str(src).unwrap();
+        // One subroutine per section, and one call per repetition.
+        let returns = track.events.iter().filter(|evt| **evt == TrackEvent::Return);
+        assert_eq!(2, returns.count());
+        let calls: Vec<_> = track
+            .events
+            .iter()
+            .filter_map(|evt| match evt {
+                TrackEvent::Call { target } => Some(*target),
+                _ => None,
+            })
+            .collect();
+        let start = |name: &str| {
+            let marker = track.markers.iter().find(|marker| marker.name == name);
+            assert_eq!(MarkerKind::Section, marker.unwrap().kind);
+            marker.unwrap().start
+        };
+        let (verse, chorus) = (start("verse"), start("chorus"));
+        assert_eq!(vec![verse, verse, chorus, verse], calls);
+
+        // The cursor knows which section each note is played in.
+        let mut cursor = TrackCursor::new(track);
+        let mut budget = cursor.instruction_budget();
+        let mut played = Vec::new();
+        while let Some((_, _, msg)) = cursor.next_until(Duration::from_secs(10), &mut budget) {
+            if let MidiMessage::NoteOn(on) = msg {
+                let section = cursor.section().map(|marker| marker.name.clone());
+                played.push((on.note().as_u8(), section.unwrap()));
+            }
+        }
+        let expected = [(60, "verse"), (60, "verse"), (67, "chorus"), (60, "verse")];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(note, section)| (*note, section.to_string()))
+            .collect();
+        assert_eq!(expected, played);
+
+        match compile_str("section verse {\nplay C4\n}\narrange verse bridge\n") {
+            Err(CompilerError::SectionNotFound(name)) => assert_eq!("bridge", name),
+            other => panic!("Expected a missing section, got {:?}", other),
+        }
+        match compile_str("section verse {\nplay C4\n}\nsection bridge {\nplay D4\n}\narrange verse\n") {
+            Err(CompilerError::UnusedSection(name)) => assert_eq!("bridge", name),
+            other => panic!("Expected an unused section, got {:?}", other),
+        }
+        match compile_str("section verse {\nplay C4\n}\nsection verse {\nplay D4\n}\narrange verse\n") {
+            Err(CompilerError::DuplicateSection(name)) => assert_eq!("verse", name),
+            other => panic!("Expected a duplicate section, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_song_key() {
+        let key = |src: &str| {
+            let (rest, song) = parse_file(src).unwrap();
+            assert_eq!("", rest.trim());
+            song_key(&song)
+        };
+        let d_dorian = key("key D dorian\nplay C4\n").unwrap().unwrap();
+        assert_eq!(NoteClass::D, d_dorian.root());
+        assert_eq!(vec![0, 2, 3, 5, 7, 9, 10], d_dorian.intervals());
+
+        let src = "key E my-scale\nscale my-scale = 0 2 3 7 9\nplay C4\n";
+        let custom = key(src).unwrap().unwrap();
+        assert_eq!(NoteClass::E, custom.root());
+        assert_eq!(vec![0, 2, 3, 7, 9], custom.intervals());
+        assert!(compile_str(src).is_ok());
+
+        match compile_str("key C nosuch\nplay C4\n") {
+            Err(CompilerError::ScaleNotFound(name)) => assert_eq!("nosuch", name),
+            other => panic!("Expected a missing scale, got {:?}", other),
+        }
+        let twice = "scale mine = 0 4 7\nplay C4\nscale mine = 0 3 7\n";
+        match compile_str(twice) {
+            Err(CompilerError::DuplicateScale(name)) => assert_eq!("mine", name),
+            other => panic!("Expected a duplicate scale, got {:?}", other),
+        }
+        // Built in scales can't be redefined either.
+        match compile_str("scale dorian = 0 4 7\nplay C4\n") {
+            Err(CompilerError::DuplicateScale(name)) => assert_eq!("dorian", name),
+            other => panic!("Expected a duplicate scale, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_chord_kinds() {
+        // The match makes a new kind of chord fail to compile until it is pinned here.
+        let expected = |kind| match kind {
+            ChordKind::Raw => vec![60],
+            ChordKind::Fifth => vec![60, 67],
+            ChordKind::Major => vec![60, 64, 67],
+            ChordKind::Minor => vec![60, 63, 67],
+            ChordKind::Major7 => vec![60, 64, 67, 71],
+            ChordKind::Minor7 => vec![60, 63, 67, 70],
+        };
+        let kinds = [
+            ChordKind::Raw,
+            ChordKind::Fifth,
+            ChordKind::Major,
+            ChordKind::Minor,
+            ChordKind::Major7,
+            ChordKind::Minor7,
+        ];
+        for kind in kinds.iter() {
+            let chord = format!("C4{}", kind.suffix());
+            let track = compile_str(&format!("play {}\n", chord)).unwrap();
+            let mut pitches: Vec<_> = note_ons(&track).into_iter().map(|(_, note)| note).collect();
+            pitches.sort_unstable();
+            assert_eq!(expected(*kind), pitches, "{}", chord);
+        }
+    }
+
+    #[test]
+    fn test_relative() {
+        let pitches = |src: &str| -> Vec<u8> {
+            let track = compile_str(src).unwrap();
+            note_ons(&track).into_iter().map(|(_, note)| note).collect()
+        };
+        let lines = ["e", "g", "c", "f", "b", "f", "c'", "c,,", "g2", "a"];
+        let body: Vec<_> = lines.iter().map(|note| format!("play {}\n", note)).collect();
+        let src = format!("relative c4 {{\n{}}}\n", body.concat());
+        // Each note goes to the octave closest to the one before it, and a
+        // tritone away always goes up. Notes with an octave move the reference.
+        assert_eq!(vec![64, 67, 72, 77, 83, 89, 96, 72, 43, 45], pitches(&src));
+
+        match compile_str("relative c9 {\nplay c'\n}\n") {
+            Err(CompilerError::RelativeOutOfRange(note)) => assert_eq!(NoteClass::C, note),
+            other => panic!("Expected a note out of range, got {:?}", other),
+        }
+        // Outside of a `relative` block, every note needs an octave.
+        match compile_str("relative c4 {\nplay e\n}\nplay e\n") {
+            Err(CompilerError::MissingOctave(note)) => assert_eq!(NoteClass::E, note),
+            other => panic!("Expected a missing octave, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_strum() {
+        let track = compile_str("play strum=2 ticks C4M for 10 ticks\n").unwrap();
+        let (ons, mut offs) = note_times(&track);
+        assert_eq!(vec![(0, 60), (2, 64), (4, 67)], ons);
+        offs.sort();
+        assert_eq!(vec![(10, 60), (10, 64), (10, 67)], offs);
+
+        let track = compile_str("play strum=2 ticks down C4M for 10 ticks\n").unwrap();
+        assert_eq!(vec![(0, 67), (2, 64), (4, 60)], note_ons(&track));
+
+        // A strum that outlasts the press ends each note as soon as it starts.
+        let track = compile_str("play strum=4 ticks C4M for 6 ticks\n").unwrap();
+        let (ons, mut offs) = note_times(&track);
+        assert_eq!(vec![(0, 60), (4, 64), (8, 67)], ons);
+        offs.sort();
+        assert_eq!(vec![(6, 60), (6, 64), (8, 67)], offs);
+    }
+
+    #[test]
+    fn test_voicelead_progression() {
+        let track = compile_str("voicelead { play for 10 ticks C4M, F4M }\n").unwrap();
+        let ons = note_ons(&track);
+        assert_eq!(6, ons.len());
+        assert!(ons[..3].iter().all(|(time, _)| *time == 0));
+        assert!(ons[3..].iter().all(|(time, _)| *time == 10));
+
+        // Single notes take their own turn in the progression.
+        let track = compile_str("voicelead { play for 4 ticks C4M, e5, F4M }\n").unwrap();
+        let ons = note_ons(&track);
+        assert_eq!(7, ons.len());
+        assert!(ons[..3].iter().all(|(time, _)| *time == 0));
+        assert_eq!((4, 76), ons[3]);
+        assert!(ons[4..].iter().all(|(time, _)| *time == 8));
+
+        // Outside of `voicelead`, the chords still play together.
+        let track = compile_str("play for 10 ticks C4M, F4M\n").unwrap();
+        assert!(note_ons(&track).iter().all(|(time, _)| *time == 0));
+    }
+}
@@ -0,0 +1,126 @@
+use crate::track::BpmInfo;
+
+use std::num::NonZeroU16;
+
+/// How a single subdivision of a `Groove` is played.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct GrooveStep {
+    /// How far the start of the subdivision is pushed back, as a percentage
+    /// of the length of a subdivision. Negative values play it early.
+    pub timing: i8,
+    /// How much is added to the velocity of notes starting on the subdivision.
+    pub velocity: i8,
+}
+
+/// A template of timing and velocity offsets applied to the straight tick grid,
+/// cycling through `steps` once per subdivision.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Groove {
+    /// The note value of each step, IE 8 for eighth notes or 16 for sixteenths.
+    pub subdivision: NonZeroU16,
+    pub steps: Vec<GrooveStep>,
+}
+
+impl Groove {
+    /// Creates a groove that delays every second subdivision so that the first
+    /// of each pair takes up `percent` percent of the pair's length.
+    pub fn swing(percent: u8, subdivision: NonZeroU16) -> Groove {
+        let offset = 2 * i16::from(percent) - 100;
+        let timing = offset.clamp(i8::MIN.into(), i8::MAX.into()) as i8;
+        Groove {
+            subdivision,
+            steps: vec![
+                GrooveStep::default(),
+                GrooveStep {
+                    timing,
+                    velocity: 0,
+                },
+            ],
+        }
+    }
+
+    /// Checks that the groove never reorders its subdivisions.
+    pub fn is_valid(&self) -> bool {
+        if self.steps.is_empty() {
+            return false;
+        }
+        let offsets = self.steps.iter().map(|step| i16::from(step.timing));
+        let next_offsets = offsets.clone().cycle().skip(1);
+        offsets
+            .zip(next_offsets)
+            .all(|(cur, next)| next + 100 > cur)
+    }
+
+    /// Gets the length of a single subdivision in ticks, if it lands on the tick grid.
+    pub fn step_ticks(&self, bpm: BpmInfo) -> Option<u64> {
+        let whole_note = 4 * u64::from(bpm.ticks_per_beat.get());
+        let subdivision = u64::from(self.subdivision.get());
+        if whole_note.is_multiple_of(subdivision) {
+            Some(whole_note / subdivision)
+        } else {
+            None
+        }
+    }
+
+    /// Gets the length in ticks of one pass through all of the groove's steps.
+    pub fn cycle_ticks(&self, step_ticks: u64) -> u64 {
+        step_ticks * self.steps.len() as u64
+    }
+
+    fn grid_point(&self, idx: u64, step_ticks: u64) -> i64 {
+        let step = self.steps[(idx % self.steps.len() as u64) as usize];
+        let offset = i64::from(step.timing) * step_ticks as i64 / 100;
+        (idx * step_ticks) as i64 + offset
+    }
+
+    /// Moves a position on the straight tick grid to where the groove plays it.
+    ///
+    /// Positions between subdivisions are stretched along with them,
+    /// so notes keep their order and never overlap differently.
+    pub fn apply(&self, position: u64, step_ticks: u64) -> u64 {
+        let idx = position / step_ticks;
+        let remainder = (position % step_ticks) as i64;
+        let start = self.grid_point(idx, step_ticks);
+        let end = self.grid_point(idx + 1, step_ticks);
+        let grooved = start + remainder * (end - start) / step_ticks as i64;
+        grooved.max(0) as u64
+    }
+
+    /// Gets the velocity change for a note starting at `position`.
+    pub fn velocity_offset(&self, position: u64, step_ticks: u64) -> i8 {
+        if !position.is_multiple_of(step_ticks) {
+            return 0;
+        }
+        let idx = (position / step_ticks) % self.steps.len() as u64;
+        self.steps[idx as usize].velocity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swing() {
+        let eighths = NonZeroU16::new(8).unwrap();
+        let groove = Groove::swing(75, eighths);
+        assert!(groove.is_valid());
+        let bpm = BpmInfo::default();
+        let step = groove.step_ticks(bpm).unwrap();
+        assert_eq!(2 * step, u64::from(bpm.ticks_per_beat.get()));
+        assert_eq!(2 * step, groove.cycle_ticks(step));
+
+        // Beats stay put, while the offbeat moves to 3/4 of the way through.
+        assert_eq!(0, groove.apply(0, step));
+        assert_eq!(3 * step / 2, groove.apply(step, step));
+        assert_eq!(2 * step, groove.apply(2 * step, step));
+        let mut prev = 0;
+        for pos in 0..8 * step {
+            let cur = groove.apply(pos, step);
+            assert!(cur >= prev);
+            prev = cur;
+        }
+
+        assert!(!Groove::swing(100, eighths).is_valid());
+    }
+}
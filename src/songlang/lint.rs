@@ -0,0 +1,177 @@
+use super::ast::{AsmCommand, LangItem, OutputLabel};
+use super::{recognize_chord, song_key, ChordName, PortList};
+use crate::midi::MidiNote;
+use crate::model::{NoteClass, NoteKey};
+use crate::track::{lint_track, reachable, CompiledTrack, TrackEvent, TrackLint};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Possible problems found in a compiled song.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum SongLint {
+    /// A problem in the compiled track itself, along with the key
+    /// the song is written in.
+    Track(TrackLint, NoteKey),
+    /// Several notes forming a chord are still held at the end of the track,
+    /// or when the jump at `event` loops back.
+    HeldChord {
+        chord: ChordName,
+        event: usize,
+        at_loop: bool,
+    },
+    /// A label that no `JUMP` or `CALL` ever targets.
+    UnusedLabel(String),
+    /// An output port that no reachable instruction ever sends to.
+    UnusedPort(Option<OutputLabel>),
+}
+
+impl fmt::Display for SongLint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SongLint::Track(lint, key) => f.write_str(&lint.describe(key)),
+            SongLint::HeldChord {
+                chord,
+                event,
+                at_loop: false,
+            } => write!(
+                f,
+                "Chord {} is never released before the end of the track at event {}.",
+                chord, event
+            ),
+            SongLint::HeldChord {
+                chord,
+                event,
+                at_loop: true,
+            } => write!(
+                f,
+                "Chord {} is still held when the jump at event {} loops back.",
+                chord, event
+            ),
+            SongLint::UnusedLabel(lbl) => write!(f, "Label {:?} is never jumped to.", lbl),
+            SongLint::UnusedPort(Some(lbl)) => {
+                write!(f, "Output {:?} is never sent to.", lbl.as_ref())
+            }
+            SongLint::UnusedPort(None) => write!(f, "The default output is never sent to."),
+        }
+    }
+}
+
+/// What linting needs from a song's source, collected up front so the
+/// song can be handed to the compiler afterwards.
+#[derive(Debug, Clone)]
+pub struct LintContext {
+    key: NoteKey,
+    declared_labels: Vec<String>,
+    targeted_labels: HashSet<String>,
+}
+
+impl LintContext {
+    pub fn new(song: &[LangItem]) -> Self {
+        let key = song_key(song)
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| NoteKey::major(NoteClass::C));
+        let mut declared_labels = Vec::new();
+        let mut targeted_labels = HashSet::new();
+        collect_labels(song, &mut declared_labels, &mut targeted_labels);
+        LintContext {
+            key,
+            declared_labels,
+            targeted_labels,
+        }
+    }
+}
+
+/// Checks a song and its compiled track for likely mistakes.
+pub fn lint_song(song: &LintContext, track: &CompiledTrack, ports: &PortList) -> Vec<SongLint> {
+    let key = song.key;
+    let track_lints = lint_track(track);
+
+    // Notes left held at the same point are reported together when they form a chord.
+    let mut held = HashMap::new();
+    for lint in track_lints.iter() {
+        if let Some((place, note)) = held_note(lint) {
+            held.entry(place).or_insert_with(Vec::new).push(note);
+        }
+    }
+    let chords: HashMap<_, _> = held
+        .into_iter()
+        .filter(|(_, notes)| notes.len() > 1)
+        .filter_map(|(place, notes)| Some((place, recognize_chord(&notes, &key)?)))
+        .collect();
+
+    let mut reported = HashSet::new();
+    let mut retvl = Vec::new();
+    for lint in track_lints {
+        let held_chord =
+            held_note(&lint).and_then(|(place, _)| Some((place, *chords.get(&place)?)));
+        match held_chord {
+            Some(((event, at_loop), chord)) => {
+                if reported.insert((event, at_loop)) {
+                    retvl.push(SongLint::HeldChord {
+                        chord,
+                        event,
+                        at_loop,
+                    });
+                }
+            }
+            None => retvl.push(SongLint::Track(lint, key)),
+        }
+    }
+
+    retvl.extend(
+        song.declared_labels
+            .iter()
+            .filter(|lbl| !song.targeted_labels.contains(*lbl))
+            .cloned()
+            .map(SongLint::UnusedLabel),
+    );
+
+    let used_ports: HashSet<_> = reachable(track)
+        .into_iter()
+        .zip(track.events.iter())
+        .filter_map(|(is_reachable, evt)| match evt {
+            TrackEvent::SendMessage { port, .. } if is_reachable => Some(*port),
+            _ => None,
+        })
+        .collect();
+    let mut unused_ports: Vec<_> = ports
+        .iter()
+        .filter(|(_, port)| !used_ports.contains(port))
+        .collect();
+    unused_ports.sort_by_key(|(_, port)| **port);
+    retvl.extend(
+        unused_ports
+            .into_iter()
+            .map(|(lbl, _)| SongLint::UnusedPort(lbl.clone())),
+    );
+    retvl
+}
+
+/// Gets the event and kind of place a note was left held at, along with the note.
+fn held_note(lint: &TrackLint) -> Option<((usize, bool), MidiNote)> {
+    match lint {
+        TrackLint::HeldAtEnd { end, note } => Some(((*end, false), note.note)),
+        TrackLint::HeldAtLoop { jump, note } => Some(((*jump, true), note.note)),
+        TrackLint::Unreachable { .. } => None,
+    }
+}
+
+fn collect_labels(items: &[LangItem], declared: &mut Vec<String>, targeted: &mut HashSet<String>) {
+    for itm in items {
+        match itm {
+            LangItem::Loop { expr, .. }
+            | LangItem::Section { body: expr, .. }
+            | LangItem::VoiceLead { body: expr, .. }
+            | LangItem::Relative { body: expr, .. } => {
+                collect_labels(expr, declared, targeted)
+            }
+            LangItem::Asm(AsmCommand::Label(lbl)) => declared.push(lbl.clone()),
+            LangItem::Asm(AsmCommand::Jump { label, .. })
+            | LangItem::Asm(AsmCommand::Call(label)) => {
+                targeted.insert(label.clone());
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,13 +1,15 @@
 use super::ast::*;
+use super::VoiceLeading;
+use crate::midi::MidiNote;
 use nom::{
     branch::alt,
     bytes::complete::tag,
     character::complete::{line_ending, not_line_ending},
-    combinator::{complete, cut, map},
+    combinator::{complete, cut, map, opt},
     error::context,
-    multi::separated_list,
+    multi::{separated_list, separated_nonempty_list},
     sequence::delimited,
-    sequence::{preceded, terminated},
+    sequence::{preceded, separated_pair, terminated},
 };
 
 use std::num::NonZeroU16;
@@ -24,6 +26,15 @@ pub use values::*;
 mod playcmd;
 pub use playcmd::*;
 
+mod dynamics;
+pub use dynamics::*;
+
+mod attributes;
+pub use attributes::*;
+
+mod groove;
+pub use groove::*;
+
 pub type ParseError<'a> = nom::error::VerboseError<&'a str>;
 
 pub type ParseResult<'a, T> = nom::IResult<&'a str, T, ParseError<'a>>;
@@ -43,6 +54,14 @@ pub fn parse_expr(input: &str) -> ParseResult<LangItem> {
         "Songlang Expression",
         alt((
             parse_loop,
+            parse_voicelead,
+            parse_relative,
+            parse_section,
+            parse_arrange,
+            parse_hairpin,
+            parse_groove,
+            parse_scale_definition,
+            map(parse_attribute, LangItem::SetAttribute),
             map(parse_pressline, LangItem::NotePress),
             map(parse_asm_command, LangItem::Asm),
         )),
@@ -85,6 +104,74 @@ pub fn parse_loop(input: &str) -> ParseResult<LangItem> {
     Ok((input, res))
 }
 
+/// Parses a `voicelead [range C3-C5] [rootbass] { ... }` block.
+pub fn parse_voicelead(input: &str) -> ParseResult<LangItem> {
+    let (input, _) = tag("voicelead")(input)?;
+    let note_parser = |input| {
+        let (input, (note, octave)) = parse_notepitch(input)?;
+        Ok((input, MidiNote::from_note_octave(note, octave)))
+    };
+    let range_parser = preceded(
+        terminated(tag("range"), space1),
+        separated_pair(note_parser, delimited(space0, tag("-"), space0), note_parser),
+    );
+    let (input, range) = opt(preceded(space1, range_parser))(input)?;
+    let (input, root_in_bass) = opt(preceded(space1, tag("rootbass")))(input)?;
+    let (input, _) = space0(input)?;
+    let (input, body) = parse_block(input)?;
+    let res = LangItem::VoiceLead {
+        settings: VoiceLeading {
+            range,
+            root_in_bass: root_in_bass.is_some(),
+        },
+        body,
+    };
+    Ok((input, res))
+}
+
+/// Parses a `relative c4 { ... }` block.
+pub fn parse_relative(input: &str) -> ParseResult<LangItem> {
+    let (input, _) = tag("relative")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, (note, octave)) = parse_notepitch(input)?;
+    let (input, _) = space0(input)?;
+    let (input, body) = parse_block(input)?;
+    let res = LangItem::Relative {
+        start: MidiNote::from_note_octave(note, octave),
+        body,
+    };
+    Ok((input, res))
+}
+
+pub fn parse_section(input: &str) -> ParseResult<LangItem> {
+    let (input, _) = tag("section")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, name) = parse_rawlabel(input)?;
+    let (input, _) = space0(input)?;
+    let (input, body) = parse_block(input)?;
+    let res = LangItem::Section {
+        name: name.to_owned(),
+        body,
+    };
+    Ok((input, res))
+}
+
+pub fn parse_arrange(input: &str) -> ParseResult<LangItem> {
+    let entry_parser = |input| {
+        let (input, section) = parse_rawlabel(input)?;
+        let (input, repetitions) = opt(preceded(tag("*"), nonzerou16))(input)?;
+        let res = ArrangeEntry {
+            section: section.to_owned(),
+            repetitions: repetitions.unwrap_or(crate::ONE_NZU16),
+        };
+        Ok((input, res))
+    };
+    let (input, _) = tag("arrange")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, entries) = separated_nonempty_list(space1, entry_parser)(input)?;
+    Ok((input, LangItem::Arrange(entries)))
+}
+
 pub fn parse_comment_inline(input: &str) -> ParseResult<()> {
     let body_parser = |inp: &str| {
         let endparser = alt((eof, tag("*/"), line_ending));
@@ -3,7 +3,7 @@ use crate::track::BpmInfo;
 
 use nom::{
     branch::alt, bytes::complete::tag, bytes::complete::tag_no_case, character::complete::alpha1,
-    combinator::map, error::context,
+    combinator::{map, opt}, error::context,
 };
 
 use super::{
@@ -25,6 +25,8 @@ mod utils {
     }
 }
 use utils::*;
+// Sections and arrangements name their parts the same way labels are named.
+pub(super) use utils::parse_rawlabel;
 
 mod midimessages {
     use super::*;
@@ -102,9 +104,18 @@ fn parse_setbpm(input: &str) -> ParseResult<AsmCommand> {
     let (input, _) = tag(",")(input)?;
     let (input, _) = space0(input)?;
     let (input, ticks) = nonzerou16(input)?;
+    // The number of beats in a bar is optional, and defaults to 4/4 time.
+    let bar_parser = |input| {
+        let (input, _) = space0(input)?;
+        let (input, _) = tag(",")(input)?;
+        let (input, _) = space0(input)?;
+        nonzerou16(input)
+    };
+    let (input, beats_per_bar) = opt(bar_parser)(input)?;
     let res = BpmInfo {
         ticks_per_beat: ticks,
         beats_per_minute: bpm,
+        beats_per_bar: beats_per_bar.unwrap_or_else(|| BpmInfo::default().beats_per_bar),
     };
     let evt = AsmCommand::SetBpm(res);
     Ok((input, evt))
@@ -126,6 +137,18 @@ fn parse_jump(input: &str) -> ParseResult<AsmCommand> {
     Ok((input, res))
 }
 
+fn parse_call(input: &str) -> ParseResult<AsmCommand> {
+    let (input, _) = tag_no_case("CALL")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, label) = parse_rawlabel(input)?;
+    Ok((input, AsmCommand::Call(label.to_owned())))
+}
+
+fn parse_return(input: &str) -> ParseResult<AsmCommand> {
+    let (input, _) = tag_no_case("RET")(input)?;
+    Ok((input, AsmCommand::Return))
+}
+
 fn parse_label(input: &str) -> ParseResult<AsmCommand> {
     let (input, _) = tag_no_case("LABEL")(input)?;
     let (input, _) = space1(input)?;
@@ -159,5 +182,45 @@ pub fn parse_asm_command(input: &str) -> ParseResult<AsmCommand> {
         context("ASM WAIT", parse_wait),
         context("ASM LABEL", parse_label),
         context("ASM JUMP", parse_jump),
+        context("ASM CALL", parse_call),
+        context("ASM RET", parse_return),
     ))(input)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU16;
+
+    #[test]
+    fn test_parse_setbpm() {
+        let bpm = |raw: &str| match parse_asm_command(raw).unwrap() {
+            ("", AsmCommand::SetBpm(bpm)) => bpm,
+            other => panic!("{:?} parsed as {:?}", raw, other),
+        };
+        let common = bpm("SETBPM 120, 32");
+        assert_eq!(120, common.beats_per_minute.get());
+        assert_eq!(32, common.ticks_per_beat.get());
+        assert_eq!(BpmInfo::default().beats_per_bar, common.beats_per_bar);
+
+        let waltz = bpm("SETBPM 90,4 , 3");
+        assert_eq!(90, waltz.beats_per_minute.get());
+        assert_eq!(4, waltz.ticks_per_beat.get());
+        assert_eq!(NonZeroU16::new(3).unwrap(), waltz.beats_per_bar);
+        assert_eq!(12, waltz.ticks_per_bar());
+    }
+
+    #[test]
+    fn test_parse_call_return() {
+        let (rest, call) = parse_asm_command("CALL chorus\n").unwrap();
+        assert_eq!(("\n", AsmCommand::Call("chorus".to_owned())), (rest, call));
+        let (rest, call) = parse_asm_command("call  verse").unwrap();
+        assert_eq!(("", AsmCommand::Call("verse".to_owned())), (rest, call));
+        assert!(parse_asm_command("CALL").is_err());
+        assert!(parse_asm_command("CALL ").is_err());
+
+        let (rest, ret) = parse_asm_command("RET\n").unwrap();
+        assert_eq!(("\n", AsmCommand::Return), (rest, ret));
+        assert_eq!(AsmCommand::Return, parse_asm_command("ret").unwrap().1);
+    }
+}
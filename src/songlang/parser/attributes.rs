@@ -0,0 +1,193 @@
+use super::{
+    parse_channel, parse_dynamic, parse_noteclass, parse_octave, parse_spellednote,
+    parse_velocity, rawuint, space0, space1, ParseResult,
+};
+use crate::midi::{MidiChannel, MidiNote};
+use crate::model::{Octave, ScaleKind};
+use crate::songlang::ast::{KeyChoice, LangItem, ScaleChoice, SongAttribute, TuningAttribute};
+use crate::tuning::{default_bend_channels, TuningMethod};
+
+use nom::{
+    branch::alt,
+    bytes::complete::{is_not, tag, tag_no_case, take_while1},
+    combinator::{map, map_opt, map_res, opt},
+    error::context,
+    multi::separated_nonempty_list,
+    sequence::{delimited, preceded, separated_pair},
+};
+
+use std::num::NonZeroU32;
+use std::str::FromStr;
+
+fn parse_dynamic_level(input: &str) -> ParseResult<SongAttribute> {
+    let (input, _) = tag_no_case("dynamic")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, dynamic) = parse_dynamic(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("=")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, vel) = parse_velocity(input)?;
+    Ok((input, SongAttribute::DynamicLevel(dynamic, vel)))
+}
+
+fn parse_quoted_path(input: &str) -> ParseResult<String> {
+    let (input, path) = delimited(tag("\""), is_not("\""), tag("\""))(input)?;
+    Ok((input, path.to_owned()))
+}
+
+/// Parses a frequency in Hz with up to 3 decimal places, returning it in millihertz.
+fn parse_millihertz(input: &str) -> ParseResult<NonZeroU32> {
+    let whole_parser = map_res(rawuint, u32::from_str);
+    let fraction_parser = map_opt(preceded(tag("."), rawuint), |digits: &str| {
+        if digits.len() > 3 {
+            return None;
+        }
+        let padded = format!("{:0<3}", digits);
+        u32::from_str(&padded).ok()
+    });
+    let (input, whole) = whole_parser(input)?;
+    let (input, fraction) = opt(fraction_parser)(input)?;
+    let raw = whole
+        .checked_mul(1000)
+        .and_then(|mhz| mhz.checked_add(fraction.unwrap_or(0)))
+        .and_then(NonZeroU32::new);
+    match raw {
+        Some(mhz) => Ok((input, mhz)),
+        None => Err(nom::Err::Error(nom::error::make_error(
+            input,
+            nom::error::ErrorKind::MapOpt,
+        ))),
+    }
+}
+
+fn parse_reference_pitch(input: &str) -> ParseResult<TuningAttribute> {
+    let (input, _) = tag_no_case("reference")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, spelled) = parse_spellednote(input)?;
+    let octave_parser = map_opt(parse_octave, |written| spelled.sounding_octave(written));
+    let (input, octave) = opt(octave_parser)(input)?;
+    let (input, _) = delimited(space0, tag("="), space0)(input)?;
+    let (input, millihertz) = parse_millihertz(input)?;
+    let octave = octave.unwrap_or_else(|| Octave::from_raw(4).unwrap());
+    let note = MidiNote::from_note_octave(spelled.note_class(), octave);
+    Ok((input, TuningAttribute::Reference { note, millihertz }))
+}
+
+fn parse_bend_method(input: &str) -> ParseResult<TuningMethod> {
+    let (input, _) = tag_no_case("bend")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, range) = map_res(rawuint, u8::from_str)(input)?;
+    let channel_range = map_opt(
+        separated_pair(parse_channel, tag("-"), parse_channel),
+        |(first, last)| {
+            let all = MidiChannel::all();
+            all.get(first.as_u8() as usize..=last.as_u8() as usize)
+                .filter(|channels| !channels.is_empty())
+        },
+    );
+    let (input, channels) = opt(preceded(
+        delimited(space1, tag_no_case("channels"), space1),
+        channel_range,
+    ))(input)?;
+    let channels = channels.unwrap_or_else(default_bend_channels).to_vec();
+    Ok((input, TuningMethod::PitchBend { range, channels }))
+}
+
+fn parse_tuning(input: &str) -> ParseResult<SongAttribute> {
+    let (input, _) = tag_no_case("tuning")(input)?;
+    let (input, _) = space1(input)?;
+    let scale_parser = preceded(
+        preceded(tag_no_case("scale"), space1),
+        map(parse_quoted_path, TuningAttribute::Scale),
+    );
+    let keymap_parser = preceded(
+        preceded(tag_no_case("keymap"), space1),
+        map(parse_quoted_path, TuningAttribute::KeyMap),
+    );
+    let method_parser = alt((
+        parse_bend_method,
+        map(tag_no_case("mts"), |_| TuningMethod::Mts),
+    ));
+    let (input, attr) = alt((
+        scale_parser,
+        keymap_parser,
+        parse_reference_pitch,
+        map(method_parser, TuningAttribute::Method),
+    ))(input)?;
+    Ok((input, SongAttribute::Tuning(attr)))
+}
+
+/// Parses the name of a scale, IE `dorian` or `harmonic-minor`.
+fn parse_scale_name(input: &str) -> ParseResult<&str> {
+    take_while1(|c: char| c.is_ascii_alphabetic() || c == '-')(input)
+}
+
+fn parse_key(input: &str) -> ParseResult<SongAttribute> {
+    let (input, _) = tag_no_case("key")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, root) = parse_noteclass(input)?;
+    let (input, _) = space1(input)?;
+    let (input, scale) = map(parse_scale_name, |name: &str| match ScaleKind::from_name(name) {
+        Some(kind) => ScaleChoice::Builtin(kind),
+        None => ScaleChoice::Named(name.to_owned()),
+    })(input)?;
+    Ok((input, SongAttribute::Key(KeyChoice { root, scale })))
+}
+
+/// Parses a scale declaration, IE `scale my-scale = 0 2 3 7 9`.
+pub fn parse_scale_definition(input: &str) -> ParseResult<LangItem> {
+    let (input, _) = tag_no_case("scale")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, name) = parse_scale_name(input)?;
+    let (input, _) = delimited(space0, tag("="), space0)(input)?;
+    let interval_parser = map_opt(rawuint, |digits: &str| {
+        u8::from_str(digits).ok().filter(|offset| *offset < 12)
+    });
+    let (input, intervals) = separated_nonempty_list(space1, interval_parser)(input)?;
+    let res = LangItem::DefineScale {
+        name: name.to_owned(),
+        intervals,
+    };
+    Ok((input, res))
+}
+
+pub fn parse_attribute(input: &str) -> ParseResult<SongAttribute> {
+    alt((
+        context("Attribute dynamic", parse_dynamic_level),
+        context("Attribute key", parse_key),
+        context("Attribute tuning", parse_tuning),
+    ))(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::NoteClass;
+
+    #[test]
+    fn test_parse_scales() {
+        let (rest, scale) = parse_scale_definition("scale my-scale = 0 2 3 7 9\n").unwrap();
+        assert_eq!("\n", rest);
+        let expected = LangItem::DefineScale {
+            name: "my-scale".to_owned(),
+            intervals: vec![0, 2, 3, 7, 9],
+        };
+        assert_eq!(expected, scale);
+        // Offsets are within an octave.
+        assert_eq!(" 12", parse_scale_definition("scale wide = 0 12").unwrap().0);
+
+        let key = |raw: &str| match parse_attribute(raw).unwrap() {
+            ("", SongAttribute::Key(key)) => key,
+            other => panic!("{:?} parsed as {:?}", raw, other),
+        };
+        let d_dorian = KeyChoice {
+            root: NoteClass::D,
+            scale: ScaleChoice::Builtin(ScaleKind::Dorian),
+        };
+        assert_eq!(d_dorian, key("key D dorian"));
+        let harmonic = ScaleChoice::Builtin(ScaleKind::HarmonicMinor);
+        assert_eq!(harmonic, key("key A harmonic-minor").scale);
+        // Keys and scale definitions name scales the same way.
+        assert_eq!(ScaleChoice::Named("my-scale".to_owned()), key("key C my-scale").scale);
+    }
+}
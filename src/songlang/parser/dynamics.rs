@@ -0,0 +1,55 @@
+use super::{nonzerou16, parse_dynamic, parse_rawduration, space0, space1, ParseResult};
+use crate::songlang::ast::{Hairpin, LangItem, SpanLength, VelocityCurve};
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, tag_no_case},
+    combinator::{map, opt},
+    sequence::preceded,
+};
+
+pub fn parse_spanlength(input: &str) -> ParseResult<SpanLength> {
+    let bars_parser = |input| {
+        let (input, n) = nonzerou16(input)?;
+        let (input, _) = space1(input)?;
+        let (input, _) = alt((tag_no_case("bars"), tag_no_case("bar")))(input)?;
+        Ok((input, SpanLength::Bars(n)))
+    };
+    alt((bars_parser, map(parse_rawduration, SpanLength::Time)))(input)
+}
+
+fn parse_curve(input: &str) -> ParseResult<VelocityCurve> {
+    alt((
+        map(tag_no_case("linear"), |_| VelocityCurve::Linear),
+        map(tag_no_case("exp"), |_| VelocityCurve::Exponential),
+    ))(input)
+}
+
+pub fn parse_hairpin(input: &str) -> ParseResult<LangItem> {
+    let (input, _) = alt((
+        tag_no_case("crescendo"),
+        tag_no_case("cresc"),
+        tag_no_case("diminuendo"),
+        tag_no_case("dim"),
+        tag_no_case("decrescendo"),
+        tag_no_case("decresc"),
+    ))(input)?;
+    let (input, _) = space1(input)?;
+    let (input, from) = parse_dynamic(input)?;
+    let (input, _) = space0(input)?;
+    let (input, _) = tag("->")(input)?;
+    let (input, _) = space0(input)?;
+    let (input, to) = parse_dynamic(input)?;
+    let (input, _) = space1(input)?;
+    let (input, _) = tag_no_case("over")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, length) = parse_spanlength(input)?;
+    let (input, curve) = opt(preceded(space1, parse_curve))(input)?;
+    let res = Hairpin {
+        from,
+        to,
+        length,
+        curve: curve.unwrap_or(VelocityCurve::Linear),
+    };
+    Ok((input, LangItem::Hairpin(res)))
+}
@@ -0,0 +1,85 @@
+use super::{nonzerou16, rawint, rawuint, space0, space1, ParseResult};
+use crate::songlang::ast::{GrooveChoice, LangItem};
+use crate::songlang::{Groove, GrooveStep};
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, tag_no_case},
+    character::complete::alpha1,
+    combinator::{map, map_res, opt},
+    multi::separated_nonempty_list,
+    sequence::{delimited, preceded, terminated},
+};
+
+use std::num::NonZeroU16;
+use std::str::FromStr;
+
+fn signed_i8(input: &str) -> ParseResult<i8> {
+    map_res(alt((preceded(tag("+"), rawuint), rawint)), i8::from_str)(input)
+}
+
+/// Parses the ` per N` suffix giving a groove's subdivision, defaulting to eighth notes.
+fn parse_subdivision(input: &str) -> ParseResult<NonZeroU16> {
+    let (input, subdivision) = opt(preceded(
+        delimited(space1, tag_no_case("per"), space1),
+        nonzerou16,
+    ))(input)?;
+    let eighths = NonZeroU16::new(8).unwrap();
+    Ok((input, subdivision.unwrap_or(eighths)))
+}
+
+fn parse_groove_step(input: &str) -> ParseResult<GrooveStep> {
+    let (input, timing) = terminated(signed_i8, tag("%"))(input)?;
+    let (input, velocity) = opt(preceded(
+        delimited(space1, tag_no_case("vel"), space1),
+        signed_i8,
+    ))(input)?;
+    let res = GrooveStep {
+        timing,
+        velocity: velocity.unwrap_or(0),
+    };
+    Ok((input, res))
+}
+
+fn parse_swing(input: &str) -> ParseResult<LangItem> {
+    let (input, _) = tag_no_case("swing")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, percent) = terminated(map_res(rawuint, u8::from_str), tag("%"))(input)?;
+    let (input, subdivision) = parse_subdivision(input)?;
+    let groove = Groove::swing(percent, subdivision);
+    Ok((input, LangItem::SetGroove(GrooveChoice::Template(groove))))
+}
+
+fn parse_groove_definition(input: &str) -> ParseResult<LangItem> {
+    let (input, _) = tag_no_case("groove")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, name) = alpha1(input)?;
+    let (input, subdivision) = parse_subdivision(input)?;
+    let (input, _) = delimited(space0, tag("="), space0)(input)?;
+    let step_sep = delimited(space0, tag(","), space0);
+    let (input, steps) = separated_nonempty_list(step_sep, parse_groove_step)(input)?;
+    let res = LangItem::DefineGroove {
+        name: name.to_owned(),
+        groove: Groove { subdivision, steps },
+    };
+    Ok((input, res))
+}
+
+fn parse_groove_use(input: &str) -> ParseResult<LangItem> {
+    let (input, _) = tag_no_case("groove")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, name) = alpha1(input)?;
+    let res = LangItem::SetGroove(GrooveChoice::Named(name.to_owned()));
+    Ok((input, res))
+}
+
+pub fn parse_groove(input: &str) -> ParseResult<LangItem> {
+    alt((
+        parse_swing,
+        map(tag_no_case("straight"), |_| {
+            LangItem::SetGroove(GrooveChoice::Straight)
+        }),
+        parse_groove_definition,
+        parse_groove_use,
+    ))(input)
+}
@@ -1,16 +1,20 @@
 use super::{
-    parse_channel, parse_fullchord, parse_outputlabel, parse_rawduration, parse_velocity, space0,
-    space1, ChordPress, ParseResult, PressLine, PressModifier,
+    parse_channel, parse_dynamic, parse_fullchord, parse_outputlabel, parse_rawduration,
+    parse_velocity, rawuint, space0, space1, ChordPress, ParseResult, PressLine, PressModifier,
+    StrumDirection,
 };
 
 use nom::{
     branch::alt,
     bytes::complete::{tag, tag_no_case},
-    combinator::{map, opt},
+    combinator::{map, map_res, opt, verify},
     multi::{separated_list, separated_nonempty_list},
     sequence::{delimited, preceded},
 };
 
+use std::num::NonZeroU8;
+use std::str::FromStr;
+
 pub fn parse_pressline(input: &str) -> ParseResult<PressLine> {
     let (input, _) = tag_no_case("play")(input)?;
     let (input, _) = space1(input)?;
@@ -28,13 +32,12 @@ pub fn parse_pressline(input: &str) -> ParseResult<PressLine> {
 
 fn parse_chordpress(input: &str) -> ParseResult<ChordPress> {
     let (input, (root, octave, kind)) = parse_fullchord(input)?;
-    let (input, _) = space1(input)?;
-    let (input, modifiers) = parse_press_modifiers(input)?;
+    let (input, modifiers) = opt(preceded(space1, parse_press_modifiers))(input)?;
     let res = ChordPress {
         root,
         octave,
         kind,
-        modifiers,
+        modifiers: modifiers.unwrap_or_default(),
     };
     Ok((input, res))
 }
@@ -44,6 +47,7 @@ fn parse_press_modifiers(input: &str) -> ParseResult<Vec<PressModifier>> {
         alt((
             map(parse_duration_mod, |res| (Some(res), None)),
             map(parse_velocity_mod, |res| (Some(res), None)),
+            map(parse_voicing_mod, |res| (Some(res), None)),
             parse_outputline_mod,
         ))(input)
     };
@@ -60,11 +64,55 @@ fn parse_velocity_mod(input: &str) -> ParseResult<PressModifier> {
     let (input, _) = space0(input)?;
     let (input, _) = tag("=")(input)?;
     let (input, _) = space0(input)?;
-    let (input, vel) = parse_velocity(input)?;
-    let res = PressModifier::Velocity(vel);
+    alt((
+        map(parse_velocity, PressModifier::Velocity),
+        map(parse_dynamic, PressModifier::Dynamic),
+    ))(input)
+}
+
+fn parse_inversion_mod(input: &str) -> ParseResult<PressModifier> {
+    let (input, _) = tag_no_case("inv")(input)?;
+    let (input, _) = delimited(space0, tag("="), space0)(input)?;
+    let (input, inversion) = map_res(rawuint, u8::from_str)(input)?;
+    Ok((input, PressModifier::Inversion(inversion)))
+}
+
+fn parse_spread_mod(input: &str) -> ParseResult<PressModifier> {
+    let (input, _) = tag_no_case("spread")(input)?;
+    let (input, _) = delimited(space0, tag("="), space0)(input)?;
+    // A chord always fits in one octave, so spreading it over one does nothing.
+    let octaves_parser = map_res(rawuint, NonZeroU8::from_str);
+    let (input, octaves) = verify(octaves_parser, |octaves: &NonZeroU8| octaves.get() > 1)(input)?;
+    let (input, _) = opt(tag_no_case("oct"))(input)?;
+    Ok((input, PressModifier::Spread(octaves)))
+}
+
+fn parse_strum_mod(input: &str) -> ParseResult<PressModifier> {
+    let (input, _) = tag_no_case("strum")(input)?;
+    let (input, _) = delimited(space0, tag("="), space0)(input)?;
+    let (input, step) = parse_rawduration(input)?;
+    let direction_parser = alt((
+        map(tag_no_case("up"), |_| StrumDirection::Up),
+        map(tag_no_case("down"), |_| StrumDirection::Down),
+    ));
+    let (input, direction) = opt(preceded(space1, direction_parser))(input)?;
+    let res = PressModifier::Strum {
+        step,
+        direction: direction.unwrap_or(StrumDirection::Up),
+    };
     Ok((input, res))
 }
 
+fn parse_voicing_mod(input: &str) -> ParseResult<PressModifier> {
+    alt((
+        parse_inversion_mod,
+        map(tag_no_case("drop2"), |_| PressModifier::Drop2),
+        map(tag_no_case("open"), |_| PressModifier::Open),
+        parse_spread_mod,
+        parse_strum_mod,
+    ))(input)
+}
+
 fn parse_duration_mod(input: &str) -> ParseResult<PressModifier> {
     let (input, _) = tag_no_case("for")(input)?;
     let (input, _) = space1(input)?;
@@ -97,3 +145,65 @@ fn parse_outputline_channel(input: &str) -> ParseResult<PressModifier> {
     let res = PressModifier::Channel(channel);
     Ok((input, res))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::WaitTime;
+    use std::num::NonZeroU16;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_voicing_mod() {
+        let expect = |raw: &str, modifier: PressModifier| {
+            let (rest, res) = parse_voicing_mod(raw).unwrap();
+            assert!(rest.is_empty(), "{:?} left {:?}", raw, rest);
+            assert_eq!(modifier, res, "{:?}", raw);
+        };
+        expect("inv=1", PressModifier::Inversion(1));
+        expect("INV = 2", PressModifier::Inversion(2));
+        expect("drop2", PressModifier::Drop2);
+        expect("open", PressModifier::Open);
+        expect("spread=2", PressModifier::Spread(NonZeroU8::new(2).unwrap()));
+        expect("spread = 3oct", PressModifier::Spread(NonZeroU8::new(3).unwrap()));
+        assert!(parse_voicing_mod("spread=1").is_err());
+        assert!(parse_voicing_mod("spread=0").is_err());
+
+        let ticks = WaitTime::Ticks(NonZeroU16::new(10).unwrap());
+        let millis = WaitTime::Clock(Duration::from_millis(30));
+        expect(
+            "strum=10 ticks",
+            PressModifier::Strum {
+                step: ticks,
+                direction: StrumDirection::Up,
+            },
+        );
+        expect(
+            "strum=30ms down",
+            PressModifier::Strum {
+                step: millis,
+                direction: StrumDirection::Down,
+            },
+        );
+    }
+
+    #[test]
+    fn test_parse_pressline_modifiers() {
+        let (rest, line) = parse_pressline("play strum=30ms C4M inv=1, G3 vel=mf\n").unwrap();
+        assert_eq!("\n", rest);
+        let step = WaitTime::Clock(Duration::from_millis(30));
+        assert_eq!(
+            vec![PressModifier::Strum {
+                step,
+                direction: StrumDirection::Up
+            }],
+            line.modifiers
+        );
+        assert_eq!(2, line.presses.len());
+        assert_eq!(vec![PressModifier::Inversion(1)], line.presses[0].modifiers);
+        assert_eq!(
+            vec![PressModifier::Dynamic(crate::model::Dynamic::Mf)],
+            line.presses[1].modifiers
+        );
+    }
+}
@@ -1,18 +1,20 @@
 use nom::{
     alt,
-    bytes::complete::tag,
-    character::complete::alpha1,
-    combinator::{map, map_opt, map_res, opt},
+    branch::alt,
+    bytes::complete::{tag, take_while_m_n},
+    character::complete::{alpha1, alphanumeric1, multispace1},
+    combinator::{map, map_opt, map_res, not, opt, peek, recognize},
     error::context,
+    multi::many0,
     named,
-    sequence::tuple,
+    sequence::{preceded, terminated, tuple},
     tag, tag_no_case,
 };
 
-use super::{nonzerou16, nonzerou64, rawint, rawuint, ParseError, ParseResult};
+use super::{eof, nonzerou16, nonzerou64, rawuint, space0, ParseError, ParseResult};
 use crate::midi::{MidiChannel, PressVelocity};
-use crate::model::{NoteClass, Octave};
-use crate::songlang::ast::{ChordKind, OutputLabel};
+use crate::model::{Dynamic, Letter, NoteClass, Octave, SpelledNote};
+use crate::songlang::ast::{ChordKind, OutputLabel, PressOctave};
 use std::str::FromStr;
 
 mod times;
@@ -29,15 +31,17 @@ pub fn parse_channel(input: &str) -> ParseResult<MidiChannel> {
     channel_parser(input)
 }
 
+/// Parses an octave, which is always a single digit so that a fifth chord
+/// like `C45` reads as C4 plus the `5` suffix.
 pub fn parse_octave(input: &str) -> ParseResult<Octave> {
-    let i8_parser = map_res(rawint, i8::from_str);
+    let digit = take_while_m_n(1, 1, |c: char| c.is_ascii_digit());
+    let digit_parser = recognize(preceded(opt(tag("-")), digit));
+    let i8_parser = map_res(digit_parser, i8::from_str);
     map_opt(i8_parser, Octave::from_raw)(input)
 }
 
 pub fn parse_notepitch(input: &str) -> ParseResult<(NoteClass, Octave)> {
-    let (input, note) = context("Parse Noteclass", parse_noteclass)(input)?;
-    let (input, octave) = context("Parse Octave", parse_octave)(input)?;
-    Ok((input, (note, octave)))
+    parse_spelled_octave(input)
 }
 
 pub fn parse_velocity(input: &str) -> ParseResult<PressVelocity> {
@@ -52,11 +56,37 @@ pub fn parse_outputlabel(input: &str) -> ParseResult<OutputLabel> {
     Ok((input, res))
 }
 
-pub fn parse_fullchord(input : &str) -> ParseResult<(NoteClass, Octave, ChordKind)> {
-    let (input, note) = context("Parse Noteclass", parse_noteclass)(input)?;
-    let (input, octave) = context("Parse Octave", parse_octave)(input)?;
+/// Checks whether a press starts here, so that a comma before it is read
+/// as separating the two presses rather than as an octave mark.
+fn starts_press(input: &str) -> ParseResult<()> {
+    let press_end = alt((multispace1, tag(","), eof));
+    let (input, _) = space0(input)?;
+    let (input, _) = parse_fullchord(input)?;
+    let (input, _) = peek(press_end)(input)?;
+    Ok((input, ()))
+}
+
+/// Parses the `'` and `,` marks that move a relative note up or down an
+/// octave, returning the number of octaves moved. A comma followed by
+/// another press separates the two presses instead, so `c, e` is two notes
+/// while `c,, e` is a low `c` and an `e`.
+fn parse_octave_marks(input: &str) -> ParseResult<i8> {
+    let up = map(tag("'"), |_| 1);
+    let down = map(terminated(tag(","), not(peek(starts_press))), |_| -1);
+    let (input, marks) = many0(alt((up, down)))(input)?;
+    Ok((input, marks.into_iter().sum()))
+}
+
+pub fn parse_fullchord(input : &str) -> ParseResult<(NoteClass, PressOctave, ChordKind)> {
+    let (input, note) = context("Parse Noteclass", parse_spellednote)(input)?;
+    let absolute_parser = map_opt(parse_octave, |written| note.sounding_octave(written));
+    let octave_parser = alt((
+        map(absolute_parser, PressOctave::Absolute),
+        map(parse_octave_marks, PressOctave::Relative),
+    ));
+    let (input, octave) = context("Parse Octave", octave_parser)(input)?;
     let (input, choord) = context("Parse Choordkind", parse_chordkind)(input)?;
-    Ok((input, (note, octave, choord)))
+    Ok((input, (note.note_class(), octave, choord)))
 }
 
 named!(
@@ -72,36 +102,139 @@ named!(
 );
 
 named!(
-    pub parse_noteclass<&str, NoteClass, ParseError>,
+    parse_rawdynamic<&str, Dynamic, ParseError>,
     alt!(
-        tag_no_case!("Ab") => {|_| NoteClass::Gs} |
-        tag_no_case!("A#") => {|_| NoteClass::As} |
-        tag_no_case!("As") => {|_| NoteClass::As} |
-        tag_no_case!("A") => {|_| NoteClass::A} |
+        tag!("ppp") => {|_| Dynamic::Ppp} |
+        tag!("pp") => {|_| Dynamic::Pp} |
+        tag!("p") => {|_| Dynamic::P} |
+        tag!("mp") => {|_| Dynamic::Mp} |
+        tag!("mf") => {|_| Dynamic::Mf} |
+        tag!("fff") => {|_| Dynamic::Fff} |
+        tag!("ff") => {|_| Dynamic::Ff} |
+        tag!("f") => {|_| Dynamic::F}
+    )
+);
 
-        tag_no_case!("Bb") => {|_| NoteClass::As} |
-        tag_no_case!("B") => {|_| NoteClass::B} |
+/// Parses a dynamic marking, which has to be a whole word so that names
+/// starting with `p` or `f` are left alone.
+pub fn parse_dynamic(input: &str) -> ParseResult<Dynamic> {
+    terminated(parse_rawdynamic, not(peek(alphanumeric1)))(input)
+}
 
-        tag_no_case!("C#") => {|_| NoteClass::Cs} |
-        tag_no_case!("Cs") => {|_| NoteClass::Cs} |
-        tag_no_case!("C") => {|_| NoteClass::C} |
+named!(
+    pub parse_letter<&str, Letter, ParseError>,
+    alt!(
+        tag_no_case!("C") => {|_| Letter::C} |
+        tag_no_case!("D") => {|_| Letter::D} |
+        tag_no_case!("E") => {|_| Letter::E} |
+        tag_no_case!("F") => {|_| Letter::F} |
+        tag_no_case!("G") => {|_| Letter::G} |
+        tag_no_case!("A") => {|_| Letter::A} |
+        tag_no_case!("B") => {|_| Letter::B} |
+        // German notation.
+        tag_no_case!("H") => {|_| Letter::B}
+    )
+);
 
-        tag_no_case!("D#") => {|_| NoteClass::Ds} |
-        tag_no_case!("Ds") => {|_| NoteClass::Ds} |
-        tag_no_case!("Db") => {|_| NoteClass::Cs} |
-        tag_no_case!("D") => {|_| NoteClass::D} |
+// A trailing `s` is a sharp, as in the names of `NoteClass`, so `As4` is
+// A#4 and `Es4` is E#4 rather than the German A-flat and E-flat. German
+// flats (`es`, `as`) are not supported, since German `B` would then have to
+// mean B-flat; only `H` is taken from German notation.
+named!(
+    pub parse_accidental<&str, i8, ParseError>,
+    alt!(
+        tag!("##") => {|_| 2} |
+        tag!("x") => {|_| 2} |
+        tag!("\u{1D12A}") => {|_| 2} |
+        tag_no_case!("bb") => {|_| -2} |
+        tag!("\u{1D12B}") => {|_| -2} |
+        tag!("#") => {|_| 1} |
+        tag!("\u{266F}") => {|_| 1} |
+        tag_no_case!("s") => {|_| 1} |
+        tag_no_case!("b") => {|_| -1} |
+        tag!("\u{266D}") => {|_| -1} |
+        tag!("\u{266E}") => {|_| 0} |
+        tag!("") => {|_| 0}
+    )
+);
 
-        tag_no_case!("Eb") => {|_| NoteClass::Ds} |
-        tag_no_case!("E") => {|_| NoteClass::E} |
+pub fn parse_spellednote(input: &str) -> ParseResult<SpelledNote> {
+    let parser = map_opt(tuple((parse_letter, parse_accidental)), |(letter, accidental)| {
+        SpelledNote::new(letter, accidental)
+    });
+    parser(input)
+}
 
-        tag_no_case!("F#") => {|_| NoteClass::Fs} |
-        tag_no_case!("Fs") => {|_| NoteClass::Fs} |
-        tag_no_case!("F") => {|_| NoteClass::F} |
+pub fn parse_noteclass(input: &str) -> ParseResult<NoteClass> {
+    map(parse_spellednote, |spelled| spelled.note_class())(input)
+}
 
-        tag_no_case!("Gb") => {|_| NoteClass::Fs} |
-        tag_no_case!("G#") => {|_| NoteClass::Gs} |
-        tag_no_case!("Gs") => {|_| NoteClass::Gs} |
-        tag_no_case!("G") => {|_| NoteClass::G}
+/// Parses a spelled note followed by the octave it is written in, returning
+/// the octave the note actually sounds in, IE `B#3` is the same pitch as `C4`.
+fn parse_spelled_octave(input: &str) -> ParseResult<(NoteClass, Octave)> {
+    let (input, note) = context("Parse Noteclass", parse_spellednote)(input)?;
+    let octave_parser = map_opt(parse_octave, |written| note.sounding_octave(written));
+    let (input, octave) = context("Parse Octave", octave_parser)(input)?;
+    Ok((input, (note.note_class(), octave)))
+}
 
-    )
-);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_notepitch() {
+        let expect = |raw: &str, note: NoteClass, octave: i8| {
+            let (rest, res) = parse_notepitch(raw).unwrap();
+            assert!(rest.is_empty(), "{:?} left {:?}", raw, rest);
+            assert_eq!((note, Octave::from_raw(octave).unwrap()), res, "{:?}", raw);
+        };
+        expect("C4", NoteClass::C, 4);
+        expect("Ab4", NoteClass::Gs, 4);
+        expect("Cb4", NoteClass::B, 3);
+        expect("B#3", NoteClass::C, 4);
+        expect("Fb4", NoteClass::E, 4);
+        expect("E#4", NoteClass::F, 4);
+        expect("Dbb4", NoteClass::C, 4);
+        expect("F##4", NoteClass::G, 4);
+        expect("Fx4", NoteClass::G, 4);
+        expect("C\u{266F}4", NoteClass::Cs, 4);
+        expect("E\u{266D}4", NoteClass::Ds, 4);
+        expect("H4", NoteClass::B, 4);
+        expect("As4", NoteClass::As, 4);
+        expect("Es4", NoteClass::F, 4);
+        expect("Cs-1", NoteClass::Cs, -1);
+        assert!(parse_notepitch("Cb-1").is_err());
+        // Octaves are a single digit, so the `5` of a fifth chord is left over.
+        assert_eq!(Ok(("5", (NoteClass::C, Octave::from_raw(4).unwrap()))), parse_notepitch("C45"));
+    }
+
+    #[test]
+    fn test_parse_dynamic() {
+        assert_eq!(Ok(("", Dynamic::Mf)), parse_dynamic("mf"));
+        assert_eq!(Ok((" ->", Dynamic::Pp)), parse_dynamic("pp ->"));
+        assert_eq!(Ok((",", Dynamic::Fff)), parse_dynamic("fff,"));
+        assert!(parse_dynamic("piano").is_err());
+        assert!(parse_dynamic("f2").is_err());
+        assert!(parse_dynamic("ppx").is_err());
+    }
+
+    #[test]
+    fn test_parse_relative_octave() {
+        let expect = |raw: &str, octave: PressOctave, rest: &str| {
+            // Chord kinds are parsed with streaming parsers, so the input
+            // needs to go on past the press.
+            let line = format!("{}\n", raw);
+            let (left, (_, res, _)) = parse_fullchord(&line).unwrap();
+            assert_eq!(octave, res, "{:?}", raw);
+            assert_eq!(format!("{}\n", rest), left, "{:?}", raw);
+        };
+        expect("c", PressOctave::Relative(0), "");
+        expect("c''M", PressOctave::Relative(2), "");
+        expect("c,", PressOctave::Relative(-1), "");
+        expect("c, e", PressOctave::Relative(0), ", e");
+        expect("c,, e", PressOctave::Relative(-1), ", e");
+        expect("c, for 1b", PressOctave::Relative(-1), " for 1b");
+        expect("c4, e", PressOctave::Absolute(Octave::from_raw(4).unwrap()), ", e");
+    }
+}
@@ -88,10 +88,36 @@ pub fn parse_rawduration(input: &str) -> ParseResult<WaitTime> {
         // parse_notediv,
         parse_beats,
         parse_ticks,
-        parse_minutes,
-        parse_seconds,
+        // Units starting with m or s must come first, so "30ms" is not read
+        // as 30 minutes followed by an "s".
         parse_millis,
         parse_micros,
         parse_nanos,
+        parse_minutes,
+        parse_seconds,
     ))(input)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU16;
+
+    #[test]
+    fn test_parse_rawduration() {
+        let expect = |raw: &str, time: WaitTime| {
+            let (rest, res) = parse_rawduration(raw).unwrap();
+            assert!(rest.is_empty(), "{:?} left {:?}", raw, rest);
+            assert_eq!(time, res, "{:?}", raw);
+        };
+        expect("30ms", WaitTime::Clock(Duration::from_millis(30)));
+        expect("30 millis", WaitTime::Clock(Duration::from_millis(30)));
+        expect("2m", WaitTime::Clock(Duration::from_secs(120)));
+        expect("2 mins", WaitTime::Clock(Duration::from_secs(120)));
+        expect("5s", WaitTime::Clock(Duration::from_secs(5)));
+        expect("7us", WaitTime::Clock(Duration::from_micros(7)));
+        expect("9ns", WaitTime::Clock(Duration::from_nanos(9)));
+        expect("3b", WaitTime::Beats(NonZeroU16::new(3).unwrap()));
+        expect("4 ticks", WaitTime::Ticks(NonZeroU16::new(4).unwrap()));
+    }
+}
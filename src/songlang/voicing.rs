@@ -0,0 +1,273 @@
+use super::{PressModifier, StrumDirection};
+use crate::midi::MidiNote;
+use crate::model::Interval;
+use crate::track::WaitTime;
+
+use std::num::NonZeroU8;
+
+/// How far above or below the written octave voice leading may move a chord.
+const MAX_OCTAVE_SHIFT: i8 = 3;
+
+/// Settings for a `voicelead` block.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Default)]
+pub struct VoiceLeading {
+    /// The lowest and highest notes any chord tone may be moved to.
+    pub range: Option<(MidiNote, MidiNote)>,
+    /// Only use root position voicings, so the root is always in the bass.
+    pub root_in_bass: bool,
+}
+
+impl VoiceLeading {
+    fn in_range(&self, pitches: &[MidiNote]) -> bool {
+        match self.range {
+            Some((low, high)) => pitches.iter().all(|pitch| low <= *pitch && *pitch <= high),
+            None => true,
+        }
+    }
+}
+
+/// Voices a chord with the given tones in inversion `inversion`, IE the first
+/// inversion of a major triad is the third, the fifth, and the root an octave up.
+/// Tones too wide to be raised an octave are dropped.
+pub fn invert_chord(intervals: &[Interval], inversion: usize) -> Vec<Interval> {
+    if intervals.is_empty() {
+        return Vec::new();
+    }
+    let inversion = inversion % intervals.len();
+    let (lower, upper) = intervals.split_at(inversion);
+    upper
+        .iter()
+        .copied()
+        .chain(
+            lower
+                .iter()
+                .filter_map(|interval| interval.checked_add(Interval::OCTAVE)),
+        )
+        .collect()
+}
+
+/// The voicing modifiers set on a chord press.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Default)]
+pub struct Voicing {
+    pub inversion: Option<u8>,
+    pub drop2: bool,
+    pub open: bool,
+    pub spread: Option<NonZeroU8>,
+    pub strum: Option<(WaitTime, StrumDirection)>,
+}
+
+impl Voicing {
+    /// Collects the voicing modifiers in a list, with later modifiers
+    /// overriding earlier ones.
+    pub fn from_modifiers<'a>(modifiers: impl IntoIterator<Item = &'a PressModifier>) -> Self {
+        let mut retvl = Voicing::default();
+        for md in modifiers {
+            match md {
+                PressModifier::Inversion(inversion) => retvl.inversion = Some(*inversion),
+                PressModifier::Drop2 => retvl.drop2 = true,
+                PressModifier::Open => retvl.open = true,
+                PressModifier::Spread(octaves) => retvl.spread = Some(*octaves),
+                PressModifier::Strum { step, direction } => retvl.strum = Some((*step, *direction)),
+                _ => {}
+            }
+        }
+        retvl
+    }
+
+    /// Whether the modifiers choose which pitches the chord is played at,
+    /// rather than only when they start.
+    pub fn is_manual(&self) -> bool {
+        self.inversion.is_some() || self.drop2 || self.open || self.spread.is_some()
+    }
+
+    /// Voices a chord built on `root`, lowest note first. Notes that would
+    /// leave the MIDI range are dropped.
+    pub fn apply(&self, root: MidiNote, intervals: &[Interval]) -> Vec<MidiNote> {
+        let inverted = invert_chord(intervals, self.inversion.unwrap_or(0).into());
+        let mut pitches: Vec<MidiNote> = inverted
+            .iter()
+            .filter_map(|interval| interval.transpose_pitch(root))
+            .collect();
+        let len = pitches.len();
+        if self.drop2 && len >= 2 {
+            if let Some(dropped) = pitches[len - 2].checked_add(-12) {
+                pitches[len - 2] = dropped;
+            }
+            pitches.sort();
+        }
+        if self.open && len >= 3 {
+            if let Some(raised) = pitches[1].checked_add(12) {
+                pitches[1] = raised;
+            }
+            pitches.sort();
+        }
+        if let Some(octaves) = self.spread {
+            // Each note is raised by the share of the span it sits at, which keeps
+            // the lowest note in place and the whole chord within the span.
+            let octaves = usize::from(octaves.get());
+            pitches = pitches
+                .iter()
+                .enumerate()
+                .map(|(idx, pitch)| {
+                    let raise = (idx * octaves / len) * 12;
+                    pitch.checked_add(raise as i8).unwrap_or(*pitch)
+                })
+                .collect();
+        }
+        pitches
+    }
+}
+
+/// How far the voices have to move to get from one chord to the next, measured
+/// as the distance from each note to the nearest note of the other chord.
+fn movement(from: &[MidiNote], to: &[MidiNote]) -> u32 {
+    let nearest = |pitch: &MidiNote, others: &[MidiNote]| {
+        others
+            .iter()
+            .map(|other| (i16::from(pitch.as_u8()) - i16::from(other.as_u8())).unsigned_abs())
+            .min()
+            .map_or(0, u32::from)
+    };
+    let forwards: u32 = from.iter().map(|pitch| nearest(pitch, to)).sum();
+    let backwards: u32 = to.iter().map(|pitch| nearest(pitch, from)).sum();
+    forwards + backwards
+}
+
+/// Picks the inversion and octave of a chord built on `root` that moves the
+/// least from the `previous` chord, staying within the settings' range.
+///
+/// Without a previous chord, the voicing closest to the written one is
+/// used. If no voicing fits the range, the chord is played as written.
+pub fn voice_lead(
+    root: MidiNote,
+    intervals: &[Interval],
+    previous: Option<&[MidiNote]>,
+    settings: &VoiceLeading,
+) -> Vec<MidiNote> {
+    let written: Vec<MidiNote> = intervals
+        .iter()
+        .filter_map(|interval| interval.transpose_pitch(root))
+        .collect();
+    let inversions = if settings.root_in_bass {
+        1
+    } else {
+        intervals.len()
+    };
+    let mut best: Option<(u32, Vec<MidiNote>)> = None;
+    let shifts = std::iter::once(0).chain((1..=MAX_OCTAVE_SHIFT).flat_map(|n| vec![n, -n]));
+    for shift in shifts {
+        let shifted_root = match root.checked_add(shift * 12) {
+            Some(pitch) => pitch,
+            None => continue,
+        };
+        for inversion in 0..inversions {
+            let voiced = invert_chord(intervals, inversion);
+            let pitches: Option<Vec<MidiNote>> = voiced
+                .iter()
+                .map(|interval| interval.transpose_pitch(shifted_root))
+                .collect();
+            let pitches = match pitches {
+                Some(pitches) if settings.in_range(&pitches) => pitches,
+                _ => continue,
+            };
+            let cost = match previous {
+                Some(previous) => movement(previous, &pitches),
+                // Stay as close to where the chord was written as possible.
+                None => movement(&written, &pitches),
+            };
+            let is_better = match &best {
+                Some((best_cost, _)) => cost < *best_cost,
+                None => true,
+            };
+            if is_better {
+                best = Some((cost, pitches));
+            }
+        }
+    }
+    best.map_or(written, |(_, pitches)| pitches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::songlang::ChordKind;
+
+    #[test]
+    fn test_voicing() {
+        let note = |raw| MidiNote::from_raw(raw).unwrap();
+        let notes = |raw: &[u8]| raw.iter().map(|n| note(*n)).collect::<Vec<_>>();
+        let seventh = ChordKind::Major7.intervals();
+        let voiced = |voicing: Voicing| voicing.apply(note(60), seventh);
+        assert_eq!(notes(&[60, 64, 67, 71]), voiced(Voicing::default()));
+        let first = Voicing {
+            inversion: Some(1),
+            ..Voicing::default()
+        };
+        assert_eq!(notes(&[64, 67, 71, 72]), voiced(first));
+        let drop2 = Voicing {
+            drop2: true,
+            ..Voicing::default()
+        };
+        assert_eq!(notes(&[55, 60, 64, 71]), voiced(drop2));
+        let open = Voicing {
+            open: true,
+            ..Voicing::default()
+        };
+        assert_eq!(notes(&[60, 67, 71, 76]), voiced(open));
+        let spread = Voicing {
+            spread: NonZeroU8::new(2),
+            ..Voicing::default()
+        };
+        assert_eq!(notes(&[60, 64, 79, 83]), voiced(spread));
+    }
+
+    #[test]
+    fn test_voice_lead() {
+        let note = |raw| MidiNote::from_raw(raw).unwrap();
+        let settings = VoiceLeading::default();
+        let c_major = voice_lead(note(60), ChordKind::Major.intervals(), None, &settings);
+        assert_eq!(vec![note(60), note(64), note(67)], c_major);
+
+        // F major moves to its second inversion, keeping the C.
+        let f_major = voice_lead(
+            note(65),
+            ChordKind::Major.intervals(),
+            Some(&c_major),
+            &settings,
+        );
+        assert_eq!(vec![note(60), note(65), note(69)], f_major);
+
+        // GM7 written high comes down next to the F chord.
+        let g_seventh = voice_lead(
+            note(79),
+            ChordKind::Major7.intervals(),
+            Some(&f_major),
+            &settings,
+        );
+        assert_eq!(vec![note(59), note(62), note(66), note(67)], g_seventh);
+
+        let rooted = VoiceLeading {
+            root_in_bass: true,
+            ..settings
+        };
+        let f_rooted = voice_lead(
+            note(65),
+            ChordKind::Major.intervals(),
+            Some(&c_major),
+            &rooted,
+        );
+        assert_eq!(vec![note(65), note(69), note(72)], f_rooted);
+        assert_eq!(
+            vec![note(53), note(57), note(60)],
+            voice_lead(
+                note(65),
+                ChordKind::Major.intervals(),
+                Some(&c_major),
+                &VoiceLeading {
+                    range: Some((note(48), note(62))),
+                    root_in_bass: true,
+                }
+            )
+        );
+    }
+}
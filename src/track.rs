@@ -4,6 +4,27 @@ pub use cursor::*;
 mod multicursor;
 pub use multicursor::*;
 
+mod seek;
+pub use seek::*;
+
+mod clip;
+pub use clip::*;
+
+mod tempo;
+pub use tempo::*;
+
+mod analysis;
+pub use analysis::*;
+
+mod lint;
+pub use lint::*;
+
+mod optimize;
+pub use optimize::*;
+
+mod compiled;
+pub use compiled::*;
+
 mod instructions;
 pub use instructions::{BpmInfo, TrackEvent, WaitTime, OutputPort};
 
@@ -41,6 +62,12 @@ pub trait EventTrack {
             .collect()
     }
 
+    /// Gets the named sections and labels in this track.
+    /// Tracks that were not compiled from a song have none.
+    fn markers(&self) -> &[Marker] {
+        &[]
+    }
+
     /// Collects all "ports" that this track sends MIDI messages to. 
     /// These ports are separate from Midi "channels", and instead 
     /// map to framework-level objects, EG Jack's MIDI Output ports.
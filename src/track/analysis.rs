@@ -0,0 +1,246 @@
+use super::{EventTrack, TrackEvent, WaitTime};
+use std::time::Duration;
+
+/// Gets the instructions that the VM may move to after running `evt`
+/// at position `idx`.
+///
+/// Jumps with a `count` can both jump and fall through, so they have two
+/// successors; `End` has none.
+///
+/// A `Call` is treated as moving to both the subroutine and the instruction
+/// it eventually returns to, so a `Return` itself has no successors.
+pub fn successors(idx: usize, evt: TrackEvent) -> impl Iterator<Item = usize> {
+    let (first, second) = match evt {
+        TrackEvent::End | TrackEvent::Return => (None, None),
+        TrackEvent::Call { target } => (Some(target), Some(idx + 1)),
+        TrackEvent::Jump {
+            target,
+            count: None,
+        } => (Some(target), None),
+        TrackEvent::Jump {
+            target,
+            count: Some(_),
+        } => (Some(target), Some(idx + 1)),
+        TrackEvent::SendMessage { .. } | TrackEvent::SetBpm(_) | TrackEvent::Wait(_) => {
+            (Some(idx + 1), None)
+        }
+    };
+    first.into_iter().chain(second)
+}
+
+/// Checks whether running `evt` is guaranteed to move the VM's clock forward.
+pub fn advances_time(evt: TrackEvent) -> bool {
+    match evt {
+        TrackEvent::Wait(WaitTime::Clock(dur)) => dur > Duration::from_nanos(0),
+        TrackEvent::Wait(WaitTime::Beats(_)) | TrackEvent::Wait(WaitTime::Ticks(_)) => true,
+        _ => false,
+    }
+}
+
+/// Finds which instructions in the track can ever be run, assuming that
+/// every counted jump can both jump and fall through.
+pub fn reachable(track: &impl EventTrack) -> Vec<bool> {
+    let len = track.len();
+    let mut retvl = vec![false; len];
+    let mut stack = vec![0];
+    while let Some(cur) = stack.pop() {
+        if cur >= len || retvl[cur] {
+            continue;
+        }
+        retvl[cur] = true;
+        if let Some(evt) = track.get(cur) {
+            stack.extend(successors(cur, evt));
+        }
+    }
+    retvl
+}
+
+/// A loop in a track that can run forever without the VM clock ever moving forward.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ZeroTimeLoop {
+    /// The address of the `TrackEvent::Jump` that closes the loop.
+    pub jump: usize,
+    /// The address the jump goes back to.
+    pub target: usize,
+}
+
+/// Finds all unbounded loops in the track that never wait.
+///
+/// A loop is only reported if it is closed by a `TrackEvent::Jump` without a
+/// `count`; loops made up solely of counted jumps always fall through eventually.
+pub fn zero_time_loops(track: &impl EventTrack) -> Vec<ZeroTimeLoop> {
+    let len = track.len();
+    let mut retvl = Vec::new();
+    let mut visited = vec![false; len];
+    let mut stack = Vec::new();
+    for jump in 0..len {
+        let target = match track.get(jump) {
+            Some(TrackEvent::Jump {
+                target,
+                count: None,
+            }) if target < len => target,
+            _ => continue,
+        };
+        visited.iter_mut().for_each(|flag| *flag = false);
+        stack.clear();
+        stack.push(target);
+        while let Some(cur) = stack.pop() {
+            if cur >= len || visited[cur] {
+                continue;
+            }
+            visited[cur] = true;
+            if cur == jump {
+                break;
+            }
+            let evt = match track.get(cur) {
+                Some(evt) => evt,
+                None => continue,
+            };
+            match evt {
+                _ if advances_time(evt) => {}
+                TrackEvent::Call { target } => {
+                    stack.push(target);
+                    if !always_waits(track, target, &mut Vec::new()) {
+                        stack.push(cur + 1);
+                    }
+                }
+                _ => stack.extend(successors(cur, evt)),
+            }
+        }
+        if visited[jump] {
+            retvl.push(ZeroTimeLoop { jump, target });
+        }
+    }
+    retvl
+}
+
+/// Checks whether every path from the subroutine starting at `start` to
+/// a `Return` moves the VM clock forward.
+///
+/// Recursive calls are assumed to wait, since they will overflow the
+/// call stack before they can spin forever.
+fn always_waits(track: &impl EventTrack, start: usize, calls: &mut Vec<usize>) -> bool {
+    if calls.contains(&start) {
+        return true;
+    }
+    calls.push(start);
+    let mut visited = vec![false; track.len()];
+    let mut stack = vec![start];
+    let mut retvl = true;
+    while let Some(cur) = stack.pop() {
+        if cur >= visited.len() || visited[cur] {
+            continue;
+        }
+        visited[cur] = true;
+        match track.get(cur) {
+            Some(TrackEvent::Return) => {
+                retvl = false;
+                break;
+            }
+            Some(TrackEvent::Call { target }) => {
+                stack.push(target);
+                if !always_waits(track, target, calls) {
+                    stack.push(cur + 1);
+                }
+            }
+            Some(evt) if !advances_time(evt) => stack.extend(successors(cur, evt)),
+            _ => {}
+        }
+    }
+    calls.pop();
+    retvl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU16;
+
+    #[test]
+    fn test_zero_time_loops() {
+        let wait = TrackEvent::Wait(WaitTime::Ticks(NonZeroU16::new(4).unwrap()));
+        let bpm = TrackEvent::SetBpm(Default::default());
+
+        let spinning = vec![
+            bpm,
+            TrackEvent::Jump {
+                target: 0,
+                count: None,
+            },
+            TrackEvent::End,
+        ];
+        assert_eq!(
+            vec![ZeroTimeLoop { jump: 1, target: 0 }],
+            zero_time_loops(&spinning)
+        );
+
+        let waiting = vec![
+            bpm,
+            wait,
+            TrackEvent::Jump {
+                target: 0,
+                count: None,
+            },
+            TrackEvent::End,
+        ];
+        assert!(zero_time_loops(&waiting).is_empty());
+
+        // The inner counted loop never waits, but always falls through to the
+        // outer loop's wait.
+        let counted = vec![
+            bpm,
+            TrackEvent::Jump {
+                target: 0,
+                count: NonZeroU16::new(3),
+            },
+            wait,
+            TrackEvent::Jump {
+                target: 0,
+                count: None,
+            },
+            TrackEvent::End,
+        ];
+        assert!(zero_time_loops(&counted).is_empty());
+
+        // Skipping over the wait makes the outer loop spin.
+        let skipped = vec![
+            TrackEvent::Jump {
+                target: 2,
+                count: None,
+            },
+            wait,
+            bpm,
+            TrackEvent::Jump {
+                target: 0,
+                count: None,
+            },
+            TrackEvent::End,
+        ];
+        assert_eq!(
+            vec![
+                ZeroTimeLoop { jump: 0, target: 2 },
+                ZeroTimeLoop { jump: 3, target: 0 }
+            ],
+            zero_time_loops(&skipped)
+        );
+
+        // Calling a subroutine that waits counts as waiting.
+        let subroutine = |body| {
+            vec![
+                TrackEvent::Call { target: 3 },
+                TrackEvent::Jump {
+                    target: 0,
+                    count: None,
+                },
+                TrackEvent::End,
+                body,
+                TrackEvent::Return,
+            ]
+        };
+        assert!(zero_time_loops(&subroutine(wait)).is_empty());
+        assert_eq!(
+            vec![ZeroTimeLoop { jump: 1, target: 0 }],
+            zero_time_loops(&subroutine(bpm))
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use std::num::NonZeroU16;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+use thiserror::*;
+
+/// Where a track of a `VecMultiCursor` is at when it is played as a clip.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ClipState {
+    Stopped,
+    /// The clip starts from the top at this playback time.
+    Queued(Duration),
+    Playing,
+    /// The clip stops at this playback time.
+    Stopping(Duration),
+}
+
+/// The grid clips are launched and stopped on.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Quantize {
+    /// The start of the next beat.
+    Beat,
+    /// The start of the next group of this many bars, counted from the
+    /// start of playback.
+    Bars(NonZeroU16),
+}
+
+impl Default for Quantize {
+    fn default() -> Self {
+        Quantize::Bars(NonZeroU16::new(1).unwrap())
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+#[error("Invalid quantization {0:?}: expected `beat`, `bar` or a number of bars.")]
+pub struct QuantizeParseError(String);
+
+impl FromStr for Quantize {
+    type Err = QuantizeParseError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let trimmed = raw.trim();
+        if trimmed.eq_ignore_ascii_case("beat") {
+            Ok(Quantize::Beat)
+        } else if trimmed.eq_ignore_ascii_case("bar") {
+            Ok(Quantize::default())
+        } else {
+            NonZeroU16::from_str(trimmed)
+                .map(Quantize::Bars)
+                .map_err(|_| QuantizeParseError(trimmed.to_owned()))
+        }
+    }
+}
+
+/// A request to change the `ClipState` of a track.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ClipCommand {
+    Launch(Quantize),
+    Stop(Quantize),
+}
+
+/// Clip commands and the loop setting of a track, which can be changed from
+/// one thread while another plays the track, IE from the command line while
+/// the realtime thread plays.
+///
+/// Only the most recent command is kept.
+#[derive(Debug, Default)]
+pub struct ClipControl {
+    /// The kind of command in the top 2 bits, with 0 meaning "no command",
+    /// and its `Quantize` in the rest: 0 for a beat, or a number of bars.
+    request: AtomicU32,
+    looping: AtomicBool,
+}
+
+const KIND_SHIFT: u32 = 30;
+const VALUE_MASK: u32 = (1 << KIND_SHIFT) - 1;
+
+impl ClipControl {
+    /// Requests that the clip start on the next `quantize` boundary,
+    /// replacing any command that was not taken yet.
+    pub fn launch(&self, quantize: Quantize) {
+        self.store(1, quantize);
+    }
+
+    /// Requests that the clip stop on the next `quantize` boundary,
+    /// replacing any command that was not taken yet.
+    pub fn stop(&self, quantize: Quantize) {
+        self.store(2, quantize);
+    }
+
+    fn store(&self, kind: u32, quantize: Quantize) {
+        let value = match quantize {
+            Quantize::Beat => 0,
+            Quantize::Bars(bars) => u32::from(bars.get()),
+        };
+        self.request.store(kind << KIND_SHIFT | value, Ordering::Release);
+    }
+
+    /// Takes the pending command, if any.
+    pub fn take(&self) -> Option<ClipCommand> {
+        let raw = self.request.swap(0, Ordering::AcqRel);
+        let quantize = match NonZeroU16::new((raw & VALUE_MASK) as u16) {
+            Some(bars) => Quantize::Bars(bars),
+            None => Quantize::Beat,
+        };
+        match raw >> KIND_SHIFT {
+            1 => Some(ClipCommand::Launch(quantize)),
+            2 => Some(ClipCommand::Stop(quantize)),
+            _ => None,
+        }
+    }
+
+    /// Flips whether the clip starts over when it reaches its end,
+    /// returning the new value.
+    pub fn toggle_loop(&self) -> bool {
+        !self.looping.fetch_xor(true, Ordering::AcqRel)
+    }
+
+    pub fn is_looping(&self) -> bool {
+        self.looping.load(Ordering::Acquire)
+    }
+}
@@ -0,0 +1,57 @@
+use super::{EventTrack, TrackEvent};
+
+/// What a `Marker` in a track refers to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum MarkerKind {
+    /// A named song section, compiled as a subroutine.
+    Section,
+    /// A jump label.
+    Label,
+}
+
+/// A named span of instructions in a compiled track.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Marker {
+    pub name: String,
+    pub kind: MarkerKind,
+    /// The first instruction in the span.
+    pub start: usize,
+    /// The instruction just past the end of the span.
+    /// Labels mark a single position, so for them this is equal to `start`.
+    pub end: usize,
+}
+
+impl Marker {
+    pub fn contains(&self, idx: usize) -> bool {
+        self.start <= idx && idx < self.end
+    }
+}
+
+/// The output of the compiler: a list of instructions plus the names
+/// of the sections and labels they were compiled from.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Default)]
+pub struct CompiledTrack {
+    pub events: Vec<TrackEvent>,
+    pub markers: Vec<Marker>,
+}
+
+impl From<Vec<TrackEvent>> for CompiledTrack {
+    fn from(events: Vec<TrackEvent>) -> Self {
+        CompiledTrack {
+            events,
+            markers: Vec::new(),
+        }
+    }
+}
+
+impl EventTrack for CompiledTrack {
+    fn get(&self, instruction_idx: usize) -> Option<TrackEvent> {
+        self.events.as_slice().get(instruction_idx).copied()
+    }
+    fn len(&self) -> usize {
+        self.events.len()
+    }
+    fn markers(&self) -> &[Marker] {
+        &self.markers
+    }
+}
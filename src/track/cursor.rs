@@ -1,8 +1,22 @@
-use super::{BpmInfo, EventTrack, OutputPort, TrackEvent};
+use super::{
+    beat_divisions, BpmInfo, ChaseState, EventTrack, Marker, MarkerKind, OutputPort,
+    SeekPosition, TempoMap, TrackEvent, WaitTime,
+};
 use crate::midi::MidiMessage;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::num::NonZeroU16;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
+use thiserror::*;
+
+/// The default maximum number of instructions a single call to
+/// `TrackCursor::step_until` may run before the cursor gives up.
+pub const DEFAULT_INSTRUCTION_BUDGET: usize = 1 << 16;
+
+/// The maximum number of nested `TrackEvent::Call`s a `TrackCursor` supports.
+pub const MAX_CALL_DEPTH: usize = 32;
 
 /// A cursor along an `EventTrack`.
 /// Allows for stepping through the track and acts as a sort of
@@ -12,14 +26,66 @@ pub struct TrackCursor<TrackData: EventTrack> {
     instruction_pointer: usize,
     cur_bpm: BpmInfo,
     cur_time: Duration,
-    cur_ticks : u16,  
+    cur_ticks: u64,
+    /// The bar the cursor is in, counted from 0.
+    cur_bar: u64,
+    /// The tick the current bar started on.
+    bar_start: u64,
+    /// The clock time and tick the current tempo segment started on.
+    /// Times within a segment are derived from its tick count, so
+    /// rounding errors never build up from one wait to the next.
+    segment_time: Duration,
+    segment_ticks: u64,
+    /// How far the clock waits so far have run into a tick that has not
+    /// been counted yet.
+    clock_remainder: Duration,
+    /// The beat the current segment started on, in `BEAT_DIVISIONS`ths of
+    /// a beat. Only used when following a master tempo.
+    segment_beat: u128,
+    /// The tempo this cursor follows instead of its own, if any.
+    master_tempo: Option<Arc<TempoMap>>,
     jump_counts: JumpCounts,
+    call_stack: [usize; MAX_CALL_DEPTH],
+    call_depth: usize,
+    instruction_budget: usize,
+    fault: Option<StepError>,
+    /// Messages chased by the last `seek()` that have not been emitted yet,
+    /// in reverse order.
+    chase: Vec<(OutputPort, MidiMessage)>,
+    chase_time: Duration,
     data: TrackData,
 }
 
+/// A position in a track in musical terms, IE bar 12, beat 3, tick 5
+/// of the chorus.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct MusicalPosition<'a> {
+    /// The bar, counted from 1.
+    pub bar: u64,
+    /// The beat within the bar, counted from 1.
+    pub beat: u64,
+    /// The tick within the beat, counted from 0.
+    pub tick: u64,
+    /// The name of the innermost section being played, if any.
+    pub section: Option<&'a str>,
+    /// The name of the label most recently passed, if any.
+    pub label: Option<&'a str>,
+}
+
+impl fmt::Display for MusicalPosition<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{:02}", self.bar, self.beat, self.tick)?;
+        match (self.section, self.label) {
+            (Some(section), _) => write!(f, " in section {}", section),
+            (None, Some(label)) => write!(f, " after label {}", label),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
 /// Possible signal values that can be returned from `step()`.
 #[derive(Debug, Eq, PartialEq, Hash)]
-enum StepOutput {
+pub(super) enum StepOutput {
     /// Playback has ended; all subsequent calls to `step()`
     /// will always return `Ok(StepOutput::end)`.
     End,
@@ -36,11 +102,73 @@ enum StepOutput {
 }
 
 /// Errors that may occur when calling `step()`.
-#[derive(Debug)]
-enum StepError {
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+pub enum StepError {
+    #[error("Jump target {target} is out of bounds.")]
     BadJumpTarget { target: usize },
+    #[error("Jump counter for the instruction at {target} was not found.")]
     JumpIdxNotFound { target: usize },
+    #[error("Instruction pointer {0} is out of bounds.")]
     BadInstrPointer(usize),
+    #[error("Ran {budget} instructions without reaching the requested time; stopped at instruction {instruction_pointer}.")]
+    BudgetExceeded {
+        budget: usize,
+        instruction_pointer: usize,
+    },
+    #[error("Call at instruction {0} exceeded the maximum call depth of {}.", MAX_CALL_DEPTH)]
+    CallStackOverflow(usize),
+    #[error("Return at instruction {0} was reached outside of a subroutine.")]
+    CallStackUnderflow(usize),
+}
+
+/// A `StepError` that can be handed from the realtime thread to the rest of
+/// the program without locking or allocating.
+///
+/// Only the most recent error is kept.
+#[derive(Debug, Default)]
+pub struct FaultSlot {
+    /// The kind of error, with 0 meaning "no error", stored after its values.
+    kind: AtomicUsize,
+    values: [AtomicUsize; 2],
+}
+
+impl FaultSlot {
+    /// Publishes `fault`, replacing any error that was not taken yet.
+    pub fn publish(&self, fault: &StepError) {
+        let (kind, first, second) = match *fault {
+            StepError::BadJumpTarget { target } => (1, target, 0),
+            StepError::JumpIdxNotFound { target } => (2, target, 0),
+            StepError::BadInstrPointer(pointer) => (3, pointer, 0),
+            StepError::BudgetExceeded {
+                budget,
+                instruction_pointer,
+            } => (4, budget, instruction_pointer),
+            StepError::CallStackOverflow(pointer) => (5, pointer, 0),
+            StepError::CallStackUnderflow(pointer) => (6, pointer, 0),
+        };
+        self.values[0].store(first, Ordering::Relaxed);
+        self.values[1].store(second, Ordering::Relaxed);
+        self.kind.store(kind, Ordering::Release);
+    }
+
+    /// Takes the published error, if any.
+    pub fn take(&self) -> Option<StepError> {
+        let kind = self.kind.swap(0, Ordering::AcqRel);
+        let first = self.values[0].load(Ordering::Relaxed);
+        let second = self.values[1].load(Ordering::Relaxed);
+        match kind {
+            1 => Some(StepError::BadJumpTarget { target: first }),
+            2 => Some(StepError::JumpIdxNotFound { target: first }),
+            3 => Some(StepError::BadInstrPointer(first)),
+            4 => Some(StepError::BudgetExceeded {
+                budget: first,
+                instruction_pointer: second,
+            }),
+            5 => Some(StepError::CallStackOverflow(first)),
+            6 => Some(StepError::CallStackUnderflow(first)),
+            _ => None,
+        }
+    }
 }
 
 impl<T: EventTrack> TrackCursor<T> {
@@ -49,24 +177,91 @@ impl<T: EventTrack> TrackCursor<T> {
             instruction_pointer: 0,
             cur_bpm: BpmInfo::default(),
             cur_time: Duration::from_nanos(0),
-            cur_ticks : 0,  
+            cur_ticks: 0,
+            cur_bar: 0,
+            bar_start: 0,
+            segment_time: Duration::from_nanos(0),
+            segment_ticks: 0,
+            clock_remainder: Duration::from_nanos(0),
+            segment_beat: 0,
+            master_tempo: None,
             jump_counts: JumpCounts::from_iter(data.len(), data.finite_jumps()),
+            call_stack: [0; MAX_CALL_DEPTH],
+            call_depth: 0,
+            instruction_budget: DEFAULT_INSTRUCTION_BUDGET,
+            fault: None,
+            chase: Vec::new(),
+            chase_time: Duration::from_nanos(0),
             data,
         }
     }
 
-    /// Gets the current instruction pointer.
+    /// Sets the maximum number of instructions a single call to `step_until()`
+    /// may run. If the budget runs out the cursor stops playback and
+    /// records a `StepError::BudgetExceeded` instead of spinning forever.
     #[allow(dead_code)]
+    pub fn with_instruction_budget(mut self, budget: usize) -> Self {
+        self.instruction_budget = budget;
+        self
+    }
+
+    /// Makes the cursor take its tempo from `master`, the tempo map of
+    /// another track, or from its own SET BPM commands again if `None`,
+    /// and resets it.
+    ///
+    /// When following a master tempo, the cursor's beats line up with the
+    /// master track's beats. Its own SET BPM commands still set the tick
+    /// grid and time signature, but their beats per minute are ignored.
+    /// Clock waits last as long as they say, moving the cursor off the
+    /// master's beats by that much.
+    pub fn follow_tempo(&mut self, master: Option<Arc<TempoMap>>) {
+        self.master_tempo = master;
+        self.reset();
+    }
+
+    /// Gets the error that stopped this cursor, if any.
+    /// A faulted cursor emits no more events until it is `reset()`.
+    pub fn fault(&self) -> Option<&StepError> {
+        self.fault.as_ref()
+    }
+
+    /// Checks whether the cursor has reached the end of its track.
+    pub fn ended(&self) -> bool {
+        self.data.get(self.instruction_pointer) == Some(TrackEvent::End)
+    }
+
+    /// Gets the current instruction pointer.
     pub fn pc(&self) -> usize {
         self.instruction_pointer
     }
 
+    /// Gets the track this cursor plays.
+    pub fn data(&self) -> &T {
+        &self.data
+    }
+
     /// Gets the current BPM value.
-    #[allow(dead_code)]
     pub fn bpm(&self) -> BpmInfo {
         self.cur_bpm
     }
 
+    /// Gets the innermost song section the cursor is currently playing,
+    /// including sections that are only on the call stack.
+    pub fn section(&self) -> Option<&Marker> {
+        let return_addrs = self.call_stack[..self.call_depth]
+            .iter()
+            .rev()
+            .map(|addr| addr - 1);
+        std::iter::once(self.instruction_pointer)
+            .chain(return_addrs)
+            .find_map(|idx| {
+                self.data
+                    .markers()
+                    .iter()
+                    .find(|marker| marker.kind == MarkerKind::Section && marker.contains(idx))
+            })
+    }
+
     /// Gets the current value of the cursor's interal track clock.
     pub fn cur_clock(&self) -> Duration {
         self.cur_time
@@ -75,52 +270,277 @@ impl<T: EventTrack> TrackCursor<T> {
     /// Gets the number of beat "ticks" that have occured in the track.
     /// Note that this is NOT a true measure of time, since the length 
     /// of a single tick can change between SET BPM commands. 
-    #[allow(dead_code)]
-    pub fn cur_ticks(&self) -> u16 {
+    pub fn cur_ticks(&self) -> u64 {
         self.cur_ticks
     }
 
+    /// Gets the bar the cursor is in, counted from 0, and the tick it started on.
+    pub(super) fn bar(&self) -> (u64, u64) {
+        (self.cur_bar, self.bar_start)
+    }
+
+    /// Gets the addresses on the call stack, innermost last.
+    pub(super) fn return_addrs(&self) -> &[usize] {
+        &self.call_stack[..self.call_depth]
+    }
+
+    /// Gets the remaining count of every counted jump, by address.
+    pub(super) fn jump_counts(&self) -> BTreeMap<usize, u16> {
+        self.jump_counts.data.iter().map(|(idx, count)| (*idx, *count)).collect()
+    }
+
+    /// Gets how far clock waits have run into a tick that has not been counted yet.
+    pub(super) fn clock_remainder(&self) -> Duration {
+        self.clock_remainder
+    }
+
+    /// Gets the label the cursor most recently passed in the instruction list.
+    pub fn label(&self) -> Option<&Marker> {
+        self.data
+            .markers()
+            .iter()
+            .filter(|marker| marker.kind == MarkerKind::Label)
+            .filter(|marker| marker.start <= self.instruction_pointer)
+            .max_by_key(|marker| marker.start)
+    }
+
+    /// Gets the cursor's position in musical terms, following the time
+    /// signature set by each SET BPM command along the way.
+    pub fn position(&self) -> MusicalPosition<'_> {
+        let into_bar = self.cur_ticks - self.bar_start;
+        let ticks_per_beat = u64::from(self.cur_bpm.ticks_per_beat.get());
+        MusicalPosition {
+            bar: self.cur_bar + 1,
+            beat: into_bar / ticks_per_beat + 1,
+            tick: into_bar % ticks_per_beat,
+            section: self.section().map(|marker| marker.name.as_str()),
+            label: self.label().map(|marker| marker.name.as_str()),
+        }
+    }
+
     /// Moves the cursor forwards in time, emitting MIDI messages
     /// encountered along the way.
     ///
     /// Events will be returned with the timestamp
     /// of the event measured since the start of track playback, NOT
     /// from the previous value of the cursor's internal clock. 
+    ///
+    /// After a `seek()`, the messages that recreate the state at the seek
+    /// position are emitted first.
+    ///
+    /// If an instruction fails, or the call runs more instructions than
+    /// the cursor's instruction budget allows, the cursor stops and
+    /// the error is available via `fault()`.
     pub fn step_until<'a>(
         &'a mut self,
         end: Duration,
     ) -> impl Iterator<Item = (Duration, OutputPort, MidiMessage)> + 'a {
-        std::iter::from_fn(move || loop {
-            if self.cur_time > end {
+        let mut remaining = self.instruction_budget;
+        std::iter::from_fn(move || self.next_until(end, &mut remaining))
+    }
+
+    /// Gets the maximum number of instructions a single call to `step_until()` may run.
+    pub fn instruction_budget(&self) -> usize {
+        self.instruction_budget
+    }
+
+    /// Runs the cursor up to its next event at or before `end`, taking the
+    /// instructions it runs out of `remaining`.
+    ///
+    /// This is a single step of `step_until()`, for callers that need to
+    /// interleave several cursors while keeping one budget per cursor.
+    pub fn next_until(
+        &mut self,
+        end: Duration,
+        remaining: &mut usize,
+    ) -> Option<(Duration, OutputPort, MidiMessage)> {
+        loop {
+            if self.chase_time <= end {
+                if let Some((port, msg)) = self.chase.pop() {
+                    return Some((self.chase_time, port, msg));
+                }
+            }
+            if self.cur_time > end || self.fault.is_some() {
+                return None;
+            }
+            if *remaining == 0 {
+                self.fault = Some(StepError::BudgetExceeded {
+                    budget: self.instruction_budget,
+                    instruction_pointer: self.instruction_pointer,
+                });
                 return None;
             }
-            match self.step().unwrap() {
-                StepOutput::End => {
+            *remaining -= 1;
+            match self.step() {
+                Ok(StepOutput::End) => {
                     return None;
                 }
-                StepOutput::Message { time, port, msg } => {
+                Ok(StepOutput::Message { time, port, msg }) => {
                     return Some((time, port, msg));
                 }
-                StepOutput::Continue => {}
+                Ok(StepOutput::Continue) => {}
+                Err(e) => {
+                    self.fault = Some(e);
+                    return None;
+                }
             }
-        })
+        }
     }
 
     /// Resets the cursor back to the beginning of the track.
     /// This includes resetting the instruction pointer, tick counter, 
-    /// internal clock, and all jump index values back to zero, as well
-    /// as resetting the BPM value back to default.
+    /// internal clock, call stack, and all jump index values back to zero, as well
+    /// as resetting the BPM value back to default and clearing any fault.
     pub fn reset(&mut self) {
         self.instruction_pointer = 0;
+        self.call_depth = 0;
         self.cur_bpm = BpmInfo::default();
         self.cur_time = Duration::from_nanos(0);
         self.cur_ticks = 0;
+        self.cur_bar = 0;
+        self.bar_start = 0;
+        self.segment_time = Duration::from_nanos(0);
+        self.segment_ticks = 0;
+        self.clock_remainder = Duration::from_nanos(0);
+        self.segment_beat = 0;
+        self.fault = None;
+        self.chase.clear();
+        self.chase_time = Duration::from_nanos(0);
         self.jump_counts.reset(&self.data).unwrap();
     }
 
+    /// Resets the cursor, as with `reset()`, to start on beat `beat` of the
+    /// master tempo it follows, given in `BEAT_DIVISIONS`ths of a beat.
+    /// Its clock still starts from 0. Cursors that follow their own tempo
+    /// are simply reset.
+    pub fn reset_to_beat(&mut self, beat: u128) {
+        self.reset();
+        self.segment_beat = beat;
+    }
+
+    /// Moves the cursor to `position` without emitting the messages along
+    /// the way, and returns the clock time of that position.
+    ///
+    /// The VM state (instruction pointer, BPM, jump counters and call stack)
+    /// is rebuilt by running the track from the start. The notes that
+    /// would be sounding at `position`, along with the latest controller,
+    /// program, pressure and pitch bend values, are "chased": the next
+    /// `step_until()` emits them first, timestamped with the returned time.
+    ///
+    /// If `position` falls in the middle of a wait, the cursor's clock ends
+    /// up at the end of that wait, past the returned time. If the track ends
+    /// or faults first, the cursor stays there and the time it stopped at
+    /// is returned. Like `step_until()`, the cursor faults if it runs more
+    /// instructions than its budget allows without its clock moving.
+    pub fn seek(&mut self, position: SeekPosition) -> Duration {
+        self.reset();
+        let mut chase = ChaseState::default();
+        let mut remaining = self.instruction_budget;
+        let seek_time = loop {
+            let reached = match position {
+                SeekPosition::Time(time) => self.cur_time >= time,
+                _ => self
+                    .seek_target_ticks(position)
+                    .is_some_and(|ticks| self.cur_ticks >= ticks),
+            };
+            if reached || self.fault.is_some() {
+                break self.cur_time;
+            }
+            if remaining == 0 {
+                self.fault = Some(StepError::BudgetExceeded {
+                    budget: self.instruction_budget,
+                    instruction_pointer: self.instruction_pointer,
+                });
+                break self.cur_time;
+            }
+            remaining -= 1;
+            let (prev_time, prev_ticks) = (self.cur_time, self.cur_ticks);
+            match self.step() {
+                Ok(StepOutput::End) => break self.cur_time,
+                Ok(StepOutput::Message { port, msg, .. }) => chase.record(port, msg),
+                Ok(StepOutput::Continue) => {}
+                Err(e) => {
+                    self.fault = Some(e);
+                    break self.cur_time;
+                }
+            }
+            if self.cur_time == prev_time {
+                continue;
+            }
+            remaining = self.instruction_budget;
+
+            // The wait that was just run jumped over the position.
+            match (position, self.seek_target_ticks(position)) {
+                (SeekPosition::Time(time), _) if self.cur_time > time => break time,
+                (_, Some(ticks)) if self.cur_ticks > ticks => {
+                    // Clock waits start a new segment, so they are measured
+                    // from the start of the wait instead.
+                    let time = if ticks >= self.segment_ticks {
+                        self.segment_time_at(ticks)
+                    } else {
+                        prev_time + self.cur_bpm.ticks_duration(ticks - prev_ticks)
+                    };
+                    break time.min(self.cur_time);
+                }
+                _ => {}
+            }
+        };
+        self.chase = chase.into_messages();
+        self.chase.reverse();
+        self.chase_time = seek_time;
+        seek_time
+    }
+
+    /// Gets the messages chased by the last `seek()` that have not been
+    /// emitted yet, in the order they will be emitted.
+    pub fn chased(&self) -> impl Iterator<Item = (OutputPort, MidiMessage)> + '_ {
+        self.chase.iter().rev().copied()
+    }
+
+    /// Queues messages to be emitted before the state chased by the last
+    /// `seek()`, at the same time, IE to release notes left sounding by
+    /// another cursor this one takes over from.
+    pub fn chase_first(&mut self, messages: &[(OutputPort, MidiMessage)]) {
+        self.chase.extend(messages.iter().rev().copied());
+    }
+
+    /// Gets the tick a tick or bar `position` falls on, as seen from the
+    /// current bar. Bar positions assume the time signature stays the same
+    /// from here on, which holds for the length of any single wait.
+    fn seek_target_ticks(&self, position: SeekPosition) -> Option<u64> {
+        match position {
+            SeekPosition::Time(_) => None,
+            SeekPosition::Ticks(ticks) => Some(ticks),
+            SeekPosition::Bar(target) => {
+                let bars_left = u64::from(target.saturating_sub(1)).saturating_sub(self.cur_bar);
+                Some(self.bar_start + bars_left * self.cur_bpm.ticks_per_bar())
+            }
+        }
+    }
+
+    /// Gets the clock time of a tick in the current tempo segment.
+    fn segment_time_at(&self, ticks: u64) -> Duration {
+        let into_segment = ticks - self.segment_ticks;
+        match &self.master_tempo {
+            None => self.segment_time + self.cur_bpm.ticks_duration(into_segment),
+            Some(master) => {
+                let start = master.time_at_beat(self.segment_beat);
+                let beat = self.segment_beat + beat_divisions(into_segment, self.cur_bpm);
+                self.segment_time + master.time_at_beat(beat).saturating_sub(start)
+            }
+        }
+    }
+
+    /// Starts a new tempo segment at the current time and tick.
+    fn start_segment(&mut self) {
+        self.segment_beat += beat_divisions(self.cur_ticks - self.segment_ticks, self.cur_bpm);
+        self.segment_time = self.cur_time;
+        self.segment_ticks = self.cur_ticks;
+    }
+
     /// Runs the instruction at the current instruction pointer
     /// and progresses the cursor state forward.
-    fn step(&mut self) -> Result<StepOutput, StepError> {
+    pub(super) fn step(&mut self) -> Result<StepOutput, StepError> {
         let next_evt = self
             .data
             .get(self.instruction_pointer)
@@ -131,7 +551,12 @@ impl<T: EventTrack> TrackCursor<T> {
             // `StepOutput::End`.
             TrackEvent::End => Ok(StepOutput::End),
             TrackEvent::SetBpm(new_info) => {
-                self.cur_bpm = new_info;
+                // Setting the BPM it already has leaves the segment alone,
+                // so that dropping the instruction changes nothing.
+                if new_info != self.cur_bpm {
+                    self.start_segment();
+                    self.cur_bpm = new_info;
+                }
                 self.instruction_pointer += 1;
                 Ok(StepOutput::Continue)
             }
@@ -145,8 +570,22 @@ impl<T: EventTrack> TrackCursor<T> {
             }
             TrackEvent::Wait(time) => {
                 self.instruction_pointer += 1;
-                self.cur_time += time.as_duration(self.cur_bpm);
-                self.cur_ticks += time.as_ticks(self.cur_bpm).get();
+                if let WaitTime::Clock(dur) = time {
+                    // Clock waits rarely end on a tick, so the part of a tick
+                    // they run into is carried over to the next one.
+                    let (ticks, remainder) = clock_ticks(self.clock_remainder + dur, self.cur_bpm);
+                    self.cur_ticks += ticks;
+                    self.clock_remainder = remainder;
+                    self.cur_time += dur;
+                    self.start_segment();
+                } else {
+                    self.cur_ticks += time.as_ticks(self.cur_bpm);
+                    self.cur_time = self.segment_time_at(self.cur_ticks);
+                }
+                let ticks_per_bar = self.cur_bpm.ticks_per_bar();
+                let bars = (self.cur_ticks - self.bar_start) / ticks_per_bar;
+                self.cur_bar += bars;
+                self.bar_start += bars * ticks_per_bar;
                 Ok(StepOutput::Continue)
             }
             TrackEvent::Jump { target, count } => {
@@ -156,10 +595,38 @@ impl<T: EventTrack> TrackCursor<T> {
                 self.instruction_pointer = new_pc;
                 Ok(StepOutput::Continue)
             }
+            TrackEvent::Call { target } => {
+                if target > self.jump_counts.max_target {
+                    return Err(StepError::BadJumpTarget { target });
+                }
+                let slot = self
+                    .call_stack
+                    .get_mut(self.call_depth)
+                    .ok_or(StepError::CallStackOverflow(self.instruction_pointer))?;
+                *slot = self.instruction_pointer + 1;
+                self.call_depth += 1;
+                self.instruction_pointer = target;
+                Ok(StepOutput::Continue)
+            }
+            TrackEvent::Return => {
+                self.call_depth = self
+                    .call_depth
+                    .checked_sub(1)
+                    .ok_or(StepError::CallStackUnderflow(self.instruction_pointer))?;
+                self.instruction_pointer = self.call_stack[self.call_depth];
+                Ok(StepOutput::Continue)
+            }
         }
     }
 }
 
+/// Splits the time clock waits have run for into the whole ticks it lasts
+/// and the part of a tick left over.
+pub fn clock_ticks(time: Duration, bpm: BpmInfo) -> (u64, Duration) {
+    let ticks = bpm.duration_ticks(time);
+    (ticks, time - bpm.ticks_duration(ticks))
+}
+
 /// State information about jumps with finite counters.
 #[derive(Debug, Clone, Eq, PartialEq)]
 struct JumpCounts {
@@ -228,3 +695,146 @@ impl JumpCounts {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{MidiChannel, MidiNote, NoteOn, PressVelocity};
+
+    #[test]
+    fn test_exact_clock() {
+        // At 144 BPM a tick lasts 13020833.33ns, so rounding every wait
+        // would leave the cursor 1.5us short after a minute.
+        let bpm = BpmInfo {
+            beats_per_minute: NonZeroU16::new(144).unwrap(),
+            ..BpmInfo::default()
+        };
+        let tick = TrackEvent::Wait(WaitTime::Ticks(NonZeroU16::new(1).unwrap()));
+        let beat = TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(1).unwrap()));
+        let ticks = vec![
+            TrackEvent::SetBpm(bpm),
+            tick,
+            TrackEvent::Jump {
+                target: 1,
+                count: NonZeroU16::new(144 * 32 - 1),
+            },
+            TrackEvent::End,
+        ];
+        let beats = vec![
+            TrackEvent::SetBpm(bpm),
+            beat,
+            TrackEvent::Jump {
+                target: 1,
+                count: NonZeroU16::new(143),
+            },
+            TrackEvent::End,
+        ];
+        let minute = Duration::from_secs(60);
+        for track in [ticks, beats].iter() {
+            let mut cursor = TrackCursor::new(track.clone());
+            cursor.step_until(minute * 2).count();
+            assert_eq!(minute, cursor.cur_clock());
+            assert_eq!(144 * 32, cursor.cur_ticks());
+        }
+    }
+
+    #[test]
+    fn test_long_wait() {
+        // 3000 beats at 32 ticks per beat do not fit in a `u16`.
+        let track = vec![
+            TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(3000).unwrap())),
+            TrackEvent::End,
+        ];
+        let mut cursor = TrackCursor::new(track);
+        cursor.step_until(Duration::from_secs(2000)).count();
+        assert_eq!(Duration::from_secs(1500), cursor.cur_clock());
+        assert_eq!(3000 * 32, cursor.cur_ticks());
+        assert_eq!(751, cursor.position().bar);
+    }
+
+    #[test]
+    fn test_short_clock_waits() {
+        // A tick lasts 15.625ms at 120 BPM, so 100 waits of 5ms make 32 ticks.
+        let track = vec![
+            TrackEvent::Wait(WaitTime::Clock(Duration::from_millis(5))),
+            TrackEvent::Jump {
+                target: 0,
+                count: NonZeroU16::new(99),
+            },
+            TrackEvent::End,
+        ];
+        let mut cursor = TrackCursor::new(track);
+        cursor.step_until(Duration::from_secs(1)).count();
+        assert_eq!(Duration::from_millis(500), cursor.cur_clock());
+        assert_eq!(32, cursor.cur_ticks());
+        assert_eq!("1:2:00", cursor.position().to_string());
+    }
+
+    #[test]
+    fn test_call_return() {
+        let port = OutputPort::from(0);
+        let send = |raw| TrackEvent::SendMessage {
+            message: MidiMessage::NoteOn(NoteOn::new(
+                MidiChannel::default(),
+                MidiNote::from_raw(raw).unwrap(),
+                PressVelocity::from_raw(90).unwrap(),
+            )),
+            port,
+        };
+        let beat = TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(1).unwrap()));
+        // The subroutine at 4 plays twice, and each call comes back to the
+        // instruction after it.
+        let track = vec![
+            TrackEvent::Call { target: 4 },
+            TrackEvent::Call { target: 4 },
+            send(72),
+            TrackEvent::End,
+            send(60),
+            beat,
+            TrackEvent::Return,
+        ];
+        let mut cursor = TrackCursor::new(track);
+        let notes: Vec<_> = cursor
+            .step_until(Duration::from_secs(10))
+            .map(|(time, _, msg)| match msg {
+                MidiMessage::NoteOn(on) => (time.as_millis(), on.note().as_u8()),
+                other => panic!("Unexpected message {:?}", other),
+            })
+            .collect();
+        assert_eq!(vec![(0, 60), (500, 60), (1000, 72)], notes);
+        assert_eq!((None, &[][..]), (cursor.fault(), cursor.return_addrs()));
+
+        // A subroutine that calls itself runs out of call stack.
+        let mut cursor = TrackCursor::new(vec![TrackEvent::Call { target: 0 }, TrackEvent::End]);
+        cursor.step_until(Duration::from_secs(1)).count();
+        assert_eq!(Some(&StepError::CallStackOverflow(0)), cursor.fault());
+        assert_eq!(MAX_CALL_DEPTH, cursor.return_addrs().len());
+
+        // A return outside of any call has nowhere to go.
+        let mut cursor = TrackCursor::new(vec![beat, TrackEvent::Return, TrackEvent::End]);
+        cursor.step_until(Duration::from_secs(1)).count();
+        assert_eq!(Some(&StepError::CallStackUnderflow(1)), cursor.fault());
+    }
+
+    #[test]
+    fn test_fault_slot() {
+        let slot = FaultSlot::default();
+        assert_eq!(None, slot.take());
+        let faults = vec![
+            StepError::BadJumpTarget { target: 1 },
+            StepError::JumpIdxNotFound { target: 2 },
+            StepError::BadInstrPointer(3),
+            StepError::BudgetExceeded {
+                budget: 4,
+                instruction_pointer: 5,
+            },
+            StepError::CallStackOverflow(6),
+            StepError::CallStackUnderflow(7),
+        ];
+        for fault in faults {
+            slot.publish(&fault);
+            assert_eq!(Some(fault), slot.take());
+            assert_eq!(None, slot.take());
+        }
+    }
+}
@@ -26,29 +26,20 @@ pub enum TrackEvent {
         count: Option<NonZeroU16>,
     },
 
+    /// Jumps to a subroutine at `target`, pushing the address of the
+    /// next instruction onto the VM's call stack.
+    Call { target: usize },
+
+    /// Returns from a subroutine, continuing at the address on
+    /// the top of the VM's call stack.
+    Return,
+
     /// Represents the end of the playback track. 
     /// If the VM reachs this instruction, it will not continue 
     /// past it at all. 
     End,
 }
 
-const fn clamped_to_nonzerou16(raw: u128) -> NonZeroU16 {
-    if raw > u16::max_value() as u128 {
-        // SAFETY: Guranteed safe b/c `u16::max_value()` is
-        // a guranteed non-zero constant.
-        unsafe { NonZeroU16::new_unchecked(u16::max_value()) }
-    } else if raw == 0 {
-        // SAFETY: Guranteed safe b/c `1` is
-        // a guranteed non-zero constant.
-        unsafe { NonZeroU16::new_unchecked(1) }
-    } else {
-        // SAFETY: Guranteed safe b/c the zero case
-        // and overflow cases were previously handled.
-        unsafe { NonZeroU16::new_unchecked(raw as u16) }
-    }
-}
-
-
 /// Represents a time that the VM will wait without performing an action.
 /// 
 /// Song playback oftentimes deals with two parallel ways of measuring time:
@@ -72,20 +63,13 @@ pub enum WaitTime {
 
 impl WaitTime {
 
-    /// Converts this waiting period to beat "ticks", as defined by the provided `bpm_info`. 
-    pub const fn as_ticks(&self, bpm_info: BpmInfo) -> NonZeroU16 {
+    /// Converts this waiting period to beat "ticks", as defined by the provided `bpm_info`.
+    /// Clock waits count the whole ticks that fit in them.
+    pub const fn as_ticks(&self, bpm_info: BpmInfo) -> u64 {
         match *self {
-            WaitTime::Ticks(ticks) => ticks,
-            WaitTime::Clock(dur) => {
-                let nanos_per_tick = bpm_info.tick_duration().as_nanos();
-                let self_nanos = dur.as_nanos();
-                let ticks = self_nanos / nanos_per_tick;
-                clamped_to_nonzerou16(ticks)
-            }
-            WaitTime::Beats(b) => {
-                let raw = b.get() * bpm_info.ticks_per_beat.get();
-                clamped_to_nonzerou16(raw as u128)
-            }
+            WaitTime::Ticks(ticks) => ticks.get() as u64,
+            WaitTime::Clock(dur) => bpm_info.duration_ticks(dur),
+            WaitTime::Beats(b) => (b.get() as u64) * (bpm_info.ticks_per_beat.get() as u64),
         }
     }
 
@@ -94,13 +78,10 @@ impl WaitTime {
         match *self {
             WaitTime::Beats(b) => {
                 let ticks = (bpm_info.ticks_per_beat.get() as u64) * (b.get() as u64);
-                let nanos = (bpm_info.tick_duration().as_nanos() as u64) * ticks;
-                Duration::from_nanos(nanos)
+                bpm_info.ticks_duration(ticks)
             }
             WaitTime::Clock(dur) => dur,
-            WaitTime::Ticks(ticks) => Duration::from_nanos(
-                (bpm_info.tick_duration().as_nanos() as u64) * (ticks.get() as u64),
-            ),
+            WaitTime::Ticks(ticks) => bpm_info.ticks_duration(ticks.get() as u64),
         }
     }
 }
@@ -123,6 +104,11 @@ pub struct BpmInfo {
     /// cannot be shorter than a quarter of a beat.
     /// Defaults to 32.
     pub ticks_per_beat: NonZeroU16,
+    /// The number of beats in a single bar, IE the top
+    /// number of the time signature.
+    ///
+    /// Defaults to 4.
+    pub beats_per_bar: NonZeroU16,
 }
 
 const NANOS_PER_MINUTE: u64 = 60 * 1000 * 1000 * 1000;
@@ -141,11 +127,47 @@ impl BpmInfo {
         Duration::from_nanos(self.nanos_per_beat())
     }
 
+    /// The number of ticks in a single bar.
+    pub const fn ticks_per_bar(&self) -> u64 {
+        (self.ticks_per_beat.get() as u64) * (self.beats_per_bar.get() as u64)
+    }
+
     /// The clock duration between the start of a 
     /// beat tick and the start of the next.
     pub const fn tick_duration(&self) -> Duration {
         Duration::from_nanos(self.nanos_per_tick())
     }
+
+    const fn ticks_per_minute(&self) -> u128 {
+        (self.beats_per_minute.get() as u128) * (self.ticks_per_beat.get() as u128)
+    }
+
+    /// The clock duration of `ticks` ticks, rounded down to the nanosecond.
+    ///
+    /// Unlike multiplying `tick_duration()`, which is itself rounded,
+    /// the rounding error does not grow with the number of ticks.
+    pub const fn ticks_duration(&self, ticks: u64) -> Duration {
+        let nanos = (ticks as u128) * (NANOS_PER_MINUTE as u128) / self.ticks_per_minute();
+        Duration::from_nanos(nanos as u64)
+    }
+
+    /// The clock duration of `beats / per_beat` beats, rounded down to the nanosecond.
+    pub const fn beats_duration(&self, beats: u128, per_beat: u128) -> Duration {
+        let nanos = beats * (NANOS_PER_MINUTE as u128)
+            / ((self.beats_per_minute.get() as u128) * per_beat);
+        Duration::from_nanos(nanos as u64)
+    }
+
+    /// The number of whole `1 / per_beat` parts of a beat that fit in `dur`.
+    pub const fn duration_beats(&self, dur: Duration, per_beat: u128) -> u128 {
+        dur.as_nanos() * (self.beats_per_minute.get() as u128) * per_beat
+            / (NANOS_PER_MINUTE as u128)
+    }
+
+    /// The number of whole ticks that fit in `dur`.
+    pub const fn duration_ticks(&self, dur: Duration) -> u64 {
+        (dur.as_nanos() * self.ticks_per_minute() / (NANOS_PER_MINUTE as u128)) as u64
+    }
 }
 
 impl Default for BpmInfo {
@@ -153,6 +175,7 @@ impl Default for BpmInfo {
         BpmInfo {
             beats_per_minute: NonZeroU16::new(120).unwrap(),
             ticks_per_beat: NonZeroU16::new(32).unwrap(),
+            beats_per_bar: NonZeroU16::new(4).unwrap(),
         }
     }
 }
@@ -0,0 +1,292 @@
+use super::{reachable, EventTrack, OutputPort, TrackEvent, MAX_CALL_DEPTH};
+use crate::midi::{MidiChannel, MidiMessage, MidiNote};
+use crate::model::{NoteClass, NoteKey};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+
+/// The maximum number of instructions `lint_track` will simulate before
+/// giving up on finding more held notes.
+const MAX_LINT_STEPS: usize = 1 << 20;
+
+/// A single note that has been pressed but not yet released.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct HeldNote {
+    pub port: OutputPort,
+    pub channel: MidiChannel,
+    pub note: MidiNote,
+}
+
+impl HeldNote {
+    /// Describes the note, spelled the way it would be written in `key`.
+    pub fn describe(&self, key: &NoteKey) -> String {
+        let spelled = key.spell(self.note.note());
+        let written_octave = self.note.octave().as_raw() - spelled.octave_offset();
+        format!(
+            "{}{} (channel {}, port {:?})",
+            spelled,
+            written_octave,
+            self.channel.as_u8() + 1,
+            self.port
+        )
+    }
+}
+
+impl fmt::Display for HeldNote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.describe(&NoteKey::major(NoteClass::C)))
+    }
+}
+
+/// Possible problems found in a compiled track.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum TrackLint {
+    /// A note is still held when playback reaches the `End` instruction at `end`.
+    HeldAtEnd { end: usize, note: HeldNote },
+    /// A note is still held when the jump at `jump` loops backwards.
+    HeldAtLoop { jump: usize, note: HeldNote },
+    /// The instructions in `start..=end` can never be run.
+    Unreachable { start: usize, end: usize },
+}
+
+impl TrackLint {
+    /// Describes the problem, spelling any notes the way they would be written in `key`.
+    pub fn describe(&self, key: &NoteKey) -> String {
+        match self {
+            TrackLint::HeldAtEnd { end, note } => format!(
+                "Note {} is never released before the end of the track at event {}.",
+                note.describe(key),
+                end
+            ),
+            TrackLint::HeldAtLoop { jump, note } => format!(
+                "Note {} is still held when the jump at event {} loops back.",
+                note.describe(key),
+                jump
+            ),
+            TrackLint::Unreachable { start, end } if start == end => {
+                format!("Event {} can never be reached.", start)
+            }
+            TrackLint::Unreachable { start, end } => {
+                format!("Events {} through {} can never be reached.", start, end)
+            }
+        }
+    }
+}
+
+impl fmt::Display for TrackLint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.describe(&NoteKey::major(NoteClass::C)))
+    }
+}
+
+/// Checks a compiled track for stuck notes and unreachable code.
+pub fn lint_track(track: &impl EventTrack) -> Vec<TrackLint> {
+    let mut retvl = held_notes(track);
+    retvl.extend(unreachable_spans(track));
+    retvl
+}
+
+/// Finds all spans of instructions that can never be run.
+///
+/// Unreachable `End` instructions are ignored, since the compiler always
+/// appends one even if the track loops forever.
+fn unreachable_spans(track: &impl EventTrack) -> Vec<TrackLint> {
+    let mut retvl = Vec::new();
+    let mut cur_start = None;
+    for (idx, is_reachable) in reachable(track).into_iter().enumerate() {
+        let is_dead = !is_reachable && track.get(idx) != Some(TrackEvent::End);
+        match (is_dead, cur_start) {
+            (true, None) => {
+                cur_start = Some(idx);
+            }
+            (false, Some(start)) => {
+                retvl.push(TrackLint::Unreachable {
+                    start,
+                    end: idx - 1,
+                });
+                cur_start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(start) = cur_start {
+        retvl.push(TrackLint::Unreachable {
+            start,
+            end: track.len() - 1,
+        });
+    }
+    retvl
+}
+
+/// Runs through the track the same way the `TrackCursor` would, tracking which
+/// notes are held when playback ends or loops back.
+///
+/// Since tracks take no input, the simulation stops as soon as it
+/// reaches an uncounted jump in a state it has seen before.
+fn held_notes(track: &impl EventTrack) -> Vec<TrackLint> {
+    let mut counters = track.finite_jumps();
+    let mut held = BTreeSet::new();
+    let mut call_stack = Vec::new();
+    let mut seen_states = HashSet::new();
+    let mut found = Vec::new();
+    let mut ip = 0;
+    for _ in 0..MAX_LINT_STEPS {
+        let evt = match track.get(ip) {
+            Some(evt) => evt,
+            None => break,
+        };
+        match evt {
+            TrackEvent::End => {
+                found.extend(held.iter().map(|note| TrackLint::HeldAtEnd {
+                    end: ip,
+                    note: *note,
+                }));
+                break;
+            }
+            TrackEvent::SendMessage { message, port } => {
+                match message {
+                    MidiMessage::NoteOn(data) if data.vel().as_u8() > 0 => {
+                        held.insert(HeldNote {
+                            port,
+                            channel: data.channel(),
+                            note: data.note(),
+                        });
+                    }
+                    MidiMessage::NoteOn(data) => {
+                        held.remove(&HeldNote {
+                            port,
+                            channel: data.channel(),
+                            note: data.note(),
+                        });
+                    }
+                    MidiMessage::NoteOff(data) => {
+                        held.remove(&HeldNote {
+                            port,
+                            channel: data.channel(),
+                            note: data.note(),
+                        });
+                    }
+                    MidiMessage::Other(_) => {}
+                }
+                ip += 1;
+            }
+            TrackEvent::SetBpm(_) | TrackEvent::Wait(_) => {
+                ip += 1;
+            }
+            TrackEvent::Call { .. } if call_stack.len() >= MAX_CALL_DEPTH => break,
+            TrackEvent::Call { target } => {
+                call_stack.push(ip + 1);
+                ip = target;
+            }
+            TrackEvent::Return => match call_stack.pop() {
+                Some(next) => {
+                    ip = next;
+                }
+                None => break,
+            },
+            TrackEvent::Jump { target, count } => {
+                let next = match count {
+                    None => target,
+                    Some(count) => {
+                        let cur_count = match counters.iter_mut().find(|(idx, _)| *idx == ip) {
+                            Some((_, cur_count)) => cur_count,
+                            None => break,
+                        };
+                        if *cur_count == 0 {
+                            *cur_count = count.get();
+                            ip + 1
+                        } else {
+                            *cur_count -= 1;
+                            target
+                        }
+                    }
+                };
+                if next <= ip {
+                    found.extend(held.iter().map(|note| TrackLint::HeldAtLoop {
+                        jump: ip,
+                        note: *note,
+                    }));
+                }
+                if count.is_none()
+                    && !seen_states.insert((
+                        ip,
+                        counters.clone(),
+                        call_stack.clone(),
+                        held.clone(),
+                    ))
+                {
+                    break;
+                }
+                ip = next;
+            }
+        }
+    }
+    let mut deduped = HashSet::new();
+    found.retain(|lint| deduped.insert(lint.clone()));
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{NoteOff, NoteOn, PressVelocity};
+    use crate::track::WaitTime;
+    use std::num::NonZeroU16;
+
+    #[test]
+    fn test_lint_track() {
+        let port = OutputPort::from(0);
+        let channel = MidiChannel::default();
+        let note = MidiNote::from_raw(60).unwrap();
+        let vel = PressVelocity::from_raw(90).unwrap();
+        let on = TrackEvent::SendMessage {
+            message: NoteOn::new(channel, note, vel).into(),
+            port,
+        };
+        let off = TrackEvent::SendMessage {
+            message: NoteOff::new(channel, note, PressVelocity::default()).into(),
+            port,
+        };
+        let wait = TrackEvent::Wait(WaitTime::Ticks(NonZeroU16::new(4).unwrap()));
+        let held = HeldNote {
+            port,
+            channel,
+            note,
+        };
+
+        let clean = vec![
+            on,
+            wait,
+            off,
+            TrackEvent::Jump {
+                target: 0,
+                count: NonZeroU16::new(2),
+            },
+            TrackEvent::End,
+        ];
+        assert!(lint_track(&clean).is_empty());
+
+        let stuck = vec![
+            on,
+            wait,
+            TrackEvent::Jump {
+                target: 0,
+                count: None,
+            },
+            off,
+            TrackEvent::End,
+        ];
+        assert_eq!(
+            vec![
+                TrackLint::HeldAtLoop { jump: 2, note: held },
+                TrackLint::Unreachable { start: 3, end: 3 },
+            ],
+            lint_track(&stuck)
+        );
+
+        let unreleased = vec![on, wait, TrackEvent::End];
+        assert_eq!(
+            vec![TrackLint::HeldAtEnd { end: 2, note: held }],
+            lint_track(&unreleased)
+        );
+    }
+}
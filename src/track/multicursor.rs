@@ -1,17 +1,235 @@
-use super::{EventTrack, TrackCursor};
-use crate::midi::MidiMessage;
+use super::{
+    tempo_map, BpmInfo, ClipState, EventTrack, OutputPort, Quantize, SeekPosition, TempoMap,
+    TempoMapError, TrackCursor, BEAT_DIVISIONS,
+};
+use crate::midi::{MidiMessage, NoteOff, PressVelocity};
 use crate::PortIdent;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
+use thiserror::*;
+
+/// Where the tracks of a `VecMultiCursor` get their tempo from.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub enum TempoMode {
+    /// Each track follows its own SET BPM commands, so tracks with
+    /// different tempos drift apart.
+    #[default]
+    Polytempo,
+    /// Every track follows the SET BPM commands of the track at this index,
+    /// keeping all tracks on the same beat.
+    Master(usize),
+}
+
+/// Errors that may occur when changing the `TempoMode` of a `VecMultiCursor`.
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+pub enum TempoModeError {
+    #[error("There is no track {0} to take the master tempo from.")]
+    NoSuchTrack(usize),
+    #[error("Could not map the tempo of master track {0}: {1}")]
+    Map(usize, #[source] TempoMapError),
+}
+
+/// How a track of a `VecMultiCursor` is played.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TrackMix {
+    pub muted: bool,
+    /// If any track is soloed, only soloed tracks are heard.
+    pub solo: bool,
+    /// The factor note velocities are scaled by. Notes scaled down to nothing
+    /// are not played, so a gain of 0 silences the track.
+    pub gain: f32,
+}
+
+impl Default for TrackMix {
+    fn default() -> Self {
+        TrackMix {
+            muted: false,
+            solo: false,
+            gain: 1.0,
+        }
+    }
+}
+
+impl TrackMix {
+    /// Scales a note velocity by the track's gain, or gets `None` if the
+    /// scaled note is too quiet to be heard at all.
+    fn scale_velocity(&self, vel: u8) -> Option<PressVelocity> {
+        match (f32::from(vel) * self.gain).round().min(127.0) as u8 {
+            0 => None,
+            scaled => PressVelocity::from_raw(scaled),
+        }
+    }
+}
+
+/// A `TrackMix` that can be changed from one thread while another plays
+/// the track, IE from the command line while the realtime thread plays.
+#[derive(Debug)]
+pub struct MixControl {
+    muted: AtomicBool,
+    solo: AtomicBool,
+    /// The bits of the `f32` gain.
+    gain: AtomicU32,
+}
+
+impl Default for MixControl {
+    fn default() -> Self {
+        MixControl {
+            muted: AtomicBool::new(false),
+            solo: AtomicBool::new(false),
+            gain: AtomicU32::new(1.0f32.to_bits()),
+        }
+    }
+}
+
+impl MixControl {
+    pub fn load(&self) -> TrackMix {
+        TrackMix {
+            muted: self.muted.load(Ordering::Acquire),
+            solo: self.solo.load(Ordering::Acquire),
+            gain: f32::from_bits(self.gain.load(Ordering::Acquire)),
+        }
+    }
+
+    /// Flips whether the track is muted, returning the new value.
+    pub fn toggle_mute(&self) -> bool {
+        !self.muted.fetch_xor(true, Ordering::AcqRel)
+    }
+
+    /// Flips whether the track is soloed, returning the new value.
+    pub fn toggle_solo(&self) -> bool {
+        !self.solo.fetch_xor(true, Ordering::AcqRel)
+    }
+
+    /// Sets the velocity scale factor. Negative and non-finite factors are ignored.
+    pub fn set_gain(&self, gain: f32) {
+        if gain.is_finite() && gain >= 0.0 {
+            self.gain.store(gain.to_bits(), Ordering::Release);
+        }
+    }
+}
+
+/// Where a wrapped cursor is at in the current `step_until()` call.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Head {
+    /// The cursor needs to be stepped to find its next event.
+    Empty,
+    /// The cursor's next event, waiting on earlier events from other cursors.
+    Pending((Duration, OutputPort, MidiMessage)),
+    /// The cursor has no more events before the end of the call.
+    Done,
+}
+
+/// A new version of one of the tracks of a `VecMultiCursor`, to be swapped
+/// in while it plays. See `VecMultiCursor::stage_swap()`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TrackSwap<T: EventTrack> {
+    /// The new cursor, already moved to the point it takes over from.
+    /// Once the swap happens, this holds the old cursor instead.
+    pub cursor: TrackCursor<T>,
+    /// When the swap happens, along the clock of the cursor being replaced.
+    pub at: Duration,
+    /// The same moment along the clock of the new cursor.
+    pub from: Duration,
+    /// Whether the swap has happened. Swaps that are cancelled or turned
+    /// down are handed back with this unset.
+    pub swapped: bool,
+}
+
+impl<T: EventTrack> TrackSwap<T> {
+    /// Prepares a swap from track `old` to track `new` at the start of bar
+    /// `bar`, with both following `master` as with `TrackCursor::follow_tempo()`.
+    ///
+    /// Both tracks are run from the start up to the bar, so this should not
+    /// be done on the realtime thread. The notes `old` has sounding at the
+    /// bar are released before the new track's state is chased. Returns
+    /// `None` if `old` ends or faults before the bar.
+    pub fn at_bar(old: T, new: T, master: Option<Arc<TempoMap>>, bar: u32) -> Option<Self> {
+        let mut old = TrackCursor::new(old);
+        old.follow_tempo(master.clone());
+        let at = old.seek(SeekPosition::Bar(bar));
+        if old.fault().is_some() || old.position().bar < u64::from(bar) {
+            return None;
+        }
+        let releases: Vec<_> = old
+            .chased()
+            .filter_map(|(port, msg)| match msg {
+                MidiMessage::NoteOn(data) if data.vel().as_u8() > 0 => {
+                    let vel = PressVelocity::from_raw(0).unwrap();
+                    let off = NoteOff::new(data.channel(), data.note(), vel);
+                    Some((port, MidiMessage::NoteOff(off)))
+                }
+                _ => None,
+            })
+            .collect();
+        let mut cursor = TrackCursor::new(new);
+        cursor.follow_tempo(master);
+        let from = cursor.seek(SeekPosition::Bar(bar));
+        cursor.chase_first(&releases);
+        Some(TrackSwap {
+            cursor,
+            at,
+            from,
+            swapped: false,
+        })
+    }
+}
 
 /// A cursor aggregator that wraps multiple `TrackCursor`s into a single
 /// progressable cursor. 
 pub struct VecMultiCursor<T: EventTrack> {
     cursors: Vec<TrackCursor<T>>,
+    /// The next event of each cursor. Pending events are kept between
+    /// calls, so none are lost if a call's iterator is dropped early.
+    heads: Vec<Head>,
+    /// The instructions each cursor has left in the current call.
+    budgets: Vec<usize>,
+    mix: Vec<TrackMix>,
+    tempo_mode: TempoMode,
+    /// A moment along each cursor's clock, paired with the same moment in
+    /// playback time. The two only differ once a cursor has been swapped in.
+    offsets: Vec<(Duration, Duration)>,
+    /// The swap waiting to happen on each track, and its playback time.
+    swaps: Vec<Option<(Duration, Box<TrackSwap<T>>)>>,
+    /// Swaps that are done with, waiting to be handed back.
+    retired: Vec<Option<Box<TrackSwap<T>>>>,
+    /// The end of the latest `step_until()` call.
+    played_until: Duration,
+    /// Where each track is at as a clip.
+    clips: Vec<ClipState>,
+    /// Whether each clip starts over when it reaches its end.
+    looping: Vec<bool>,
+    /// The clips that stopped since the last `clear_stopped()`.
+    stopped: Vec<bool>,
+    /// Whether clips start out stopped rather than playing.
+    session: bool,
+    /// The tempo clips are launched and stopped in time with: the master
+    /// track's in `TempoMode::Master`, and the first track's otherwise.
+    grid: Option<Arc<TempoMap>>,
 }
 
 impl<T: EventTrack> VecMultiCursor<T> {
     pub fn new(cursors: Vec<TrackCursor<T>>) -> Self {
-        Self { cursors }
+        let tracks = cursors.len();
+        Self {
+            heads: vec![Head::Empty; tracks],
+            budgets: vec![0; tracks],
+            mix: vec![TrackMix::default(); tracks],
+            tempo_mode: TempoMode::default(),
+            offsets: vec![Default::default(); tracks],
+            swaps: (0..tracks).map(|_| None).collect(),
+            retired: (0..tracks).map(|_| None).collect(),
+            played_until: Duration::default(),
+            clips: vec![ClipState::Playing; tracks],
+            looping: vec![false; tracks],
+            stopped: vec![false; tracks],
+            session: false,
+            grid: cursors
+                .first()
+                .and_then(|cursor| tempo_map(cursor.data()).ok())
+                .map(Arc::new),
+            cursors,
+        }
     }
 
     /// Gets the inner list of `TrackCursor<T>`s that this
@@ -38,7 +256,8 @@ impl<T: EventTrack> VecMultiCursor<T> {
         // play time. 
         self.cursors
             .iter()
-            .map(|cursor| cursor.cur_clock())
+            .zip(self.offsets.iter())
+            .map(|(cursor, offset)| to_play_time(*offset, cursor.cur_clock()))
             .max() 
             .unwrap_or_default()
     }
@@ -54,25 +273,830 @@ impl<T: EventTrack> VecMultiCursor<T> {
     /// with the `usize` corresponding to the index in the vector of the track that produced the
     /// message. This allows for more dynamic mapping of track + port label index -> actual output
     /// port structure.
+    ///
+    /// Events from all tracks are merged in time order, with events at the same time
+    /// ordered by track index. Each track gets its own instruction budget for the call,
+    /// as with `TrackCursor::step_until()`. No allocation happens here, so this is
+    /// safe to call from the realtime thread.
     pub fn step_until<'a>(
         &'a mut self,
         end: Duration,
     ) -> impl Iterator<Item = (Duration, PortIdent, MidiMessage)> + 'a {
-        let cursor_mapper = move |(idx, cursor): (_, &'a mut TrackCursor<_>)| {
-            cursor
-                .step_until( end)
-                .map(move |(time, port, msg)| (time, (idx, port), msg))
+        let heads = self.heads.iter_mut().zip(self.budgets.iter_mut());
+        for ((head, budget), cursor) in heads.zip(self.cursors.iter()) {
+            if *head == Head::Done {
+                *head = Head::Empty;
+            }
+            *budget = cursor.instruction_budget();
+        }
+        self.played_until = end;
+        std::iter::from_fn(move || self.next_event(end))
+    }
+
+    /// Queues a new version of track `idx` to take over from the current one
+    /// at `swap.at`, keeping the rest of the tracks playing.
+    ///
+    /// The new cursor should already be at the point it takes over from,
+    /// IE through `TrackCursor::seek()`, and is played from `swap.from` on
+    /// its own clock. Everything the old cursor would emit from `swap.at`
+    /// on is dropped. Both the swap and the old cursor are handed back
+    /// through `take_retired()`, so nothing is freed here, and this is
+    /// safe to call from the realtime thread.
+    ///
+    /// Only one swap per track can be in flight at a time. The swap is
+    /// handed straight back if another one is, or if its time has already
+    /// been played. Clips that are not playing are swapped right away.
+    pub fn stage_swap(
+        &mut self,
+        idx: usize,
+        swap: Box<TrackSwap<T>>,
+    ) -> Result<(), Box<TrackSwap<T>>> {
+        let offset = match self.offsets.get(idx) {
+            Some(offset) => *offset,
+            None => return Err(swap),
         };
-        self.cursors.iter_mut().enumerate().flat_map(cursor_mapper)
+        let at = to_play_time(offset, swap.at);
+        let busy = self.swaps[idx].is_some() || self.retired[idx].is_some();
+        if busy {
+            return Err(swap);
+        }
+        if let ClipState::Stopped | ClipState::Queued(_) = self.clips[idx] {
+            self.swap_in(idx, self.played_until, swap);
+            return Ok(());
+        }
+        if at <= self.played_until {
+            return Err(swap);
+        }
+        if let Head::Pending((time, _, _)) = self.heads[idx] {
+            if time >= at {
+                self.heads[idx] = Head::Empty;
+            }
+        }
+        self.swaps[idx] = Some((at, swap));
+        Ok(())
+    }
+
+    /// Takes back a swap of track `idx` that happened or was cancelled.
+    pub fn take_retired(&mut self, idx: usize) -> Option<Box<TrackSwap<T>>> {
+        self.retired.get_mut(idx).and_then(Option::take)
+    }
+
+    #[allow(dead_code)]
+    pub fn tempo_mode(&self) -> TempoMode {
+        self.tempo_mode
+    }
+
+    /// Sets where the tracks get their tempo from, and resets all cursors.
+    ///
+    /// In `TempoMode::Master` the master track is mapped out up front, so
+    /// this allocates and should not be called from the realtime thread.
+    /// See `TrackCursor::follow_tempo()` for how the other tracks follow it.
+    pub fn set_tempo_mode(&mut self, mode: TempoMode) -> Result<(), TempoModeError> {
+        let master = follow_master(&mut self.cursors, mode)?;
+        self.clear_heads();
+        self.cancel_swaps();
+        self.reset_clips();
+        self.grid = match master {
+            Some(map) => Some(map),
+            None => self
+                .cursors
+                .first()
+                .and_then(|cursor| tempo_map(cursor.data()).ok())
+                .map(Arc::new),
+        };
+        self.tempo_mode = mode;
+        Ok(())
+    }
+
+    /// Gets where a track is at as a clip, if the track exists.
+    #[allow(dead_code)]
+    pub fn clip_state(&self, idx: usize) -> Option<ClipState> {
+        self.clips.get(idx).copied()
+    }
+
+    /// Queues clip `idx` to start from the top on the next `quantize`
+    /// boundary after the end of the latest `step_until()` call, or keeps
+    /// it playing if it was about to stop. Clips that are already playing
+    /// carry on as they are.
+    ///
+    /// Boundaries follow the tempo of the master track in `TempoMode::Master`,
+    /// and of the first track otherwise. Bars are counted from the start of
+    /// playback, in the time signature the song starts in.
+    pub fn launch(&mut self, idx: usize, quantize: Quantize) {
+        let at = self.next_boundary(quantize);
+        if let Some(clip) = self.clips.get_mut(idx) {
+            *clip = match *clip {
+                ClipState::Stopped | ClipState::Queued(_) => ClipState::Queued(at),
+                ClipState::Playing | ClipState::Stopping(_) => ClipState::Playing,
+            };
+        }
+    }
+
+    /// Stops clip `idx` on the next `quantize` boundary, as with `launch()`,
+    /// or keeps it stopped if it was about to start.
+    ///
+    /// Releasing the notes of stopped clips is up to the caller,
+    /// through `clip_stopped()`.
+    pub fn stop(&mut self, idx: usize, quantize: Quantize) {
+        let at = self.next_boundary(quantize);
+        if let Some(clip) = self.clips.get_mut(idx) {
+            *clip = match *clip {
+                ClipState::Stopped | ClipState::Queued(_) => ClipState::Stopped,
+                ClipState::Playing | ClipState::Stopping(_) => ClipState::Stopping(at),
+            };
+        }
+    }
+
+    /// Sets whether clip `idx` starts over when it reaches its end,
+    /// rather than stopping.
+    pub fn set_looping(&mut self, idx: usize, looping: bool) {
+        if let Some(slot) = self.looping.get_mut(idx) {
+            *slot = looping;
+        }
+    }
+
+    /// Stops every clip, and has them start out stopped after a `seek()`
+    /// or `reset()` too, so that only launched clips are played.
+    pub fn start_stopped(&mut self) {
+        self.session = true;
+        self.clear_heads();
+        self.cancel_swaps();
+        self.reset_clips();
+    }
+
+    /// Checks whether clip `idx` stopped since the last `clear_stopped()`,
+    /// either by being stopped or by reaching its end.
+    pub fn clip_stopped(&self, idx: usize) -> bool {
+        self.stopped.get(idx).copied().unwrap_or(false)
+    }
+
+    pub fn clear_stopped(&mut self) {
+        for stopped in self.stopped.iter_mut() {
+            *stopped = false;
+        }
+    }
+
+    /// Gets the first `quantize` boundary after the end of the latest
+    /// `step_until()` call.
+    fn next_boundary(&self, quantize: Quantize) -> Duration {
+        let bpm = match &self.grid {
+            Some(grid) => grid.segments()[0].bpm,
+            None => BpmInfo::default(),
+        };
+        let beats = match quantize {
+            Quantize::Beat => 1,
+            Quantize::Bars(bars) => u128::from(bpm.beats_per_bar.get()) * u128::from(bars.get()),
+        };
+        let unit = beats * BEAT_DIVISIONS;
+        let time_at = |beat| match &self.grid {
+            Some(grid) => grid.time_at_beat(beat),
+            None => bpm.beats_duration(beat, BEAT_DIVISIONS),
+        };
+        let beat = match &self.grid {
+            Some(grid) => grid.beat_at_time(self.played_until),
+            None => bpm.duration_beats(self.played_until, BEAT_DIVISIONS),
+        };
+        let mut boundary = beat / unit * unit;
+        while time_at(boundary) <= self.played_until {
+            boundary += unit;
+        }
+        time_at(boundary)
+    }
+
+    /// Starts clip `idx` from the top at playback time `at`.
+    fn start_clip(&mut self, idx: usize, at: Duration) {
+        let beat = self.grid.as_ref().map_or(0, |grid| grid.beat_at_time(at));
+        self.cursors[idx].reset_to_beat(beat);
+        self.offsets[idx] = (Duration::default(), at);
+        self.clips[idx] = ClipState::Playing;
+    }
+
+    /// Stops clip `idx`, making its staged swap right away.
+    fn stop_clip(&mut self, idx: usize) {
+        self.clips[idx] = ClipState::Stopped;
+        self.stopped[idx] = true;
+        if let Some((at, swap)) = self.swaps[idx].take() {
+            self.swap_in(idx, at, swap);
+        }
+    }
+
+    /// Puts every clip back the way it starts out.
+    fn reset_clips(&mut self) {
+        let state = if self.session {
+            ClipState::Stopped
+        } else {
+            ClipState::Playing
+        };
+        for clip in self.clips.iter_mut() {
+            *clip = state;
+        }
+    }
+
+    /// Gets how a track is played, if the track exists.
+    pub fn mix(&self, idx: usize) -> Option<TrackMix> {
+        self.mix.get(idx).copied()
+    }
+
+    /// Sets how a track is played.
+    ///
+    /// Muted tracks keep moving forward in time and still send everything
+    /// but new notes, so they pick up in the right state when unmuted.
+    /// Releasing the notes a track was playing when it went silent is up
+    /// to the caller: the returned function tells which tracks the change
+    /// silenced, by index.
+    pub fn set_mix(&mut self, idx: usize, mix: TrackMix) -> impl Fn(usize) -> bool + '_ {
+        let old_any_solo = self.mix.iter().any(|mix| mix.solo);
+        let old_mix = self.mix.get(idx).copied();
+        if let Some(slot) = self.mix.get_mut(idx) {
+            *slot = mix;
+        }
+        move |track| {
+            let before = if track == idx {
+                old_mix
+            } else {
+                self.mix.get(track).copied()
+            };
+            let was_audible = before.is_some_and(|mix| !mix.muted && (mix.solo || !old_any_solo));
+            was_audible && !self.is_audible(track)
+        }
+    }
+
+    /// Checks whether a track's notes are heard, given the mute and solo
+    /// settings of all tracks.
+    pub fn is_audible(&self, idx: usize) -> bool {
+        let any_solo = self.mix.iter().any(|mix| mix.solo);
+        self.mix
+            .get(idx)
+            .is_some_and(|mix| !mix.muted && (mix.solo || !any_solo))
+    }
+
+    /// Takes the next event, applying the mix settings of its track.
+    fn next_event(&mut self, end: Duration) -> Option<(Duration, PortIdent, MidiMessage)> {
+        loop {
+            let (time, (idx, port), msg) = self.next_merged(end)?;
+            let msg = match msg {
+                MidiMessage::NoteOn(data) if data.vel().as_u8() > 0 => {
+                    if !self.is_audible(idx) {
+                        continue;
+                    }
+                    // Notes the gain silences are dropped, leaving their note offs
+                    // to go out with nothing to end.
+                    match self.mix[idx].scale_velocity(data.vel().as_u8()) {
+                        Some(vel) => MidiMessage::NoteOn(data.with_vel(vel)),
+                        None => continue,
+                    }
+                }
+                other => other,
+            };
+            return Some((time, (idx, port), msg));
+        }
+    }
+
+    /// Steps each cursor that has no pending event, then takes the earliest
+    /// pending event at or before `end`.
+    fn next_merged(&mut self, end: Duration) -> Option<(Duration, PortIdent, MidiMessage)> {
+        let mut next: Option<(usize, Duration)> = None;
+        for idx in 0..self.cursors.len() {
+            if self.heads[idx] == Head::Empty {
+                self.heads[idx] = self.step_head(idx, end);
+            }
+            if let (ClipState::Stopping(at), Head::Pending((time, _, _))) =
+                (self.clips[idx], self.heads[idx])
+            {
+                // Events past the stop are only dropped once the stop comes,
+                // so none are lost if it is called off.
+                if at <= end && time >= at {
+                    self.stop_clip(idx);
+                    self.heads[idx] = Head::Done;
+                }
+            }
+            if let Head::Pending((time, _, _)) = self.heads[idx] {
+                // Strictly earlier, so ties go to the lowest track index.
+                if time <= end && next.is_none_or(|(_, best)| time < best) {
+                    next = Some((idx, time));
+                }
+            }
+        }
+        let (idx, _) = next?;
+        match std::mem::replace(&mut self.heads[idx], Head::Empty) {
+            Head::Pending((time, port, msg)) => Some((time, (idx, port), msg)),
+            _ => None,
+        }
+    }
+
+    /// Finds the next event of cursor `idx` at or before `end`, in playback
+    /// time, swapping in the track's staged swap once its time comes, and
+    /// starting, stopping or looping the clip as it goes.
+    fn step_head(&mut self, idx: usize, end: Duration) -> Head {
+        loop {
+            let stop = match self.clips[idx] {
+                ClipState::Stopped => return Head::Done,
+                ClipState::Queued(at) if at > end => return Head::Done,
+                ClipState::Queued(at) => {
+                    self.start_clip(idx, at);
+                    None
+                }
+                ClipState::Playing => None,
+                ClipState::Stopping(at) => Some(at),
+            };
+            let swap = self.swaps[idx].as_ref().map(|(at, _)| *at);
+            // The cursor only plays up to, not including, the swap or stop.
+            let limit = swap
+                .into_iter()
+                .chain(stop)
+                .fold(end, |limit, at| limit.min(at.saturating_sub(Duration::from_nanos(1))));
+            let offset = self.offsets[idx];
+            let cursor_end = limit.saturating_sub(offset.1) + offset.0;
+            let cursor = &mut self.cursors[idx];
+            if let Some((time, port, msg)) = cursor.next_until(cursor_end, &mut self.budgets[idx]) {
+                return Head::Pending((to_play_time(offset, time), port, msg));
+            }
+            if stop.is_some_and(|stop| stop <= end && swap.is_none_or(|swap| stop <= swap)) {
+                self.stop_clip(idx);
+                return Head::Done;
+            }
+            let finish = to_play_time(offset, cursor.cur_clock());
+            if cursor.ended() && finish <= limit {
+                // A clip that takes no time would loop forever.
+                if self.looping[idx] && finish > offset.1 {
+                    self.start_clip(idx, finish);
+                    continue;
+                }
+                self.stop_clip(idx);
+                return Head::Done;
+            }
+            match self.swaps[idx].take() {
+                Some((at, swap)) if at <= end => self.swap_in(idx, at, swap),
+                waiting => {
+                    self.swaps[idx] = waiting;
+                    return Head::Done;
+                }
+            }
+        }
+    }
+
+    /// Swaps in the new cursor of `swap` at playback time `at`, and retires
+    /// the swap along with the old cursor.
+    fn swap_in(&mut self, idx: usize, at: Duration, mut swap: Box<TrackSwap<T>>) {
+        std::mem::swap(&mut self.cursors[idx], &mut swap.cursor);
+        swap.swapped = true;
+        self.offsets[idx] = (swap.from, at);
+        self.budgets[idx] = self.cursors[idx].instruction_budget();
+        self.retired[idx] = Some(swap);
+    }
+
+    /// Hands back every staged swap without making it, and lines each
+    /// cursor's clock back up with playback time.
+    fn cancel_swaps(&mut self) {
+        for (swap, retired) in self.swaps.iter_mut().zip(self.retired.iter_mut()) {
+            if let Some((_, swap)) = swap.take() {
+                *retired = Some(swap);
+            }
+        }
+        for offset in self.offsets.iter_mut() {
+            *offset = Default::default();
+        }
+        self.played_until = Duration::default();
+    }
+
+    /// Moves all cursors to `position`, chasing the state of each track,
+    /// and returns the clock time of that position.
+    ///
+    /// Tick and bar positions are measured along the master track in
+    /// `TempoMode::Master`, and along the first track otherwise; the other
+    /// tracks are then moved to the same clock time so they stay in sync.
+    /// Swaps that have not happened yet are cancelled, and clips are put
+    /// back the way they start out: playing, or stopped after
+    /// `start_stopped()`. See `TrackCursor::seek()` for details.
+    ///
+    /// This runs every track from the start, so it should not be called from
+    /// the realtime thread; see `PreparedSeek` for seeking from there.
+    #[allow(dead_code)]
+    pub fn seek(&mut self, position: SeekPosition) -> Duration {
+        self.clear_heads();
+        self.cancel_swaps();
+        self.reset_clips();
+        seek_cursors(&mut self.cursors, self.tempo_mode, position)
+    }
+
+    /// Swaps in cursors that were moved to a new position ahead of time,
+    /// and returns the clock time of that position. Swaps and clips are
+    /// handled as with `seek()`.
+    ///
+    /// The cursors that were playing are handed back in `seek`, so nothing
+    /// is freed here, and this is safe to call from the realtime thread.
+    /// Only as many cursors as both sides have are swapped.
+    pub fn apply_seek(&mut self, seek: &mut PreparedSeek<T>) -> Duration {
+        self.clear_heads();
+        self.cancel_swaps();
+        self.reset_clips();
+        for (cursor, seeked) in self.cursors.iter_mut().zip(seek.cursors.iter_mut()) {
+            std::mem::swap(cursor, seeked);
+        }
+        seek.time
     }
 
     /// Resets all cursors back to the beginning of the track.
     /// This includes resetting the instruction pointer, tick counter, 
     /// internal clock, and all jump index values back to zero, as well
-    /// as resetting the BPM value back to default.
+    /// as resetting the BPM value back to default. Swaps that have not
+    /// happened yet are cancelled, and clips are put back the way they
+    /// start out.
     pub fn reset(&mut self) {
+        self.clear_heads();
+        self.cancel_swaps();
+        self.reset_clips();
         for cursor in self.cursors.iter_mut() {
             cursor.reset();
         }
     }
+
+    fn clear_heads(&mut self) {
+        for head in self.heads.iter_mut() {
+            *head = Head::Empty;
+        }
+    }
+}
+
+/// The tracks of a `VecMultiCursor`, moved to a new position away from the
+/// realtime thread. See `VecMultiCursor::apply_seek()`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PreparedSeek<T: EventTrack> {
+    /// Once the seek is applied, this holds the cursors that were playing.
+    pub cursors: Vec<TrackCursor<T>>,
+    /// The clock time of the position.
+    pub time: Duration,
+}
+
+impl<T: EventTrack> PreparedSeek<T> {
+    /// Runs each of `tracks` up to `position`, following the tempo `mode`
+    /// sets, as `VecMultiCursor::seek()` would.
+    pub fn new(
+        tracks: Vec<T>,
+        mode: TempoMode,
+        position: SeekPosition,
+    ) -> Result<Self, TempoModeError> {
+        let mut cursors: Vec<_> = tracks.into_iter().map(TrackCursor::new).collect();
+        follow_master(&mut cursors, mode)?;
+        let time = seek_cursors(&mut cursors, mode, position);
+        Ok(PreparedSeek { cursors, time })
+    }
+}
+
+/// Has all `cursors` but the master follow its tempo, as set by `mode`,
+/// and returns the map of that tempo.
+fn follow_master<T: EventTrack>(
+    cursors: &mut [TrackCursor<T>],
+    mode: TempoMode,
+) -> Result<Option<Arc<TempoMap>>, TempoModeError> {
+    let master = match mode {
+        TempoMode::Polytempo => None,
+        TempoMode::Master(idx) => {
+            let cursor = cursors.get(idx).ok_or(TempoModeError::NoSuchTrack(idx))?;
+            let map = tempo_map(cursor.data()).map_err(|e| TempoModeError::Map(idx, e))?;
+            Some((idx, Arc::new(map)))
+        }
+    };
+    for (idx, cursor) in cursors.iter_mut().enumerate() {
+        let follows = master
+            .as_ref()
+            .filter(|(master_idx, _)| *master_idx != idx)
+            .map(|(_, map)| Arc::clone(map));
+        cursor.follow_tempo(follows);
+    }
+    Ok(master.map(|(_, map)| map))
+}
+
+/// Moves `cursors` to `position`, measured along the track that leads in
+/// `mode`, and returns the clock time of that position. See
+/// `VecMultiCursor::seek()`.
+fn seek_cursors<T: EventTrack>(
+    cursors: &mut [TrackCursor<T>],
+    mode: TempoMode,
+    position: SeekPosition,
+) -> Duration {
+    let lead = match mode {
+        TempoMode::Master(idx) => idx,
+        TempoMode::Polytempo => 0,
+    };
+    let time = match cursors.get_mut(lead) {
+        Some(cursor) => cursor.seek(position),
+        None => return Duration::default(),
+    };
+    for (idx, cursor) in cursors.iter_mut().enumerate() {
+        if idx != lead {
+            cursor.seek(SeekPosition::Time(time));
+        }
+    }
+    time
+}
+
+/// Converts a time along a cursor's clock to playback time, given a
+/// moment along the clock paired with the same moment in playback time.
+fn to_play_time(offset: (Duration, Duration), time: Duration) -> Duration {
+    time.saturating_sub(offset.0) + offset.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{MidiChannel, MidiNote, NoteOn};
+    use crate::track::{BpmInfo, ClipCommand, ClipControl, TrackEvent, WaitTime};
+    use std::num::NonZeroU16;
+
+    #[test]
+    fn test_merge_order() {
+        let port = OutputPort::from(0);
+        let note = |raw| {
+            MidiMessage::NoteOn(NoteOn::new(
+                MidiChannel::default(),
+                MidiNote::from_raw(raw).unwrap(),
+                PressVelocity::from_raw(90).unwrap(),
+            ))
+        };
+        let send = |raw| TrackEvent::SendMessage {
+            message: note(raw),
+            port,
+        };
+        let beat = TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(1).unwrap()));
+        let half = TrackEvent::Wait(WaitTime::Ticks(NonZeroU16::new(16).unwrap()));
+        let first = vec![send(60), beat, send(62), beat, TrackEvent::End];
+        let second = vec![half, send(64), half, send(65), TrackEvent::End];
+        let mut cursor = VecMultiCursor::new(vec![
+            TrackCursor::new(first),
+            TrackCursor::new(second),
+        ]);
+        let ms = Duration::from_millis;
+
+        // An event left over from a dropped iterator comes out in the next call.
+        assert_eq!(1, cursor.step_until(ms(600)).take(1).count());
+        let merged: Vec<_> = cursor.step_until(ms(600)).collect();
+        assert_eq!(
+            vec![
+                (ms(250), (1, port), note(64)),
+                (ms(500), (0, port), note(62)),
+                (ms(500), (1, port), note(65)),
+            ],
+            merged
+        );
+    }
+
+    #[test]
+    fn test_mix() {
+        let port = OutputPort::from(0);
+        let note = |vel| {
+            MidiMessage::NoteOn(NoteOn::new(
+                MidiChannel::default(),
+                MidiNote::from_raw(60).unwrap(),
+                PressVelocity::from_raw(vel).unwrap(),
+            ))
+        };
+        let track = || {
+            TrackCursor::new(vec![
+                TrackEvent::SendMessage {
+                    message: note(100),
+                    port,
+                },
+                TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(1).unwrap())),
+                TrackEvent::Jump {
+                    target: 0,
+                    count: None,
+                },
+            ])
+        };
+        let mut cursor = VecMultiCursor::new(vec![track(), track(), track()]);
+        let tracks_at = |cursor: &mut VecMultiCursor<_>, time| {
+            cursor
+                .step_until(Duration::from_millis(time))
+                .map(|(_, (idx, _), msg)| (idx, msg))
+                .collect::<Vec<_>>()
+        };
+        let _ = cursor.set_mix(
+            1,
+            TrackMix {
+                muted: true,
+                ..TrackMix::default()
+            },
+        );
+        let _ = cursor.set_mix(
+            2,
+            TrackMix {
+                gain: 0.5,
+                ..TrackMix::default()
+            },
+        );
+        assert_eq!(vec![(0, note(100)), (2, note(50))], tracks_at(&mut cursor, 0));
+
+
+        // Soloing a track silences the others, but they keep their place.
+        let _ = cursor.set_mix(
+            1,
+            TrackMix {
+                solo: true,
+                ..TrackMix::default()
+            },
+        );
+        assert!(!cursor.is_audible(0));
+        assert_eq!(vec![(1, note(100))], tracks_at(&mut cursor, 500));
+        assert_eq!(Duration::from_millis(1000), cursor.cur_clock());
+
+        // A gain of 0 silences the track instead of playing it at the lowest velocity.
+        let _ = cursor.set_mix(1, TrackMix::default());
+        let _ = cursor.set_mix(
+            2,
+            TrackMix {
+                gain: 0.0,
+                ..TrackMix::default()
+            },
+        );
+        assert_eq!(vec![(0, note(100)), (1, note(100))], tracks_at(&mut cursor, 1000));
+    }
+
+    #[test]
+    fn test_master_tempo() {
+        let port = OutputPort::from(0);
+        let note = MidiMessage::NoteOn(NoteOn::new(
+            MidiChannel::default(),
+            MidiNote::from_raw(60).unwrap(),
+            PressVelocity::from_raw(90).unwrap(),
+        ));
+        let send = TrackEvent::SendMessage {
+            message: note,
+            port,
+        };
+        let beats = |count| TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(count).unwrap()));
+        let bpm = |beats_per_minute, ticks_per_beat| {
+            TrackEvent::SetBpm(BpmInfo {
+                beats_per_minute: NonZeroU16::new(beats_per_minute).unwrap(),
+                ticks_per_beat: NonZeroU16::new(ticks_per_beat).unwrap(),
+                ..BpmInfo::default()
+            })
+        };
+        // One beat at 120 BPM, then 60 BPM from half a second in.
+        let master = vec![beats(1), bpm(60, 32), beats(4), TrackEvent::End];
+        // A different tick grid and tempo, and a wait across the tempo change.
+        let follower = vec![bpm(240, 96), beats(2), send, beats(1), send, TrackEvent::End];
+        let mut cursor = VecMultiCursor::new(vec![
+            TrackCursor::new(master.clone()),
+            TrackCursor::new(follower.clone()),
+        ]);
+        let times = |cursor: &mut VecMultiCursor<_>| {
+            cursor
+                .step_until(Duration::from_secs(10))
+                .map(|(time, _, _)| time.as_millis())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![500, 750], times(&mut cursor));
+
+        cursor.set_tempo_mode(TempoMode::Master(0)).unwrap();
+        assert_eq!(vec![1500, 2500], times(&mut cursor));
+        // Bars are counted along the master track.
+        assert_eq!(
+            Duration::from_millis(3500),
+            cursor.seek(SeekPosition::Bar(2))
+        );
+        // Seeks prepared ahead of time land in the same place.
+        let mut prepared =
+            PreparedSeek::new(vec![master, follower], TempoMode::Master(0), SeekPosition::Bar(2))
+                .unwrap();
+        let seeked = cursor.cursors().to_vec();
+        cursor.reset();
+        assert_eq!(Duration::from_millis(3500), cursor.apply_seek(&mut prepared));
+        assert_eq!(seeked, cursor.cursors());
+        assert_eq!(
+            Err(TempoModeError::NoSuchTrack(2)),
+            cursor.set_tempo_mode(TempoMode::Master(2))
+        );
+    }
+
+    #[test]
+    fn test_swap() {
+        let port = OutputPort::from(0);
+        let note = |raw| {
+            NoteOn::new(
+                MidiChannel::default(),
+                MidiNote::from_raw(raw).unwrap(),
+                PressVelocity::from_raw(90).unwrap(),
+            )
+        };
+        let off = |raw| {
+            let vel = PressVelocity::from_raw(0).unwrap();
+            MidiMessage::NoteOff(NoteOff::new(
+                MidiChannel::default(),
+                MidiNote::from_raw(raw).unwrap(),
+                vel,
+            ))
+        };
+        let send = |raw| TrackEvent::SendMessage {
+            message: MidiMessage::NoteOn(note(raw)),
+            port,
+        };
+        let bar = TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(4).unwrap()));
+        let loop_to = |target| TrackEvent::Jump {
+            target,
+            count: None,
+        };
+        let slow = TrackEvent::SetBpm(BpmInfo {
+            beats_per_minute: NonZeroU16::new(60).unwrap(),
+            ..BpmInfo::default()
+        });
+        let old = vec![send(60), bar, loop_to(0)];
+        let new = vec![slow, send(62), bar, loop_to(1)];
+        let mut cursor = VecMultiCursor::new(vec![TrackCursor::new(old.clone())]);
+        let events = |cursor: &mut VecMultiCursor<_>, secs| {
+            cursor
+                .step_until(Duration::from_secs(secs))
+                .map(|(time, _, msg)| (time.as_secs(), msg))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![(0, MidiMessage::NoteOn(note(60)))], events(&mut cursor, 1));
+
+        // Bar 2 is 2 seconds in for the old track, but 4 seconds in for the new one.
+        let swap = TrackSwap::at_bar(old.clone(), new.clone(), None, 2).unwrap();
+        assert_eq!((2, 4), (swap.at.as_secs(), swap.from.as_secs()));
+        assert!(cursor.stage_swap(0, Box::new(swap.clone())).is_ok());
+        assert!(cursor.stage_swap(0, Box::new(swap.clone())).is_err());
+        assert_eq!(
+            vec![
+                (2, off(60)),
+                (2, MidiMessage::NoteOn(note(62))),
+                (2, MidiMessage::NoteOn(note(62))),
+                (6, MidiMessage::NoteOn(note(62))),
+            ],
+            events(&mut cursor, 6)
+        );
+        let retired = cursor.take_retired(0).unwrap();
+        assert!(retired.swapped);
+        assert_eq!(&old, retired.cursor.data());
+
+        // Swaps that come too late are turned down.
+        assert!(cursor.stage_swap(0, Box::new(swap)).is_err());
+    }
+
+    #[test]
+    fn test_clips() {
+        let bars = |bars| Quantize::Bars(NonZeroU16::new(bars).unwrap());
+        assert_eq!(Ok(Quantize::Beat), "beat".parse());
+        assert_eq!(Ok(bars(1)), "Bar".parse());
+        assert_eq!(Ok(bars(4)), "4".parse());
+        assert!("0".parse::<Quantize>().is_err());
+        let control = ClipControl::default();
+        control.stop(Quantize::Beat);
+        control.launch(bars(4));
+        assert_eq!(Some(ClipCommand::Launch(bars(4))), control.take());
+        assert_eq!(None, control.take());
+
+        let port = OutputPort::from(0);
+        let send = |raw| TrackEvent::SendMessage {
+            message: MidiMessage::NoteOn(NoteOn::new(
+                MidiChannel::default(),
+                MidiNote::from_raw(raw).unwrap(),
+                PressVelocity::from_raw(90).unwrap(),
+            )),
+            port,
+        };
+        let beats = |beats| TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(beats).unwrap()));
+        let loop_to = |target| TrackEvent::Jump {
+            target,
+            count: None,
+        };
+        let tracks = vec![
+            TrackCursor::new(vec![send(60), beats(4), loop_to(0)]),
+            TrackCursor::new(vec![send(62), beats(1), TrackEvent::End]),
+        ];
+        let mut cursor = VecMultiCursor::new(tracks);
+        let events = |cursor: &mut VecMultiCursor<_>, millis| {
+            cursor
+                .step_until(Duration::from_millis(millis))
+                .map(|(time, (idx, _), msg)| (idx, time.as_millis(), msg.as_raw().bytes()[1]))
+                .collect::<Vec<_>>()
+        };
+        cursor.start_stopped();
+        assert!(events(&mut cursor, 1000).is_empty());
+
+        // A bar is 2 seconds and a beat half a second at the default tempo.
+        cursor.launch(0, Quantize::default());
+        cursor.launch(1, Quantize::Beat);
+        assert_eq!(Some(ClipState::Queued(Duration::from_secs(2))), cursor.clip_state(0));
+        assert_eq!(vec![(1, 1500, 62), (0, 2000, 60)], events(&mut cursor, 3000));
+        assert_eq!(Some(ClipState::Stopped), cursor.clip_state(1));
+        assert!(cursor.clip_stopped(1));
+        cursor.clear_stopped();
+
+        cursor.set_looping(1, true);
+        cursor.launch(1, Quantize::Beat);
+        assert_eq!(
+            vec![(1, 3500, 62), (0, 4000, 60), (1, 4000, 62), (1, 4500, 62)],
+            events(&mut cursor, 4500)
+        );
+
+        // The bar clip's next note would land right on the stop.
+        cursor.stop(0, Quantize::default());
+        assert_eq!(
+            vec![(1, 5000, 62), (1, 5500, 62), (1, 6000, 62)],
+            events(&mut cursor, 6000)
+        );
+        assert_eq!(Some(ClipState::Stopped), cursor.clip_state(0));
+        assert!(cursor.clip_stopped(0) && !cursor.clip_stopped(1));
+    }
 }
@@ -0,0 +1,366 @@
+use super::{reachable, successors, BpmInfo, CompiledTrack, TrackEvent, WaitTime};
+use std::num::NonZeroU16;
+
+/// Rewrites a compiled track into an equivalent one that the VM can step
+/// through in fewer instructions.
+///
+/// The optimized track emits exactly the same messages at exactly the same
+/// times as the original. The passes are:
+/// * jumps to uncounted jumps are redirected to the final target,
+///   and jumps to the next instruction are dropped;
+/// * unreachable instructions are dropped;
+/// * `SetBpm`s that cannot change the current BPM are dropped;
+/// * `Wait`s in beats are converted to ticks where the BPM is known,
+///   and consecutive tick `Wait`s are merged.
+///
+/// Clock waits are never merged, since each one rounds to ticks separately.
+/// Markers are moved along with the instructions they point to, and a wait
+/// that a marker starts on is never merged into the wait before it, so
+/// markers keep their times.
+pub fn optimize(mut track: CompiledTrack) -> CompiledTrack {
+    loop {
+        let before = track.clone();
+        thread_jumps(&mut track.events);
+        drop_nop_jumps(&mut track);
+        drop_unreachable(&mut track);
+        drop_redundant_bpm(&mut track);
+        merge_waits(&mut track);
+        if track == before {
+            return track;
+        }
+    }
+}
+
+/// Redirects every jump or call whose target is an uncounted jump to that jump's target.
+fn thread_jumps(track: &mut [TrackEvent]) {
+    for idx in 0..track.len() {
+        let mut target = match track[idx] {
+            TrackEvent::Jump { target, .. } | TrackEvent::Call { target } => target,
+            _ => continue,
+        };
+        // Bounded by the track length so that jump cycles can't hang the optimizer.
+        for _ in 0..track.len() {
+            match track.get(target) {
+                Some(TrackEvent::Jump {
+                    target: next,
+                    count: None,
+                }) if *next != target => {
+                    target = *next;
+                }
+                _ => break,
+            }
+        }
+        match &mut track[idx] {
+            TrackEvent::Jump { target: cur, .. } | TrackEvent::Call { target: cur } => {
+                *cur = target;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Drops jumps to the next instruction, since both taking and skipping them
+/// end up in the same place.
+fn drop_nop_jumps(track: &mut CompiledTrack) {
+    let keep: Vec<_> = track
+        .events
+        .iter()
+        .enumerate()
+        .map(|(idx, evt)| match evt {
+            TrackEvent::Jump { target, .. } => *target != idx + 1,
+            _ => true,
+        })
+        .collect();
+    compact(track, &keep);
+}
+
+fn drop_unreachable(track: &mut CompiledTrack) {
+    let keep = reachable(track);
+    compact(track, &keep);
+}
+
+fn drop_redundant_bpm(track: &mut CompiledTrack) {
+    let known = known_bpms(&track.events);
+    let keep: Vec<_> = track
+        .events
+        .iter()
+        .zip(known.iter())
+        .map(|(evt, bpm)| match evt {
+            TrackEvent::SetBpm(new_bpm) => *bpm != Some(*new_bpm),
+            _ => true,
+        })
+        .collect();
+    compact(track, &keep);
+}
+
+fn merge_waits(track: &mut CompiledTrack) {
+    let known = known_bpms(&track.events);
+    for (evt, bpm) in track.events.iter_mut().zip(known.iter()) {
+        if let (TrackEvent::Wait(WaitTime::Beats(beats)), Some(bpm)) = (*evt, bpm) {
+            let ticks = beats
+                .get()
+                .checked_mul(bpm.ticks_per_beat.get())
+                .and_then(NonZeroU16::new);
+            if let Some(ticks) = ticks {
+                *evt = TrackEvent::Wait(WaitTime::Ticks(ticks));
+            }
+        }
+    }
+
+    let mut targets = jump_targets(&track.events);
+    for marker in track.markers.iter() {
+        if let Some(flag) = targets.get_mut(marker.start) {
+            *flag = true;
+        }
+    }
+    let mut keep = vec![true; track.events.len()];
+    let mut prev_wait = None;
+    for idx in 0..track.events.len() {
+        let cur = match track.events[idx] {
+            TrackEvent::Wait(WaitTime::Ticks(ticks)) => ticks,
+            _ => {
+                prev_wait = None;
+                continue;
+            }
+        };
+        let merged = prev_wait
+            .filter(|_| !targets[idx])
+            .and_then(|prev: usize| match track.events[prev] {
+                TrackEvent::Wait(WaitTime::Ticks(prev_ticks)) => {
+                    prev_ticks.get().checked_add(cur.get()).and_then(NonZeroU16::new)
+                }
+                _ => None,
+            });
+        match (prev_wait, merged) {
+            (Some(prev), Some(ticks)) => {
+                track.events[prev] = TrackEvent::Wait(WaitTime::Ticks(ticks));
+                keep[idx] = false;
+            }
+            _ => {
+                prev_wait = Some(idx);
+            }
+        }
+    }
+    compact(track, &keep);
+}
+
+/// Finds the BPM the VM is guaranteed to have when it reaches each instruction,
+/// or `None` if it depends on how the instruction was reached.
+fn known_bpms(track: &[TrackEvent]) -> Vec<Option<BpmInfo>> {
+    // `None` means unvisited, `Some(None)` means the BPM varies.
+    let mut states: Vec<Option<Option<BpmInfo>>> = vec![None; track.len()];
+    let mut worklist = vec![(0, Some(BpmInfo::default()))];
+    while let Some((idx, incoming)) = worklist.pop() {
+        let state = match states.get_mut(idx) {
+            Some(state) => state,
+            None => continue,
+        };
+        let merged = match *state {
+            None => incoming,
+            Some(prev) if prev == incoming => continue,
+            Some(_) => None,
+        };
+        if *state == Some(merged) {
+            continue;
+        }
+        *state = Some(merged);
+        match track[idx] {
+            // The subroutine may change the BPM before it returns.
+            TrackEvent::Call { target } => {
+                worklist.push((target, merged));
+                worklist.push((idx + 1, None));
+            }
+            TrackEvent::SetBpm(bpm) => {
+                worklist.extend(successors(idx, track[idx]).map(|next| (next, Some(bpm))));
+            }
+            evt => {
+                worklist.extend(successors(idx, evt).map(|next| (next, merged)));
+            }
+        }
+    }
+    states.into_iter().map(|state| state.flatten()).collect()
+}
+
+fn jump_targets(track: &[TrackEvent]) -> Vec<bool> {
+    let mut retvl = vec![false; track.len()];
+    for evt in track {
+        if let TrackEvent::Jump { target, .. } | TrackEvent::Call { target } = evt {
+            if let Some(flag) = retvl.get_mut(*target) {
+                *flag = true;
+            }
+        }
+    }
+    retvl
+}
+
+/// Removes every instruction whose `keep` flag is `false`, pointing any jumps,
+/// calls or markers at a removed instruction to the next instruction that was kept.
+fn compact(track: &mut CompiledTrack, keep: &[bool]) {
+    let len = track.events.len();
+    let mut new_idx = vec![0; len + 1];
+    let mut cur = 0;
+    for idx in 0..len {
+        new_idx[idx] = cur;
+        if keep[idx] {
+            cur += 1;
+        }
+    }
+    new_idx[len] = cur;
+
+    let mut idx = 0;
+    track.events.retain(|_| {
+        idx += 1;
+        keep[idx - 1]
+    });
+    for marker in track.markers.iter_mut() {
+        marker.start = new_idx[marker.start.min(len)];
+        marker.end = new_idx[marker.end.min(len)];
+    }
+    for evt in track.events.iter_mut() {
+        if let TrackEvent::Jump { target, .. } | TrackEvent::Call { target } = evt {
+            if let Some(mapped) = new_idx.get(*target) {
+                *target = *mapped;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{MidiChannel, MidiMessage, MidiNote, NoteOff, NoteOn, PressVelocity};
+    use crate::songlang::{compile_song, parse_file};
+    use crate::track::{MarkerKind, OutputPort, TrackCursor};
+    use std::time::Duration;
+
+    fn render(track: CompiledTrack) -> Vec<(Duration, OutputPort, MidiMessage)> {
+        let mut cursor = TrackCursor::new(track);
+        cursor.step_until(Duration::from_secs(60)).collect()
+    }
+
+    fn compile_str(src: &str) -> CompiledTrack {
+        let (rest, song) = parse_file(src).unwrap();
+        assert_eq!("", rest.trim());
+        compile_song(song).unwrap().0
+    }
+
+    #[test]
+    fn test_optimize_songs() {
+        let songs = [
+            // Loops
+            "loop 3 {\nplay for 1 beat C4\nWAIT 2 ticks\n}\nplay for 8 ticks E4\n",
+            // Sections called from an arrangement
+            "section verse {\nplay for 1 beat C4\nWAIT 1 beat\n}\n\
+             section chorus {\nplay for 4 ticks G4, E4\n}\n\
+             arrange verse*2 chorus verse\n",
+            // Grooves
+            "swing 60%\nloop 2 {\nplay for 16 ticks C4\nplay for 16 ticks D4\n}\n\
+             straight\nplay for 16 ticks E4\n",
+            // Tempo changes, including ones that change nothing
+            "play for 1 beat C4\nSETBPM 90, 32\nplay for 1 beat D4\nSETBPM 90, 32\n\
+             WAIT 1 beat\nSETBPM 120, 16, 3\nplay for 1 beat E4\n",
+        ];
+        for src in songs.iter() {
+            let track = compile_str(src);
+            let optimized = optimize(track.clone());
+            assert!(optimized.events.len() <= track.events.len(), "{:?}", src);
+            assert_eq!(render(track), render(optimized), "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn test_optimize_keeps_markers() {
+        let track = compile_str("WAIT 4 ticks\nLABEL middle:\nWAIT 4 ticks\nWAIT 4 ticks\nplay C4\n");
+        let optimized = optimize(track);
+        let label = optimized
+            .markers
+            .iter()
+            .find(|marker| marker.kind == MarkerKind::Label)
+            .unwrap();
+        let ticks = |events: &[TrackEvent]| -> u16 {
+            events
+                .iter()
+                .map(|evt| match evt {
+                    TrackEvent::Wait(WaitTime::Ticks(ticks)) => ticks.get(),
+                    _ => 0,
+                })
+                .sum()
+        };
+        // The waits after the label are still merged.
+        assert_eq!(4, ticks(&optimized.events[..label.start]));
+        assert_eq!(
+            TrackEvent::Wait(WaitTime::Ticks(NonZeroU16::new(8).unwrap())),
+            optimized.events[label.start]
+        );
+    }
+
+    #[test]
+    fn test_optimize() {
+        let port = OutputPort::from(0);
+        let note = MidiNote::from_raw(60).unwrap();
+        let vel = PressVelocity::from_raw(90).unwrap();
+        let on = TrackEvent::SendMessage {
+            message: NoteOn::new(MidiChannel::default(), note, vel).into(),
+            port,
+        };
+        let off = TrackEvent::SendMessage {
+            message: NoteOff::new(MidiChannel::default(), note, vel).into(),
+            port,
+        };
+        let ticks = |n| TrackEvent::Wait(WaitTime::Ticks(NonZeroU16::new(n).unwrap()));
+        let fast = BpmInfo {
+            beats_per_minute: NonZeroU16::new(180).unwrap(),
+            ..BpmInfo::default()
+        };
+
+        let track = vec![
+            TrackEvent::SetBpm(BpmInfo::default()),
+            on,
+            ticks(8),
+            TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(1).unwrap())),
+            off,
+            TrackEvent::Jump {
+                target: 7,
+                count: None,
+            },
+            ticks(3),
+            TrackEvent::Jump {
+                target: 9,
+                count: None,
+            },
+            TrackEvent::SetBpm(BpmInfo::default()),
+            TrackEvent::SetBpm(fast),
+            on,
+            ticks(16),
+            off,
+            ticks(16),
+            TrackEvent::SetBpm(fast),
+            TrackEvent::Jump {
+                target: 9,
+                count: NonZeroU16::new(3),
+            },
+            TrackEvent::End,
+        ];
+        let track = CompiledTrack::from(track);
+        let optimized = optimize(track.clone());
+        assert_eq!(
+            vec![
+                on,
+                ticks(40),
+                off,
+                TrackEvent::SetBpm(fast),
+                on,
+                ticks(16),
+                off,
+                ticks(16),
+                TrackEvent::Jump {
+                    target: 3,
+                    count: NonZeroU16::new(3),
+                },
+                TrackEvent::End,
+            ],
+            optimized.events
+        );
+        assert_eq!(render(track), render(optimized));
+    }
+}
@@ -0,0 +1,260 @@
+use super::OutputPort;
+use crate::midi::{MidiMessage, NoteOn, RawMessage};
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::*;
+
+/// A point in a track that a cursor can seek to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SeekPosition {
+    /// A clock time since the start of the track.
+    Time(Duration),
+    /// A number of beat "ticks" since the start of the track.
+    Ticks(u64),
+    /// The start of a bar. Bars are counted from 1, following the
+    /// time signature set by each SET BPM command along the way.
+    Bar(u32),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+#[error("Invalid seek position {0:?}: expected a bar number, ticks like `96t`, or a time like `1:30`, `12.5s` or `250ms`.")]
+pub struct SeekParseError(String);
+
+impl FromStr for SeekPosition {
+    type Err = SeekParseError;
+
+    /// Parses a bar number (`64`), a tick count (`96t`), or a clock time
+    /// (`1:30.5`, `12.5s`, `250ms`).
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let trimmed = raw.trim();
+        let err = || SeekParseError(trimmed.to_owned());
+        let secs = |raw: &str| {
+            f64::from_str(raw)
+                .ok()
+                .filter(|secs| secs.is_finite() && *secs >= 0.0)
+                .map(Duration::from_secs_f64)
+                .ok_or_else(err)
+        };
+        if let Some(ticks) = trimmed.strip_suffix('t') {
+            u64::from_str(ticks).map(SeekPosition::Ticks).map_err(|_| err())
+        } else if let Some(millis) = trimmed.strip_suffix("ms") {
+            u64::from_str(millis)
+                .map(|millis| SeekPosition::Time(Duration::from_millis(millis)))
+                .map_err(|_| err())
+        } else if let Some(seconds) = trimmed.strip_suffix('s') {
+            secs(seconds).map(SeekPosition::Time)
+        } else if let Some((minutes, seconds)) = trimmed.split_once(':') {
+            let minutes = u64::from_str(minutes).map_err(|_| err())?;
+            let seconds = secs(seconds)?;
+            Ok(SeekPosition::Time(Duration::from_secs(minutes * 60) + seconds))
+        } else {
+            u32::from_str(trimmed).map(SeekPosition::Bar).map_err(|_| err())
+        }
+    }
+}
+
+const CONTROL_CHANGE: u8 = 0xB0;
+const PROGRAM_CHANGE: u8 = 0xC0;
+const CHANNEL_PRESSURE: u8 = 0xD0;
+const PITCH_BEND: u8 = 0xE0;
+
+const ALL_SOUND_OFF: u8 = 120;
+const RESET_CONTROLLERS: u8 = 121;
+const ALL_NOTES_OFF: u8 = 123;
+
+/// The state a track has built up on its outputs at some point in playback:
+/// the notes that are sounding and the latest controller, program, pressure
+/// and pitch bend values on each channel.
+///
+/// Used to "chase" that state when playback starts partway through a track.
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct ChaseState {
+    notes: Vec<(OutputPort, NoteOn)>,
+    programs: Vec<(OutputPort, RawMessage)>,
+    controls: Vec<(OutputPort, RawMessage)>,
+}
+
+impl ChaseState {
+    /// Updates the state with a message sent to `port`.
+    pub fn record(&mut self, port: OutputPort, msg: MidiMessage) {
+        match msg {
+            MidiMessage::NoteOn(data) if data.vel().as_u8() > 0 => {
+                self.release(port, msg);
+                self.notes.push((port, data));
+            }
+            MidiMessage::NoteOn(_) | MidiMessage::NoteOff(_) => self.release(port, msg),
+            MidiMessage::Other(raw) => self.record_raw(port, raw),
+        }
+    }
+
+    fn release(&mut self, port: OutputPort, msg: MidiMessage) {
+        let key = msg.as_raw();
+        let (channel, note) = (key.bytes()[0] & 0x0F, key.bytes()[1]);
+        self.notes.retain(|(cur_port, cur)| {
+            *cur_port != port || cur.channel().as_u8() != channel || cur.note().as_u8() != note
+        });
+    }
+
+    fn record_raw(&mut self, port: OutputPort, raw: RawMessage) {
+        let bytes = raw.bytes();
+        let (status, channel) = match bytes.first() {
+            Some(status) => (status & 0xF0, status & 0x0F),
+            None => return,
+        };
+        let same_slot = |(cur_port, cur): &(OutputPort, RawMessage)| {
+            *cur_port == port
+                && cur.bytes()[0] == bytes[0]
+                && (status != CONTROL_CHANGE || cur.bytes().get(1) == bytes.get(1))
+        };
+        match status {
+            CONTROL_CHANGE => match bytes.get(1).copied() {
+                Some(ALL_SOUND_OFF) | Some(ALL_NOTES_OFF) => self.notes.retain(|(cur_port, cur)| {
+                    *cur_port != port || cur.channel().as_u8() != channel
+                }),
+                Some(RESET_CONTROLLERS) => self.controls.retain(|(cur_port, cur)| {
+                    *cur_port != port || cur.bytes()[0] & 0x0F != channel
+                }),
+                // The remaining channel mode messages change how the synth
+                // listens rather than what it plays, so they are not chased.
+                Some(controller) if controller >= ALL_SOUND_OFF => {}
+                Some(_) => {
+                    self.controls.retain(|cur| !same_slot(cur));
+                    self.controls.push((port, raw));
+                }
+                None => {}
+            },
+            CHANNEL_PRESSURE | PITCH_BEND => {
+                self.controls.retain(|cur| !same_slot(cur));
+                self.controls.push((port, raw));
+            }
+            PROGRAM_CHANGE => {
+                self.programs.retain(|cur| !same_slot(cur));
+                self.programs.push((port, raw));
+            }
+            _ => {}
+        }
+    }
+
+    /// Gets the messages that recreate this state from a blank slate.
+    /// Program changes come first, since synths may reset their controllers
+    /// on a program change, and notes come last so they start with the
+    /// right sound.
+    pub fn into_messages(self) -> Vec<(OutputPort, MidiMessage)> {
+        let raw_messages = self.programs.into_iter().chain(self.controls);
+        raw_messages
+            .map(|(port, raw)| (port, MidiMessage::Other(raw)))
+            .chain(
+                self.notes
+                    .into_iter()
+                    .map(|(port, data)| (port, MidiMessage::NoteOn(data))),
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{MidiChannel, MidiNote, NoteOff, PressVelocity};
+    use crate::track::{BpmInfo, TrackCursor, TrackEvent, WaitTime};
+    use std::num::NonZeroU16;
+
+    #[test]
+    fn test_seek() {
+        let port = OutputPort::from(0);
+        let note = |raw, vel| {
+            MidiMessage::NoteOn(NoteOn::new(
+                MidiChannel::default(),
+                MidiNote::from_raw(raw).unwrap(),
+                PressVelocity::from_raw(vel).unwrap(),
+            ))
+        };
+        let off = |raw| {
+            MidiMessage::NoteOff(NoteOff::new(
+                MidiChannel::default(),
+                MidiNote::from_raw(raw).unwrap(),
+                PressVelocity::from_raw(0).unwrap(),
+            ))
+        };
+        let send = |message| TrackEvent::SendMessage { message, port };
+        let program = MidiMessage::Other(RawMessage::from_raw(&[PROGRAM_CHANGE, 5]));
+        let volume = |value| MidiMessage::Other(RawMessage::from_raw(&[CONTROL_CHANGE, 7, value]));
+        let bar = TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(4).unwrap()));
+        let track = vec![
+            send(program),
+            send(volume(100)),
+            send(note(60, 90)),
+            send(note(64, 90)),
+            bar,
+            send(off(60)),
+            send(volume(80)),
+            TrackEvent::SetBpm(BpmInfo {
+                beats_per_minute: NonZeroU16::new(60).unwrap(),
+                ..BpmInfo::default()
+            }),
+            bar,
+            send(off(64)),
+            send(note(67, 90)),
+            bar,
+            TrackEvent::End,
+        ];
+        let mut cursor = TrackCursor::new(track);
+
+        // Bar 2 starts after 2 seconds at 120 BPM, before anything in it has run.
+        let time = cursor.seek(SeekPosition::Bar(2));
+        assert_eq!(Duration::from_secs(2), time);
+        let chased: Vec<_> = cursor.step_until(time).collect();
+        assert_eq!(
+            vec![
+                (time, port, program),
+                (time, port, volume(100)),
+                (time, port, note(60, 90)),
+                (time, port, note(64, 90)),
+                (time, port, off(60)),
+                (time, port, volume(80)),
+            ],
+            chased
+        );
+
+        // Halfway through bar 2 the cursor jumps past the wait and picks up
+        // on bar 3, at 60 BPM.
+        let time = cursor.seek(SeekPosition::Ticks(192));
+        assert_eq!(Duration::from_secs(4), time);
+        assert_eq!(Duration::from_secs(6), cursor.cur_clock());
+        let chased: Vec<_> = cursor.step_until(Duration::from_secs(6)).collect();
+        assert_eq!(
+            vec![
+                (time, port, program),
+                (time, port, volume(80)),
+                (time, port, note(64, 90)),
+                (Duration::from_secs(6), port, off(64)),
+                (Duration::from_secs(6), port, note(67, 90)),
+            ],
+            chased
+        );
+
+        assert_eq!(
+            cursor.seek(SeekPosition::Bar(3)),
+            cursor.seek(SeekPosition::Time(Duration::from_secs(6)))
+        );
+        let position = cursor.position();
+        assert_eq!((3, 1, 0), (position.bar, position.beat, position.tick));
+        cursor.seek(SeekPosition::Ticks(200));
+        assert_eq!("3:1:00", cursor.position().to_string());
+        assert_eq!(256, cursor.cur_ticks());
+
+        // Tick counts go well past a `u16`.
+        let long_wait = TrackEvent::Wait(WaitTime::Ticks(NonZeroU16::new(u16::MAX).unwrap()));
+        let mut cursor = TrackCursor::new(vec![long_wait, long_wait, TrackEvent::End]);
+        cursor.step_until(Duration::from_secs(60 * 60)).count();
+        assert_eq!(2 * u64::from(u16::MAX), cursor.cur_ticks());
+        let position = cursor.position();
+        assert_eq!((1024, 4, 30), (position.bar, position.beat, position.tick));
+        assert_eq!(
+            Ok(SeekPosition::Time(Duration::from_millis(90_500))),
+            SeekPosition::from_str("1:30.5")
+        );
+        assert_eq!(Ok(SeekPosition::Ticks(96)), SeekPosition::from_str("96t"));
+        assert!(SeekPosition::from_str("bar").is_err());
+    }
+}
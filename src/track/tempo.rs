@@ -0,0 +1,656 @@
+use super::{
+    BpmInfo, EventTrack, Marker, MarkerKind, StepError, StepOutput, TrackCursor, TrackEvent,
+    WaitTime,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::Duration;
+use thiserror::*;
+
+/// The maximum number of instructions `tempo_map` runs before giving up.
+pub const TEMPO_MAP_BUDGET: usize = 1 << 24;
+
+/// The number of parts `TempoMap` splits each beat into when lining up
+/// tracks with different `ticks_per_beat` values. Every tick grid up to 16
+/// ticks per beat divides it evenly, as do 24, 32, 96, 384, 480 and 960.
+pub const BEAT_DIVISIONS: u128 = 5_765_760;
+
+/// Converts a number of ticks into `BEAT_DIVISIONS`ths of a beat.
+pub fn beat_divisions(ticks: u64, bpm: BpmInfo) -> u128 {
+    u128::from(ticks) * BEAT_DIVISIONS / u128::from(bpm.ticks_per_beat.get())
+}
+
+/// Errors that may occur when mapping out a track's tempo.
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+pub enum TempoMapError {
+    #[error(transparent)]
+    Step(#[from] StepError),
+    #[error("The track loops forever at instruction {0} without its clock moving.")]
+    Stalled(usize),
+    #[error("Ran {0} instructions without reaching the end of the track or finding where it repeats.")]
+    TooLong(usize),
+}
+
+/// How long a track plays for.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SongLength {
+    /// The track reaches an `End` instruction after this long.
+    Finite(Duration),
+    /// The track never ends: everything from `loop_start` on repeats every `period`.
+    Infinite { loop_start: Duration, period: Duration },
+}
+
+/// Formats a duration as `m:ss.mmm`.
+fn fmt_duration(f: &mut fmt::Formatter<'_>, time: Duration) -> fmt::Result {
+    let secs = time.as_secs();
+    write!(f, "{}:{:02}.{:03}", secs / 60, secs % 60, time.subsec_millis())
+}
+
+impl fmt::Display for SongLength {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SongLength::Finite(length) => {
+                fmt_duration(f, length)?;
+                f.write_str(" long")
+            }
+            SongLength::Infinite { loop_start, period } => {
+                f.write_str("loops forever, repeating every ")?;
+                fmt_duration(f, period)?;
+                f.write_str(" from ")?;
+                fmt_duration(f, loop_start)
+            }
+        }
+    }
+}
+
+/// A stretch of a track over which ticks map evenly to clock time.
+///
+/// A new segment starts at every SET BPM command and after every wait
+/// measured in clock time, the same as in `TrackCursor`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct TempoSegment {
+    pub start_time: Duration,
+    pub start_tick: u64,
+    /// The beat the segment starts on, in `BEAT_DIVISIONS`ths of a beat.
+    /// This differs from the start tick when `ticks_per_beat` changes.
+    pub start_beat: u128,
+    /// The bar the segment starts in, counted from 0.
+    pub bar: u64,
+    /// The tick that `bar` started on, which may be before the segment.
+    pub bar_start: u64,
+    pub bpm: BpmInfo,
+}
+
+impl TempoSegment {
+    fn time_at(&self, tick: u64) -> Duration {
+        self.start_time + self.bpm.ticks_duration(tick - self.start_tick)
+    }
+
+    /// Gets the last tick that starts at or before `time`.
+    fn tick_at(&self, time: Duration) -> u64 {
+        let into_segment = time - self.start_time;
+        let mut ticks = self.bpm.duration_ticks(into_segment);
+        // `ticks_duration()` rounds down, so the next tick may start a hair early.
+        if self.bpm.ticks_duration(ticks + 1) <= into_segment {
+            ticks += 1;
+        }
+        self.start_tick + ticks
+    }
+
+    fn beat_at(&self, tick: u64) -> u128 {
+        self.start_beat + beat_divisions(tick - self.start_tick, self.bpm)
+    }
+
+    fn time_at_beat(&self, beat: u128) -> Duration {
+        self.start_time + self.bpm.beats_duration(beat - self.start_beat, BEAT_DIVISIONS)
+    }
+
+    /// Gets the last beat division that starts at or before `time`.
+    fn beat_at_time(&self, time: Duration) -> u128 {
+        let into_segment = time - self.start_time;
+        let mut beat = self.bpm.duration_beats(into_segment, BEAT_DIVISIONS);
+        // `beats_duration()` rounds down, so the next division may start a hair early.
+        if self.bpm.beats_duration(beat + 1, BEAT_DIVISIONS) <= into_segment {
+            beat += 1;
+        }
+        self.start_beat + beat
+    }
+
+    fn bar_at(&self, tick: u64) -> u64 {
+        self.bar + (tick - self.bar_start) / self.bpm.ticks_per_bar()
+    }
+
+    fn bar_tick(&self, bar: u64) -> u64 {
+        self.bar_start + (bar - self.bar) * self.bpm.ticks_per_bar()
+    }
+}
+
+/// One play of a song section.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct SectionTiming {
+    pub name: String,
+    pub start: Duration,
+    pub end: Duration,
+    pub start_tick: u64,
+    pub end_tick: u64,
+}
+
+/// Where an infinite track starts repeating itself, and how far apart the
+/// repeats are in time, ticks and bars.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct Repeat {
+    start_time: Duration,
+    start_tick: u64,
+    start_beat: u128,
+    start_bar: u64,
+    period_time: Duration,
+    period_ticks: u64,
+    period_beats: u128,
+    period_bars: u64,
+}
+
+/// Splits `value` into a value in `[start, start + period)` plus a number
+/// of whole periods.
+fn fold(value: u128, start: u128, period: u128) -> (u128, u128) {
+    let periods = (value - start) / period;
+    (value - periods * period, periods)
+}
+
+fn repeat_duration(period: Duration, count: u128) -> Duration {
+    let nanos = period.as_nanos() * count;
+    Duration::from_nanos(nanos.min(u128::from(u64::MAX)) as u64)
+}
+
+/// The tempo changes of a track, along with when each section is played.
+///
+/// Built by `tempo_map()`, this converts between clock time, ticks and bars
+/// without having to run a `TrackCursor` up to the point in question.
+/// Bars are counted from 1, as in `SeekPosition::Bar`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TempoMap {
+    segments: Vec<TempoSegment>,
+    /// Where the mapped part of the track ends: at the `End` instruction for
+    /// finite tracks, or one period after the repeat starts for infinite ones.
+    end_time: Duration,
+    end_tick: u64,
+    end_beat: u128,
+    repeat: Option<Repeat>,
+    sections: Vec<SectionTiming>,
+}
+
+#[allow(dead_code)]
+impl TempoMap {
+    pub fn length(&self) -> SongLength {
+        match self.repeat {
+            None => SongLength::Finite(self.end_time),
+            Some(repeat) => SongLength::Infinite {
+                loop_start: repeat.start_time,
+                period: repeat.period_time,
+            },
+        }
+    }
+
+    /// Gets the tempo segments of the mapped part of the track.
+    pub fn segments(&self) -> &[TempoSegment] {
+        &self.segments
+    }
+
+    /// Gets each play of each section, in the order they start. For infinite
+    /// tracks, only the plays that end before the first repeat are included.
+    pub fn sections(&self) -> &[SectionTiming] {
+        &self.sections
+    }
+
+    /// Gets the clock time of a tick, or `None` if it is past the end of the track.
+    pub fn time_at_tick(&self, tick: u64) -> Option<Duration> {
+        match self.repeat {
+            Some(repeat) if tick > self.end_tick => {
+                let (tick, periods) = fold(
+                    tick.into(),
+                    repeat.start_tick.into(),
+                    repeat.period_ticks.into(),
+                );
+                let time = self.mapped_time_at(tick as u64);
+                Some(time + repeat_duration(repeat.period_time, periods))
+            }
+            _ if tick > self.end_tick => None,
+            _ => Some(self.mapped_time_at(tick)),
+        }
+    }
+
+    /// Gets the clock time of a beat, given in `BEAT_DIVISIONS`ths of a beat.
+    ///
+    /// Unlike the other conversions this never fails: past the end of a
+    /// finite track, the last tempo holds forever.
+    pub fn time_at_beat(&self, beat: u128) -> Duration {
+        let (beat, periods) = match self.repeat {
+            Some(repeat) if beat > self.end_beat => {
+                fold(beat, repeat.start_beat, repeat.period_beats)
+            }
+            _ => (beat, 0),
+        };
+        let idx = self
+            .segments
+            .partition_point(|seg| seg.start_beat <= beat)
+            .saturating_sub(1);
+        let time = self.segments[idx].time_at_beat(beat);
+        let time = match self.segments.get(idx + 1) {
+            Some(next) => time.min(next.start_time),
+            None => time,
+        };
+        match self.repeat {
+            Some(repeat) => time + repeat_duration(repeat.period_time, periods),
+            None => time,
+        }
+    }
+
+    /// Gets the beat, in `BEAT_DIVISIONS`ths of a beat, that is playing at
+    /// `time`. The reverse of `time_at_beat()`, and likewise never fails.
+    pub fn beat_at_time(&self, time: Duration) -> u128 {
+        let (time, periods, period_beats) = match self.repeat {
+            Some(repeat) if time > self.end_time && repeat.period_time > Duration::default() => {
+                let (time, periods) = fold(
+                    time.as_nanos(),
+                    repeat.start_time.as_nanos(),
+                    repeat.period_time.as_nanos(),
+                );
+                (Duration::from_nanos(time as u64), periods, repeat.period_beats)
+            }
+            _ => (time, 0, 0),
+        };
+        let idx = self
+            .segments
+            .partition_point(|seg| seg.start_time <= time)
+            .saturating_sub(1);
+        let beat = self.segments[idx].beat_at_time(time);
+        let beat = match self.segments.get(idx + 1) {
+            Some(next) => beat.min(next.start_beat),
+            None => beat,
+        };
+        beat + periods * period_beats
+    }
+
+    /// Gets the last tick that starts at or before `time`, or `None` if
+    /// `time` is past the end of the track.
+    pub fn tick_at_time(&self, time: Duration) -> Option<u64> {
+        match self.repeat {
+            Some(repeat) if time > self.end_time && repeat.period_time > Duration::default() => {
+                let (time, periods) = fold(
+                    time.as_nanos(),
+                    repeat.start_time.as_nanos(),
+                    repeat.period_time.as_nanos(),
+                );
+                let tick = self.mapped_tick_at(Duration::from_nanos(time as u64));
+                Some(tick + repeat.period_ticks * periods as u64)
+            }
+            _ if time > self.end_time => None,
+            _ => Some(self.mapped_tick_at(time)),
+        }
+    }
+
+    /// Gets the bar a tick falls in, or `None` if it is past the end of the track.
+    pub fn bar_at_tick(&self, tick: u64) -> Option<u64> {
+        match self.repeat {
+            Some(repeat) if tick > self.end_tick => {
+                let (tick, periods) = fold(
+                    tick.into(),
+                    repeat.start_tick.into(),
+                    repeat.period_ticks.into(),
+                );
+                let bar = self.segment_at_tick(tick as u64).bar_at(tick as u64);
+                Some(bar + repeat.period_bars * periods as u64 + 1)
+            }
+            _ if tick > self.end_tick => None,
+            _ => Some(self.segment_at_tick(tick).bar_at(tick) + 1),
+        }
+    }
+
+    /// Gets the tick a bar starts on, or `None` if it starts past the end of the track.
+    pub fn tick_of_bar(&self, bar: u64) -> Option<u64> {
+        let bar = bar.saturating_sub(1);
+        match self.repeat {
+            Some(repeat) if bar >= repeat.start_bar + repeat.period_bars => {
+                let (bar, periods) = fold(
+                    bar.into(),
+                    repeat.start_bar.into(),
+                    repeat.period_bars.into(),
+                );
+                let tick = self.mapped_bar_tick(bar as u64)?;
+                Some(tick + repeat.period_ticks * periods as u64)
+            }
+            _ => self.mapped_bar_tick(bar).filter(|tick| *tick <= self.end_tick),
+        }
+    }
+
+    /// Gets the clock time a bar starts at, or `None` if it starts past the end of the track.
+    pub fn time_of_bar(&self, bar: u64) -> Option<Duration> {
+        self.tick_of_bar(bar)
+            .and_then(|tick| self.time_at_tick(tick))
+    }
+
+    fn segment_at_tick(&self, tick: u64) -> &TempoSegment {
+        let idx = self.segments.partition_point(|seg| seg.start_tick <= tick);
+        &self.segments[idx.saturating_sub(1)]
+    }
+
+    fn mapped_time_at(&self, tick: u64) -> Duration {
+        let idx = self
+            .segments
+            .partition_point(|seg| seg.start_tick <= tick)
+            .saturating_sub(1);
+        let limit = self
+            .segments
+            .get(idx + 1)
+            .map_or(self.end_time, |next| next.start_time);
+        self.segments[idx].time_at(tick).min(limit)
+    }
+
+    fn mapped_tick_at(&self, time: Duration) -> u64 {
+        let idx = self
+            .segments
+            .partition_point(|seg| seg.start_time <= time)
+            .saturating_sub(1);
+        let limit = self
+            .segments
+            .get(idx + 1)
+            .map_or(self.end_tick, |next| next.start_tick);
+        self.segments[idx].tick_at(time).min(limit)
+    }
+
+    /// Gets the tick a bar, counted from 0, starts on within the mapped segments.
+    fn mapped_bar_tick(&self, bar: u64) -> Option<u64> {
+        // A segment's first bar may have started in an earlier segment.
+        let candidates = self.segments.partition_point(|seg| seg.bar <= bar);
+        self.segments[..candidates].iter().rev().find_map(|seg| {
+            let tick = seg.bar_tick(bar);
+            if tick >= seg.start_tick {
+                Some(tick)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// The VM state at an uncounted jump. If a track reaches the same state
+/// twice, it will repeat what it did in between forever.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct LoopState {
+    instruction_pointer: usize,
+    return_addrs: Vec<usize>,
+    jump_counts: BTreeMap<usize, u16>,
+    bpm: BpmInfo,
+    /// How far into the current bar the track is.
+    bar_phase: u64,
+    clock_remainder: Duration,
+}
+
+/// Where the VM was when it first reached a `LoopState`.
+#[derive(Debug, Copy, Clone)]
+struct LoopVisit {
+    time: Duration,
+    tick: u64,
+    beat: u128,
+    bar: u64,
+}
+
+/// A section the VM is currently playing, started by the `Call` that
+/// pushed the matching call stack entry.
+#[derive(Debug, Copy, Clone)]
+struct OpenSection {
+    marker: usize,
+    start: Duration,
+    start_tick: u64,
+}
+
+/// Starts a new segment at `segment.start_tick`, filling in the beat it
+/// starts on from the current one.
+fn push_segment(segments: &mut Vec<TempoSegment>, mut segment: TempoSegment) {
+    if let Some(last) = segments.last() {
+        segment.start_beat = last.beat_at(segment.start_tick);
+    }
+    match segments.last_mut() {
+        Some(last) if last.start_tick == segment.start_tick && last.start_time == segment.start_time => {
+            *last = segment;
+        }
+        _ => segments.push(segment),
+    }
+}
+
+/// Lets a `TrackCursor` step through a track it does not own.
+struct Borrowed<'a, T>(&'a T);
+
+impl<T: EventTrack> EventTrack for Borrowed<'_, T> {
+    fn get(&self, instruction_idx: usize) -> Option<TrackEvent> {
+        self.0.get(instruction_idx)
+    }
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+    fn finite_jumps(&self) -> Vec<(usize, u16)> {
+        self.0.finite_jumps()
+    }
+    fn markers(&self) -> &[Marker] {
+        self.0.markers()
+    }
+}
+
+/// Gets the segment a cursor is in, right after it started a new one.
+fn cursor_segment<T: EventTrack>(cursor: &TrackCursor<T>) -> TempoSegment {
+    let (bar, bar_start) = cursor.bar();
+    TempoSegment {
+        start_time: cursor.cur_clock(),
+        start_tick: cursor.cur_ticks(),
+        start_beat: 0,
+        bar,
+        bar_start,
+        bpm: cursor.bpm(),
+    }
+}
+
+/// Maps out the tempo of a track by following its control flow, including
+/// jumps, finite loop counts and subroutine calls, without emitting anything.
+///
+/// The track is run by a `TrackCursor`, so the map always agrees with playback.
+/// Infinite tracks are mapped up to the point where the VM is seen to
+/// repeat itself; conversions past that point follow the repeat.
+pub fn tempo_map(track: &impl EventTrack) -> Result<TempoMap, TempoMapError> {
+    if track.len() == 0 {
+        return Err(StepError::BadInstrPointer(0).into());
+    }
+    let markers = track.markers();
+    let mut cursor = TrackCursor::new(Borrowed(track));
+    // The section started by each call on the cursor's call stack, if any.
+    let mut open_sections: Vec<Option<OpenSection>> = Vec::new();
+    let mut seen: HashMap<LoopState, LoopVisit> = HashMap::new();
+    let mut sections = Vec::new();
+    let mut segments = vec![cursor_segment(&cursor)];
+
+    for _ in 0..TEMPO_MAP_BUDGET {
+        let instruction_pointer = cursor.pc();
+        let evt = track
+            .get(instruction_pointer)
+            .ok_or(StepError::BadInstrPointer(instruction_pointer))?;
+        let time = cursor.cur_clock();
+        let tick = cursor.cur_ticks();
+        let (bar, bar_start) = cursor.bar();
+        let mut repeat = None;
+        if let TrackEvent::Jump { count: None, .. } = evt {
+            let state = LoopState {
+                instruction_pointer,
+                return_addrs: cursor.return_addrs().to_vec(),
+                jump_counts: cursor.jump_counts(),
+                bpm: cursor.bpm(),
+                bar_phase: tick - bar_start,
+                clock_remainder: cursor.clock_remainder(),
+            };
+            let beat = segments[segments.len() - 1].beat_at(tick);
+            match seen.get(&state) {
+                Some(visit) if visit.tick == tick => {
+                    return Err(TempoMapError::Stalled(instruction_pointer));
+                }
+                Some(visit) => {
+                    repeat = Some(Repeat {
+                        start_time: visit.time,
+                        start_tick: visit.tick,
+                        start_beat: visit.beat,
+                        start_bar: visit.bar,
+                        period_time: time - visit.time,
+                        period_ticks: tick - visit.tick,
+                        period_beats: beat - visit.beat,
+                        period_bars: bar - visit.bar,
+                    });
+                }
+                None => {
+                    seen.insert(
+                        state,
+                        LoopVisit {
+                            time,
+                            tick,
+                            beat,
+                            bar,
+                        },
+                    );
+                }
+            }
+        }
+        let bpm = cursor.bpm();
+        if repeat.is_none() && cursor.step()? != StepOutput::End {
+            match evt {
+                TrackEvent::SetBpm(new_bpm) if new_bpm != bpm => {
+                    push_segment(&mut segments, cursor_segment(&cursor));
+                }
+                TrackEvent::Wait(WaitTime::Clock(_)) => {
+                    push_segment(&mut segments, cursor_segment(&cursor));
+                }
+                TrackEvent::Call { target } => {
+                    let section = markers
+                        .iter()
+                        .position(|marker| marker.kind == MarkerKind::Section && marker.start == target)
+                        .map(|marker| OpenSection {
+                            marker,
+                            start: time,
+                            start_tick: tick,
+                        });
+                    open_sections.push(section);
+                }
+                TrackEvent::Return => {
+                    if let Some(Some(section)) = open_sections.pop() {
+                        sections.push(SectionTiming {
+                            name: markers[section.marker].name.clone(),
+                            start: section.start,
+                            end: cursor.cur_clock(),
+                            start_tick: section.start_tick,
+                            end_tick: cursor.cur_ticks(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        // Nested sections end before the ones around them, but start after.
+        sections.sort_by_key(|section| section.start_tick);
+        return Ok(TempoMap {
+            end_beat: segments[segments.len() - 1].beat_at(tick),
+            segments,
+            end_time: time,
+            end_tick: tick,
+            repeat,
+            sections,
+        });
+    }
+    Err(TempoMapError::TooLong(TEMPO_MAP_BUDGET))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::CompiledTrack;
+    use std::num::NonZeroU16;
+
+    #[test]
+    fn test_tempo_map() {
+        let bar = TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(4).unwrap()));
+        let slow = BpmInfo {
+            beats_per_minute: NonZeroU16::new(60).unwrap(),
+            ..BpmInfo::default()
+        };
+        let track = CompiledTrack {
+            events: vec![
+                TrackEvent::SetBpm(BpmInfo::default()),
+                TrackEvent::Call { target: 4 },
+                TrackEvent::Call { target: 4 },
+                TrackEvent::End,
+                bar,
+                TrackEvent::SetBpm(slow),
+                bar,
+                TrackEvent::SetBpm(BpmInfo::default()),
+                TrackEvent::Return,
+            ],
+            markers: vec![Marker {
+                name: "verse".to_owned(),
+                kind: MarkerKind::Section,
+                start: 4,
+                end: 9,
+            }],
+        };
+        let map = tempo_map(&track).unwrap();
+        let secs = Duration::from_secs;
+        assert_eq!(SongLength::Finite(secs(12)), map.length());
+        assert_eq!(
+            vec![(secs(0), secs(6)), (secs(6), secs(12))],
+            map.sections()
+                .iter()
+                .map(|section| (section.start, section.end))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(secs(4)), map.time_at_tick(192));
+        assert_eq!(Some(224), map.tick_at_time(secs(5)));
+        assert_eq!(Some(3), map.bar_at_tick(300));
+        assert_eq!(Some(secs(8)), map.time_of_bar(4));
+        assert_eq!(Some(secs(12)), map.time_of_bar(5));
+        assert_eq!(None, map.time_of_bar(6));
+        assert_eq!(None, map.time_at_tick(513));
+
+        let looping = vec![
+            TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(1).unwrap())),
+            bar,
+            TrackEvent::Jump {
+                target: 1,
+                count: None,
+            },
+            TrackEvent::End,
+        ];
+        let map = tempo_map(&looping).unwrap();
+        assert_eq!(
+            SongLength::Infinite {
+                loop_start: Duration::from_millis(2500),
+                period: secs(2),
+            },
+            map.length()
+        );
+        assert_eq!(Some(secs(18)), map.time_of_bar(10));
+        assert_eq!(Some(10), map.bar_at_tick(1152));
+        assert_eq!(Some(1152), map.tick_at_time(secs(18)));
+        assert_eq!(Some(Duration::from_millis(18_500)), map.time_at_tick(1184));
+
+        let stalled = vec![
+            TrackEvent::SetBpm(BpmInfo::default()),
+            TrackEvent::Jump {
+                target: 0,
+                count: None,
+            },
+        ];
+        assert_eq!(Err(TempoMapError::Stalled(1)), tempo_map(&stalled));
+
+        // 3000 beats at 32 ticks per beat do not fit in a `u16`.
+        let long = vec![
+            TrackEvent::Wait(WaitTime::Beats(NonZeroU16::new(3000).unwrap())),
+            TrackEvent::End,
+        ];
+        let map = tempo_map(&long).unwrap();
+        assert_eq!(SongLength::Finite(secs(1500)), map.length());
+        assert_eq!(Some(secs(1500)), map.time_at_tick(3000 * 32));
+    }
+}
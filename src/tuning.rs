@@ -0,0 +1,213 @@
+use crate::midi::{MidiChannel, MidiNote};
+
+use thiserror::*;
+
+mod scala;
+
+mod bend;
+pub use bend::*;
+
+mod mts;
+pub use mts::*;
+
+#[derive(Debug, Error)]
+pub enum TuningError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Line {line}: {message}")]
+    Syntax { line: usize, message: &'static str },
+    #[error("The scale has no degrees.")]
+    EmptyScale,
+    #[error("The reference note {0:?} is not mapped to any scale degree.")]
+    UnmappedReference(MidiNote),
+}
+
+/// How a retuned song is sent to the synthesizer.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum TuningMethod {
+    /// Send each note on a channel whose pitch bend moves it to the right pitch.
+    PitchBend {
+        /// The synthesizer's pitch bend range, in semitones.
+        range: u8,
+        /// The channels notes are spread across.
+        channels: Vec<MidiChannel>,
+    },
+    /// Send MIDI Tuning Standard messages once at startup.
+    Mts,
+}
+
+/// The channels notes are spread across when retuning with pitch bend,
+/// leaving the first channel free.
+pub fn default_bend_channels() -> &'static [MidiChannel] {
+    &MidiChannel::all()[1..]
+}
+
+impl Default for TuningMethod {
+    fn default() -> Self {
+        TuningMethod::PitchBend {
+            range: 2,
+            channels: default_bend_channels().to_vec(),
+        }
+    }
+}
+
+/// Maps MIDI keys onto the degrees of a `Tuning`, following the Scala `.kbm` format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMap {
+    /// The lowest key that gets a pitch.
+    pub first: u8,
+    /// The highest key that gets a pitch.
+    pub last: u8,
+    /// The key that plays degree 0 of the scale.
+    pub middle: u8,
+    /// The key that plays `reference_freq`.
+    pub reference_note: u8,
+    pub reference_freq: f64,
+    /// The degree that the mapping repeats at.
+    pub octave_degree: usize,
+    /// The scale degree of each key in a repetition of the mapping, or `None`
+    /// if that key is silent. If empty, keys map onto consecutive degrees.
+    pub mapping: Vec<Option<usize>>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        KeyMap {
+            first: 0,
+            last: 127,
+            middle: 60,
+            reference_note: 69,
+            reference_freq: 440.0,
+            octave_degree: 12,
+            mapping: Vec::new(),
+        }
+    }
+}
+
+/// A scale of pitches, plus the mapping of MIDI keys onto it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    /// The size of each degree above the root, in cents.
+    /// The last degree is the interval the scale repeats at.
+    degrees: Vec<f64>,
+    keymap: KeyMap,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning::equal_temperament()
+    }
+}
+
+impl Tuning {
+    pub fn new(degrees: Vec<f64>, keymap: KeyMap) -> Result<Self, TuningError> {
+        if degrees.is_empty() {
+            return Err(TuningError::EmptyScale);
+        }
+        let retvl = Tuning { degrees, keymap };
+        let reference = MidiNote::mask(retvl.keymap.reference_note);
+        if retvl.cents(reference).is_none() {
+            return Err(TuningError::UnmappedReference(reference));
+        }
+        Ok(retvl)
+    }
+
+    /// The standard 12-tone equal temperament with A4 at 440 Hz.
+    pub fn equal_temperament() -> Self {
+        let degrees = (1..=12).map(|n| f64::from(n) * 100.0).collect();
+        Tuning {
+            degrees,
+            keymap: KeyMap::default(),
+        }
+    }
+
+    /// Changes which key plays which frequency, keeping the rest of the tuning.
+    pub fn with_reference_pitch(mut self, note: MidiNote, freq: f64) -> Result<Self, TuningError> {
+        if self.key_degree(note).is_none() {
+            return Err(TuningError::UnmappedReference(note));
+        }
+        self.keymap.reference_note = note.as_u8();
+        self.keymap.reference_freq = freq;
+        Ok(self)
+    }
+
+    fn key_degree(&self, note: MidiNote) -> Option<i64> {
+        let key = note.as_u8();
+        if key < self.keymap.first || key > self.keymap.last {
+            return None;
+        }
+        let offset = i64::from(key) - i64::from(self.keymap.middle);
+        if self.keymap.mapping.is_empty() {
+            return Some(offset);
+        }
+        let size = self.keymap.mapping.len() as i64;
+        let degree = self.keymap.mapping[offset.rem_euclid(size) as usize]? as i64;
+        Some(offset.div_euclid(size) * self.keymap.octave_degree as i64 + degree)
+    }
+
+    fn degree_cents(&self, degree: i64) -> f64 {
+        let size = self.degrees.len() as i64;
+        let period = self.degrees[self.degrees.len() - 1];
+        let repetitions = degree.div_euclid(size);
+        let base = match degree.rem_euclid(size) {
+            0 => 0.0,
+            idx => self.degrees[idx as usize - 1],
+        };
+        repetitions as f64 * period + base
+    }
+
+    /// Gets the pitch of a key in cents above the key the scale starts on,
+    /// or `None` if the key is not mapped.
+    fn cents(&self, note: MidiNote) -> Option<f64> {
+        self.key_degree(note).map(|degree| self.degree_cents(degree))
+    }
+
+    /// Gets the frequency of a key in Hz, or `None` if the key is not mapped.
+    pub fn frequency(&self, note: MidiNote) -> Option<f64> {
+        let reference = self.cents(MidiNote::mask(self.keymap.reference_note))?;
+        let cents = self.cents(note)?;
+        Some(self.keymap.reference_freq * ((cents - reference) / 1200.0).exp2())
+    }
+
+    /// Gets the pitch of a key as a fractional 12-TET MIDI note number,
+    /// or `None` if the key is not mapped.
+    pub fn midi_pitch(&self, note: MidiNote) -> Option<f64> {
+        note.frequency_in(self)
+            .map(|freq| 69.0 + 12.0 * (freq / 440.0).log2())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(expected: f64, actual: f64) {
+        assert!((expected - actual).abs() < 1e-6, "{} != {}", expected, actual);
+    }
+
+    #[test]
+    fn test_frequency() {
+        let tet = Tuning::equal_temperament();
+        for note in MidiNote::all() {
+            let expected = 440.0 * ((f64::from(note.as_u8()) - 69.0) / 12.0).exp2();
+            assert_close(expected, note.frequency_in(&tet).unwrap());
+            assert_close(note.frequency(), note.frequency_in(&tet).unwrap());
+        }
+
+        let a = MidiNote::from_raw(69).unwrap();
+        let a432 = tet.with_reference_pitch(a, 432.0).unwrap();
+        assert_close(432.0, a.frequency_in(&a432).unwrap());
+        assert_close(216.0, MidiNote::from_raw(57).unwrap().frequency_in(&a432).unwrap());
+
+        // Just intonation major scale on the white keys, with black keys unmapped.
+        let scale = "! just.scl\nJust major\n 7\n!\n9/8\n5/4\n4/3\n3/2\n5/3\n15/8\n2/1\n";
+        let keymap = "12\n0\n127\n60\n60\n261.625565\n7\n0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n";
+        let degrees = scala::parse_scl(scale).unwrap();
+        let just = Tuning::new(degrees, scala::parse_kbm(keymap).unwrap()).unwrap();
+        let c = MidiNote::from_raw(60).unwrap();
+        let c_freq = c.frequency_in(&just).unwrap();
+        assert_close(c_freq * 1.5, MidiNote::from_raw(67).unwrap().frequency_in(&just).unwrap());
+        assert_close(c_freq * 2.5, MidiNote::from_raw(76).unwrap().frequency_in(&just).unwrap());
+        assert_eq!(None, MidiNote::from_raw(61).unwrap().frequency_in(&just));
+    }
+}
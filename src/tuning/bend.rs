@@ -0,0 +1,275 @@
+use super::Tuning;
+use crate::midi::{MidiChannel, MidiMessage, MidiNote, RawMessage};
+use crate::track::{CompiledTrack, TrackEvent};
+
+use std::collections::{BTreeSet, HashMap};
+
+/// The pitch bend value that leaves a note untouched.
+const BEND_CENTER: u16 = 8192;
+
+/// Finds the 12-TET key and pitch bend value that play `note` in `tuning`.
+fn bent_note(tuning: &Tuning, note: MidiNote, range: u8) -> Option<(MidiNote, u16)> {
+    let pitch = tuning.midi_pitch(note)?;
+    let nearest = pitch.round().clamp(0.0, 127.0);
+    let semitones = (pitch - nearest) / f64::from(range.max(1));
+    let bend = (f64::from(BEND_CENTER) * (1.0 + semitones))
+        .round()
+        .clamp(0.0, 16383.0) as u16;
+    Some((MidiNote::mask(nearest as u8), bend))
+}
+
+fn pitch_bend(channel: MidiChannel, bend: u16) -> MidiMessage {
+    let bytes = [
+        0xE0 | channel.as_u8(),
+        (bend & 0x7F) as u8,
+        (bend >> 7) as u8,
+    ];
+    MidiMessage::Other(RawMessage::from_raw(&bytes))
+}
+
+fn control_change(channel: MidiChannel, control: u8, value: u8) -> MidiMessage {
+    let bytes = [0xB0 | channel.as_u8(), control, value];
+    MidiMessage::Other(RawMessage::from_raw(&bytes))
+}
+
+/// Sets the pitch bend range of a channel through RPN 0.
+fn bend_range_messages(channel: MidiChannel, range: u8) -> [MidiMessage; 4] {
+    [
+        control_change(channel, 101, 0),
+        control_change(channel, 100, 0),
+        control_change(channel, 6, range),
+        control_change(channel, 38, 0),
+    ]
+}
+
+/// The kinds of channel message that set up how a channel sounds: control
+/// change, program change and channel pressure.
+const CHANNEL_SETTINGS: [u8; 3] = [0xB0, 0xC0, 0xD0];
+
+/// Splits `channels` among the channels notes were written on, so each of
+/// those keeps its own patch and controllers. If there are more of them
+/// than channels to split, some have to share.
+fn split_channels(
+    sources: &BTreeSet<MidiChannel>,
+    channels: &[MidiChannel],
+) -> HashMap<MidiChannel, Vec<MidiChannel>> {
+    let count = sources.len();
+    sources
+        .iter()
+        .enumerate()
+        .map(|(idx, source)| {
+            let own = if count <= channels.len() {
+                channels.iter().skip(idx).step_by(count).copied().collect()
+            } else {
+                vec![channels[idx % channels.len()]]
+            };
+            (*source, own)
+        })
+        .collect()
+}
+
+/// Rewrites a track so that each note plays at its pitch in `tuning` on a
+/// 12-TET synthesizer, by sending it on a channel bent to the right pitch.
+///
+/// Each channel notes were written on gets its own share of `channels`,
+/// and its control, program and pressure messages are sent on every channel
+/// in that share instead. Notes from the same channel that need the same
+/// bend share a channel, and each distinct bend is given the next channel
+/// in the share. If there are more distinct bends than channels, the bend
+/// is resent before every note, which may detune notes still held on the
+/// same channel.
+pub fn retune_with_bend(
+    track: CompiledTrack,
+    tuning: &Tuning,
+    range: u8,
+    channels: &[MidiChannel],
+) -> CompiledTrack {
+    if channels.is_empty() {
+        return track;
+    }
+    let mut note_ports = BTreeSet::new();
+    let mut sources = BTreeSet::new();
+    for evt in track.events.iter() {
+        let (channel, port) = match evt {
+            TrackEvent::SendMessage {
+                message: MidiMessage::NoteOn(data),
+                port,
+            } => (data.channel(), port),
+            TrackEvent::SendMessage {
+                message: MidiMessage::NoteOff(data),
+                port,
+            } => (data.channel(), port),
+            _ => continue,
+        };
+        note_ports.insert(*port);
+        sources.insert(channel);
+    }
+    let shares = split_channels(&sources, channels);
+
+    let len = track.events.len();
+    let mut events = Vec::with_capacity(len * 2);
+    for port in note_ports {
+        for channel in channels {
+            events.extend(
+                bend_range_messages(*channel, range)
+                    .iter()
+                    .map(|message| TrackEvent::SendMessage {
+                        message: *message,
+                        port,
+                    }),
+            );
+        }
+    }
+
+    let mut bend_channels: HashMap<(MidiChannel, u16), MidiChannel> = HashMap::new();
+    let mut channel_for = |source: MidiChannel, bend: u16| {
+        let share = &shares[&source];
+        let used = bend_channels.keys().filter(|(cur, _)| *cur == source).count();
+        let next = share[used % share.len()];
+        *bend_channels.entry((source, bend)).or_insert(next)
+    };
+    let mut new_idx = vec![0; len + 1];
+    for (idx, evt) in track.events.into_iter().enumerate() {
+        // Jumps to an instruction should also run anything inserted before
+        // it, but not the bend range setup at the start.
+        new_idx[idx] = events.len();
+        let (message, port) = match evt {
+            TrackEvent::SendMessage { message, port } => (message, port),
+            other => {
+                events.push(other);
+                continue;
+            }
+        };
+        let retuned = match message {
+            MidiMessage::NoteOn(data) => bent_note(tuning, data.note(), range).map(|(note, bend)| {
+                let channel = channel_for(data.channel(), bend);
+                let message = data.with_channel(channel).with_note(note).into();
+                (message, channel, bend, data.vel().as_u8() > 0)
+            }),
+            MidiMessage::NoteOff(data) => bent_note(tuning, data.note(), range).map(|(note, bend)| {
+                let channel = channel_for(data.channel(), bend);
+                let message = data.with_channel(channel).with_note(note).into();
+                (message, channel, bend, false)
+            }),
+            MidiMessage::Other(raw) => {
+                let share = match raw.bytes() {
+                    [status, ..] if CHANNEL_SETTINGS.contains(&(status & 0xF0)) => {
+                        MidiChannel::from_raw(status & 0x0F).and_then(|source| shares.get(&source))
+                    }
+                    _ => None,
+                };
+                if let Some(share) = share {
+                    for channel in share {
+                        let mut bytes = [0; 3];
+                        bytes[..raw.len()].copy_from_slice(raw.bytes());
+                        bytes[0] = (bytes[0] & 0xF0) | channel.as_u8();
+                        let message = MidiMessage::Other(RawMessage::from_raw(&bytes[..raw.len()]));
+                        events.push(TrackEvent::SendMessage { message, port });
+                    }
+                    continue;
+                }
+                None
+            }
+        };
+        match retuned {
+            Some((message, channel, bend, is_press)) => {
+                if is_press {
+                    events.push(TrackEvent::SendMessage {
+                        message: pitch_bend(channel, bend),
+                        port,
+                    });
+                }
+                events.push(TrackEvent::SendMessage { message, port });
+            }
+            None => events.push(TrackEvent::SendMessage { message, port }),
+        }
+    }
+    new_idx[len] = events.len();
+
+    for evt in events.iter_mut() {
+        if let TrackEvent::Jump { target, .. } | TrackEvent::Call { target } = evt {
+            if let Some(mapped) = new_idx.get(*target) {
+                *target = *mapped;
+            }
+        }
+    }
+    let mut markers = track.markers;
+    for marker in markers.iter_mut() {
+        marker.start = new_idx[marker.start.min(len)];
+        marker.end = new_idx[marker.end.min(len)];
+    }
+    CompiledTrack { events, markers }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{NoteOff, NoteOn, PressVelocity};
+    use crate::track::{OutputPort, TrackCursor, WaitTime};
+    use std::num::NonZeroU16;
+    use std::time::Duration;
+
+    #[test]
+    fn test_retune_with_bend() {
+        let port = OutputPort::from(0);
+        let note = MidiNote::from_raw(69).unwrap();
+        let vel = PressVelocity::from_raw(90).unwrap();
+        let press = |message: MidiMessage| TrackEvent::SendMessage { message, port };
+        let track = CompiledTrack::from(vec![
+            press(NoteOn::new(MidiChannel::default(), note, vel).into()),
+            TrackEvent::Wait(WaitTime::Ticks(NonZeroU16::new(4).unwrap())),
+            press(NoteOff::new(MidiChannel::default(), note, vel).into()),
+            TrackEvent::Jump {
+                target: 0,
+                count: NonZeroU16::new(1),
+            },
+            TrackEvent::End,
+        ]);
+
+        // An eighth tone sharp, with a 2 semitone bend range.
+        let tuning = Tuning::equal_temperament()
+            .with_reference_pitch(note, 440.0 * (0.25f64 / 12.0).exp2())
+            .unwrap();
+        let channel = MidiChannel::from_raw(1).unwrap();
+        let retuned = retune_with_bend(track, &tuning, 2, &[channel]);
+        assert_eq!(Some(&TrackEvent::End), retuned.events.last());
+
+        let sent: Vec<_> = TrackCursor::new(retuned)
+            .step_until(Duration::from_secs(60))
+            .map(|(_, _, msg)| msg)
+            .collect();
+        let bend = pitch_bend(channel, BEND_CENTER + BEND_CENTER / 8);
+        let on = NoteOn::new(channel, note, vel).into();
+        let off = NoteOff::new(channel, note, vel).into();
+        let setup = bend_range_messages(channel, 2);
+        let mut expected = setup.to_vec();
+        expected.extend_from_slice(&[bend, on, off]);
+        // The loop back to the start skips the bend range setup.
+        expected.extend_from_slice(&[bend, on, off]);
+        assert_eq!(expected, sent);
+
+        // Each written channel gets its own bend channels, which follow its program.
+        let program = |channel: u8| MidiMessage::Other(RawMessage::from_raw(&[0xC0 | channel, 5]));
+        let on = |channel| NoteOn::new(MidiChannel::from_raw(channel).unwrap(), note, vel).into();
+        let track = CompiledTrack::from(vec![
+            press(program(0)),
+            press(on(0)),
+            press(on(2)),
+            TrackEvent::End,
+        ]);
+        let channels: Vec<_> = [1, 3, 4, 5]
+            .iter()
+            .map(|raw| MidiChannel::from_raw(*raw).unwrap())
+            .collect();
+        let retuned = retune_with_bend(track, &tuning, 2, &channels);
+        let sent: Vec<_> = retuned.events[channels.len() * setup.len()..]
+            .iter()
+            .filter_map(|evt| match evt {
+                TrackEvent::SendMessage { message, .. } => Some(*message),
+                _ => None,
+            })
+            .filter(|message| !matches!(message, MidiMessage::Other(raw) if raw.tag() == 0xE0))
+            .collect();
+        assert_eq!(vec![program(1), program(4), on(1), on(3)], sent);
+    }
+}
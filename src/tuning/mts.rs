@@ -0,0 +1,47 @@
+use super::Tuning;
+use crate::midi::MidiNote;
+
+/// The most notes a single MTS message can retune.
+const MAX_NOTES_PER_MESSAGE: usize = 127;
+
+/// Encodes a fractional 12-TET MIDI note number as MTS frequency data:
+/// the semitone below it, then the 14-bit fraction of a semitone above that.
+fn mts_frequency(pitch: f64) -> [u8; 3] {
+    let pitch = pitch.clamp(0.0, 127.0);
+    let mut semitone = pitch.floor();
+    let mut fraction = ((pitch - semitone) * 16384.0).round();
+    if fraction >= 16384.0 {
+        semitone += 1.0;
+        fraction = 0.0;
+    }
+    // 0x7F 0x7F 0x7F means "no change", so the top of the range is clamped below it.
+    if semitone >= 127.0 {
+        return [127, 0x7F, 0x7E];
+    }
+    let fraction = fraction as u16;
+    [semitone as u8, (fraction >> 7) as u8, (fraction & 0x7F) as u8]
+}
+
+/// Builds the MIDI Tuning Standard real-time single note tuning change
+/// messages that retune every mapped key to its pitch in `tuning`.
+pub fn mts_messages(tuning: &Tuning, device_id: u8) -> Vec<Vec<u8>> {
+    let changes: Vec<[u8; 4]> = MidiNote::all()
+        .iter()
+        .filter_map(|note| {
+            let [semitone, msb, lsb] = mts_frequency(tuning.midi_pitch(*note)?);
+            Some([note.as_u8(), semitone, msb, lsb])
+        })
+        .collect();
+    changes
+        .chunks(MAX_NOTES_PER_MESSAGE)
+        .map(|chunk| {
+            let mut msg = vec![0xF0, 0x7F, device_id & 0x7F, 0x08, 0x02, 0x00];
+            msg.push(chunk.len() as u8);
+            for change in chunk {
+                msg.extend_from_slice(change);
+            }
+            msg.push(0xF7);
+            msg
+        })
+        .collect()
+}
@@ -0,0 +1,132 @@
+use super::{KeyMap, Tuning, TuningError};
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Iterates over the first word of every non-comment line, along with its line number.
+fn scala_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.starts_with('!'))
+        .map(|(idx, line)| (idx + 1, line.trim()))
+}
+
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+fn parse_field<T: FromStr>(
+    entry: Option<(usize, &str)>,
+    message: &'static str,
+) -> Result<T, TuningError> {
+    let (line, text) = entry.ok_or(TuningError::Syntax { line: 0, message })?;
+    first_word(text)
+        .parse()
+        .map_err(|_| TuningError::Syntax { line, message })
+}
+
+/// Parses a single pitch from a `.scl` file into cents.
+fn parse_pitch(line: usize, text: &str) -> Result<f64, TuningError> {
+    let word = first_word(text);
+    let bad_pitch = TuningError::Syntax {
+        line,
+        message: "Expected a pitch in cents or as a ratio.",
+    };
+    if word.contains('.') {
+        return word.parse().map_err(|_| bad_pitch);
+    }
+    let mut parts = word.splitn(2, '/');
+    let num: u64 = parts
+        .next()
+        .and_then(|n| n.parse().ok())
+        .ok_or(TuningError::Syntax {
+            line,
+            message: "Expected a pitch in cents or as a ratio.",
+        })?;
+    let denom: u64 = match parts.next() {
+        Some(d) => d.parse().map_err(|_| bad_pitch)?,
+        None => 1,
+    };
+    if num == 0 || denom == 0 {
+        return Err(TuningError::Syntax {
+            line,
+            message: "Ratios must be positive.",
+        });
+    }
+    Ok(1200.0 * (num as f64 / denom as f64).log2())
+}
+
+/// Parses the contents of a Scala `.scl` file into the sizes of each
+/// of its degrees in cents.
+pub fn parse_scl(text: &str) -> Result<Vec<f64>, TuningError> {
+    let mut lines = scala_lines(text);
+    // The description may be anything, including empty.
+    let _description = lines.next();
+    let count: usize = parse_field(lines.next(), "Expected the number of notes.")?;
+    let mut degrees = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (line, text) = lines.next().ok_or(TuningError::Syntax {
+            line: text.lines().count(),
+            message: "Fewer pitches than the scale's note count.",
+        })?;
+        degrees.push(parse_pitch(line, text)?);
+    }
+    if degrees.is_empty() {
+        return Err(TuningError::EmptyScale);
+    }
+    Ok(degrees)
+}
+
+/// Parses the contents of a Scala `.kbm` keyboard mapping file.
+pub fn parse_kbm(text: &str) -> Result<KeyMap, TuningError> {
+    let mut lines = scala_lines(text).filter(|(_, line)| !line.is_empty());
+    let size: usize = parse_field(lines.next(), "Expected the size of the map.")?;
+    let first = parse_field(lines.next(), "Expected the first key to retune.")?;
+    let last = parse_field(lines.next(), "Expected the last key to retune.")?;
+    let middle = parse_field(lines.next(), "Expected the middle key.")?;
+    let reference_note = parse_field(lines.next(), "Expected the reference key.")?;
+    let reference_freq = parse_field(lines.next(), "Expected the reference frequency.")?;
+    let octave_degree = parse_field(lines.next(), "Expected the formal octave degree.")?;
+    let mut mapping = Vec::with_capacity(size);
+    for _ in 0..size {
+        // Missing entries at the end of the map are left unmapped.
+        let degree = match lines.next() {
+            Some((_, text)) if first_word(text).eq_ignore_ascii_case("x") => None,
+            Some(entry) => Some(parse_field(Some(entry), "Expected a degree or 'x'.")?),
+            None => None,
+        };
+        mapping.push(degree);
+    }
+    Ok(KeyMap {
+        first,
+        last,
+        middle,
+        reference_note,
+        reference_freq,
+        octave_degree,
+        mapping,
+    })
+}
+
+impl Tuning {
+    /// Loads a tuning from a `.scl` file and an optional `.kbm` file.
+    ///
+    /// Without a scale file, 12-TET is used; without a keyboard mapping,
+    /// the scale starts on middle C and A4 is kept at 440 Hz.
+    pub fn load(scale: Option<&Path>, keymap: Option<&Path>) -> Result<Tuning, TuningError> {
+        let base = Tuning::equal_temperament();
+        let degrees = match scale {
+            Some(path) => parse_scl(&fs::read_to_string(path)?)?,
+            None => base.degrees,
+        };
+        let keymap = match keymap {
+            Some(path) => parse_kbm(&fs::read_to_string(path)?)?,
+            None => KeyMap {
+                octave_degree: degrees.len(),
+                ..base.keymap
+            },
+        };
+        Tuning::new(degrees, keymap)
+    }
+}
@@ -1,4 +1,6 @@
 use std::num::NonZeroU16;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
 
 #[macro_export]
 macro_rules! const_try {
@@ -47,3 +49,94 @@ impl<K, V, F: Fn(&K) -> V, I: Iterator<Item = K>> Iterator for TuplerIter<K, V,
     }
 }
 
+
+/// A single slot for handing a boxed value from one thread to another
+/// without locking, IE between the realtime thread and the rest of the program.
+///
+/// Moving a box in or out never allocates or frees, so either end is safe
+/// to use from the realtime thread as long as it does not drop what it takes.
+pub struct Mailbox<T> {
+    slot: AtomicPtr<T>,
+}
+
+// SAFETY: the slot owns the value it points to, and only ever hands it
+// out whole, so sharing the mailbox only ever moves a `T` between threads.
+unsafe impl<T: Send> Send for Mailbox<T> {}
+unsafe impl<T: Send> Sync for Mailbox<T> {}
+
+impl<T> Default for Mailbox<T> {
+    fn default() -> Self {
+        Self {
+            slot: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+impl<T> Mailbox<T> {
+    /// Puts `value` in the slot, handing it back if the slot is already full.
+    pub fn put(&self, value: Box<T>) -> Result<(), Box<T>> {
+        let raw = Box::into_raw(value);
+        match self
+            .slot
+            .compare_exchange(ptr::null_mut(), raw, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => Ok(()),
+            // SAFETY: `raw` came from `Box::into_raw` above and was not stored.
+            Err(_) => Err(unsafe { Box::from_raw(raw) }),
+        }
+    }
+
+    /// Takes the value out of the slot, if there is one.
+    pub fn take(&self) -> Option<Box<T>> {
+        let raw = self.slot.swap(ptr::null_mut(), Ordering::AcqRel);
+        if raw.is_null() {
+            None
+        } else {
+            // SAFETY: non-null pointers in the slot always come from
+            // `Box::into_raw` in `put()`, and the swap makes this the only copy.
+            Some(unsafe { Box::from_raw(raw) })
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slot.load(Ordering::Acquire).is_null()
+    }
+}
+
+impl<T> Drop for Mailbox<T> {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_mailbox() {
+        let mailbox = Mailbox::default();
+        assert!(mailbox.is_empty());
+        assert_eq!(None, mailbox.take());
+
+        assert_eq!(Ok(()), mailbox.put(Box::new(1)));
+        assert!(!mailbox.is_empty());
+        // A full slot hands the new value back and keeps the old one.
+        assert_eq!(Err(Box::new(2)), mailbox.put(Box::new(2)));
+        assert_eq!(Some(Box::new(1)), mailbox.take());
+        assert!(mailbox.is_empty());
+        assert_eq!(None, mailbox.take());
+    }
+
+    #[test]
+    fn test_mailbox_drop() {
+        let value = Arc::new(());
+        let mailbox = Mailbox::default();
+        assert!(mailbox.put(Box::new(Arc::clone(&value))).is_ok());
+        assert_eq!(2, Arc::strong_count(&value));
+        // Dropping a full mailbox drops what it holds.
+        drop(mailbox);
+        assert_eq!(1, Arc::strong_count(&value));
+    }
+}